@@ -1,4 +1,7 @@
+pub mod telemetry;
+
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -40,6 +43,52 @@ pub fn serialize_edit(edit: &Edit) -> Result<String, serde_json::Error> {
     serde_json::to_string(edit)
 }
 
+/// Tags an edit so the server applies it only once and acknowledges it with
+/// the version it produced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpId<'a> {
+    pub client_id: &'a str,
+    pub seq: u64,
+}
+
+/// Wraps an edit in the `{"type": "edit"}` envelope the server expects,
+/// against the document at `version`, along with its tag and the W3C
+/// `traceparent` of the edit's trace when there are some.
+pub fn edit_message(
+    edit: &Edit,
+    version: usize,
+    id: Option<OpId>,
+    traceparent: Option<&str>,
+) -> Result<String, serde_json::Error> {
+    let mut edit = serde_json::to_value(edit)?;
+    edit["version"] = json!(version);
+    let mut message = json!({
+        "type": "edit",
+        "edit": edit,
+    });
+    if let Some(id) = id {
+        message["client_id"] = json!(id.client_id);
+        message["seq"] = json!(id.seq);
+    }
+    if let Some(traceparent) = traceparent {
+        message["traceparent"] = json!(traceparent);
+    }
+    Ok(message.to_string())
+}
+
+/// The document version a message from the server brings the client to:
+/// that of the initial state, of an acknowledged edit or of an edit made by
+/// someone else. `None` for messages that do not change it.
+pub fn document_version(message: &str) -> Option<usize> {
+    let message: serde_json::Value = serde_json::from_str(message).ok()?;
+    let version = match message["type"].as_str()? {
+        "initial" | "initial_start" | "full_state" | "ack" => &message["version"],
+        "edit" => &message["edit"]["version"],
+        _ => return None,
+    };
+    version.as_u64().map(|version| version as usize)
+}
+
 pub fn deserialize_edit(json_str: &str) -> Result<Edit, serde_json::Error> {
     serde_json::from_str(json_str)
 }
@@ -1,12 +1,15 @@
 use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn};
 use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
 
 // Import the necessary items from your library crate
+use collaborative_editor_client::telemetry::EditTracer;
 use collaborative_editor_client::{
-    calculate_retry_delay, deserialize_edit, parse_user_input, serialize_edit,
+    calculate_retry_delay, document_version, edit_message, parse_user_input, OpId,
 };
 
 async fn connect_to_server(
@@ -60,6 +63,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let tracer = EditTracer::init();
+    // The version edits are sent against, kept up to date from the server's
+    // messages.
+    let version = Arc::new(AtomicUsize::new(0));
+
+    let sent_version = version.clone();
+    let client_id = format!("cli-{}", std::process::id());
+    let mut seq = 0;
     let user_input = tokio::spawn(async move {
         loop {
             print!("Enter an edit (position,insert/delete): ");
@@ -79,7 +90,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             };
 
-            let edit_json = match serialize_edit(&edit) {
+            let trace = tracer.start_edit();
+            seq += 1;
+            let id = OpId {
+                client_id: &client_id,
+                seq,
+            };
+            let edit_json = match edit_message(
+                &edit,
+                sent_version.load(Ordering::Relaxed),
+                Some(id),
+                trace.traceparent(),
+            ) {
                 Ok(json) => json,
                 Err(e) => {
                     error!("Failed to serialize edit: {}", e);
@@ -97,10 +119,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let receive_messages = tokio::spawn(async move {
         while let Some(message) = read.next().await {
             match message {
-                Ok(Message::Text(text)) => match deserialize_edit(&text) {
-                    Ok(edit) => info!("Received edit: {:?}", edit),
-                    Err(e) => warn!("Failed to parse received edit: {}", e),
-                },
+                Ok(Message::Text(text)) => {
+                    if let Some(new_version) = document_version(&text) {
+                        version.store(new_version, Ordering::Relaxed);
+                    }
+                    info!("Received: {}", text);
+                }
                 Ok(_) => warn!("Received non-text message"),
                 Err(e) => {
                     error!("Error receiving message: {}", e);
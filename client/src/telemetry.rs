@@ -0,0 +1,119 @@
+//! Trace context for edits.
+//!
+//! With the `otel` cargo feature every edit the user types starts a trace,
+//! exported over OTLP, whose W3C `traceparent` travels with the edit so the
+//! server can continue it. Without the feature no trace context is sent.
+
+#[cfg(feature = "otel")]
+use opentelemetry::trace::{Span as _, Tracer as _, TracerProvider as _};
+
+/// Owns the trace exporter; dropping it flushes any buffered spans.
+pub struct EditTracer {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// The trace of a single edit, ended when dropped.
+pub struct EditTrace {
+    traceparent: Option<String>,
+    #[cfg(feature = "otel")]
+    span: Option<opentelemetry_sdk::trace::Span>,
+}
+
+impl EditTrace {
+    /// The W3C `traceparent` to send with the edit, if tracing is enabled.
+    pub fn traceparent(&self) -> Option<&str> {
+        self.traceparent.as_deref()
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl EditTracer {
+    pub fn init() -> Self {
+        EditTracer {}
+    }
+
+    pub fn start_edit(&self) -> EditTrace {
+        EditTrace { traceparent: None }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl EditTracer {
+    /// Exports to the collector at `EDITOR_OTLP_ENDPOINT`, by default
+    /// `http://localhost:4317`.
+    pub fn init() -> Self {
+        use opentelemetry_otlp::WithExportConfig;
+
+        let endpoint = std::env::var("EDITOR_OTLP_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4317".to_string());
+        let exporter = match opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(exporter) => exporter,
+            Err(e) => {
+                log::error!("Failed to create the OTLP exporter: {}", e);
+                return EditTracer { provider: None };
+            }
+        };
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                opentelemetry_sdk::Resource::builder()
+                    .with_service_name("collaborative-editor-client")
+                    .build(),
+            )
+            .build();
+        EditTracer {
+            provider: Some(provider),
+        }
+    }
+
+    pub fn start_edit(&self) -> EditTrace {
+        let provider = match &self.provider {
+            Some(provider) => provider,
+            None => {
+                return EditTrace {
+                    traceparent: None,
+                    span: None,
+                }
+            }
+        };
+        let span = provider
+            .tracer("collaborative-editor-client")
+            .start("send_edit");
+        let context = span.span_context();
+        let traceparent = format!(
+            "00-{}-{}-{:02x}",
+            context.trace_id(),
+            context.span_id(),
+            context.trace_flags().to_u8()
+        );
+        EditTrace {
+            traceparent: Some(traceparent),
+            span: Some(span),
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for EditTrace {
+    fn drop(&mut self) {
+        if let Some(mut span) = self.span.take() {
+            span.end();
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for EditTracer {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                log::error!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
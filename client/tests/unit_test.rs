@@ -1,5 +1,6 @@
 use collaborative_editor_client::{
-    calculate_retry_delay, deserialize_edit, parse_user_input, serialize_edit, Edit,
+    calculate_retry_delay, deserialize_edit, document_version, edit_message, parse_user_input,
+    serialize_edit, Edit, OpId,
 };
 use std::time::Duration;
 
@@ -143,3 +144,45 @@ fn test_parse_user_input_invalid_delete_syntax() {
         "Invalid delete count. Please enter a number."
     );
 }
+
+#[test]
+fn test_edit_message_with_traceparent() {
+    let edit = Edit {
+        position: 5,
+        insert: Some("hello".to_string()),
+        delete: None,
+    };
+    let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+    let id = OpId {
+        client_id: "cli-1",
+        seq: 2,
+    };
+    let json = edit_message(&edit, 4, Some(id), Some(traceparent)).unwrap();
+    let expected_json = r#"{"client_id":"cli-1","edit":{"delete":null,"insert":"hello","position":5,"version":4},"seq":2,"traceparent":"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01","type":"edit"}"#;
+    assert_eq!(json, expected_json);
+}
+
+#[test]
+fn test_edit_message_without_traceparent() {
+    let edit = Edit {
+        position: 3,
+        insert: None,
+        delete: Some(2),
+    };
+    let json = edit_message(&edit, 0, None, None).unwrap();
+    let expected_json =
+        r#"{"edit":{"delete":2,"insert":null,"position":3,"version":0},"type":"edit"}"#;
+    assert_eq!(json, expected_json);
+}
+
+#[test]
+fn test_document_version_follows_server_messages() {
+    let initial = r#"{"type":"initial","content":"hi","version":3}"#;
+    assert_eq!(document_version(initial), Some(3));
+    let ack = r#"{"type":"ack","client_id":"c","seq":1,"version":4,"duplicate":false}"#;
+    assert_eq!(document_version(ack), Some(4));
+    let edit = r#"{"type":"edit","edit":{"position":0,"insert":"a","delete":null,"version":5}}"#;
+    assert_eq!(document_version(edit), Some(5));
+    let error = r#"{"type":"error","code":"rate_limited"}"#;
+    assert_eq!(document_version(error), None);
+}
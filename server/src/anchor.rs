@@ -0,0 +1,76 @@
+//! Byte ranges anchored to a document's text.
+//!
+//! An anchor moves with the text as edits are applied: it shifts with edits
+//! before it, grows with inserts inside it and shrinks with deletes. Text
+//! typed at either edge stays outside it. A non-empty anchor whose text is
+//! deleted entirely is marked orphaned; it is no longer moved after that.
+
+use serde::{Deserialize, Serialize};
+
+use crate::ot::Op;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Anchor {
+    /// Byte offsets of the anchored text in the current content.
+    pub start: usize,
+    pub end: usize,
+    /// Set once the anchored text has been deleted entirely.
+    pub orphaned: bool,
+}
+
+impl Anchor {
+    pub fn new(start: usize, end: usize) -> Self {
+        Anchor {
+            start,
+            end,
+            orphaned: false,
+        }
+    }
+
+    /// Moves the anchor past an operation applied to the document.
+    pub fn transform(&mut self, op: &Op) {
+        if self.orphaned {
+            return;
+        }
+        match op {
+            Op::Insert { position, text } => self.insert(*position, text.len()),
+            Op::Delete { position, text } => self.delete(*position, text.len()),
+            Op::Replace {
+                position,
+                deleted,
+                inserted,
+            } => {
+                self.delete(*position, deleted.len());
+                self.insert(*position, inserted.len());
+            }
+            Op::Format { .. } => {}
+        }
+    }
+
+    fn insert(&mut self, position: usize, len: usize) {
+        if position <= self.start {
+            self.start += len;
+            self.end += len;
+        } else if position < self.end {
+            self.end += len;
+        }
+    }
+
+    fn delete(&mut self, position: usize, len: usize) {
+        let map = |offset: usize| {
+            if offset <= position {
+                offset
+            } else if offset >= position + len {
+                offset - len
+            } else {
+                position
+            }
+        };
+        let was_empty = self.start == self.end;
+        self.start = map(self.start);
+        self.end = map(self.end);
+        if !was_empty && self.start == self.end {
+            self.orphaned = true;
+        }
+    }
+}
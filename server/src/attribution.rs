@@ -0,0 +1,123 @@
+//! Tracks who wrote and who deleted each part of a document while replaying
+//! its history.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use crate::history::AppliedOp;
+use crate::ot::Op;
+use crate::runs::{RunValue, Runs};
+
+/// Where a run of bytes in the replayed content came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// Bytes of the starting content, beginning at this offset.
+    Original(usize),
+    /// Bytes inserted by the operation applied at this version.
+    Inserted(usize),
+}
+
+impl RunValue for Source {
+    fn split_off(&self, at: usize) -> Self {
+        match *self {
+            Source::Original(start) => Source::Original(start + at),
+            source @ Source::Inserted(_) => source,
+        }
+    }
+}
+
+/// A span of the replayed content and the operation that inserted it, if
+/// it was not part of the starting content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span<'a> {
+    pub range: Range<usize>,
+    pub inserted_by: Option<&'a AppliedOp>,
+}
+
+/// Replays operations onto some starting content, remembering which
+/// operation inserted each byte of the result and which deleted each byte
+/// of the start.
+pub struct Attribution<'a> {
+    runs: Runs<Source>,
+    ops: &'a [AppliedOp],
+    /// Ranges of the starting content, with the index into `ops` of the
+    /// operation that deleted them.
+    deletions: Vec<(Range<usize>, usize)>,
+}
+
+impl<'a> Attribution<'a> {
+    /// Replays `ops` onto content of `start_len` bytes.
+    pub fn replay(start_len: usize, ops: &'a [AppliedOp]) -> Self {
+        let mut attribution = Attribution {
+            runs: Runs::filled(start_len, Source::Original(0)),
+            ops,
+            deletions: Vec::new(),
+        };
+        for (index, applied) in ops.iter().enumerate() {
+            match &applied.op {
+                Op::Insert { position, text } => attribution.insert(*position, text.len(), index),
+                Op::Delete { position, text } => attribution.delete(*position, text.len(), index),
+                Op::Replace {
+                    position,
+                    deleted,
+                    inserted,
+                } => {
+                    attribution.delete(*position, deleted.len(), index);
+                    attribution.insert(*position, inserted.len(), index);
+                }
+                Op::Format { .. } => {}
+            }
+        }
+        attribution
+    }
+
+    fn insert(&mut self, position: usize, len: usize, op: usize) {
+        self.runs.insert(position, len, Source::Inserted(op));
+    }
+
+    fn delete(&mut self, position: usize, len: usize, op: usize) {
+        for run in self.runs.delete(position, len) {
+            if let Source::Original(offset) = run.value {
+                self.deletions.push((offset..offset + run.len, op));
+            }
+        }
+    }
+
+    /// The replayed content split into spans by origin, in order.
+    pub fn spans(&self) -> Vec<Span<'a>> {
+        let mut spans: Vec<Span<'a>> = Vec::new();
+        for (range, source) in self.runs.iter() {
+            let inserted_by = match *source {
+                Source::Original(_) => None,
+                Source::Inserted(op) => Some(&self.ops[op]),
+            };
+            match spans.last_mut() {
+                Some(last) if last.inserted_by == inserted_by => last.range.end = range.end,
+                _ => spans.push(Span { range, inserted_by }),
+            }
+        }
+        spans
+    }
+
+    /// The authors who inserted any of `range` of the replayed content.
+    pub fn inserted_by(&self, range: Range<usize>) -> BTreeSet<&'a str> {
+        self.spans()
+            .into_iter()
+            .filter(|span| overlaps(&span.range, &range))
+            .filter_map(|span| span.inserted_by?.author.as_deref())
+            .collect()
+    }
+
+    /// The authors who deleted any of `range` of the starting content.
+    pub fn deleted_by(&self, range: Range<usize>) -> BTreeSet<&'a str> {
+        self.deletions
+            .iter()
+            .filter(|(deleted, _)| overlaps(deleted, &range))
+            .filter_map(|(_, op)| self.ops[*op].author.as_deref())
+            .collect()
+    }
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
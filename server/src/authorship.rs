@@ -0,0 +1,113 @@
+//! Who wrote each part of a document, kept up to date as edits are applied.
+
+use serde::{Deserialize, Serialize};
+
+use crate::attribution::Attribution;
+use crate::history::AppliedOp;
+use crate::ot::Op;
+use crate::runs::{RunValue, Runs};
+
+/// The edit that inserted some text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    pub author: Option<String>,
+    /// Version of the document the inserting edit was applied to.
+    pub version: usize,
+    pub timestamp_ms: u64,
+}
+
+impl From<&AppliedOp> for Origin {
+    fn from(applied: &AppliedOp) -> Self {
+        Origin {
+            author: applied.author.clone(),
+            version: applied.version,
+            timestamp_ms: applied.timestamp_ms,
+        }
+    }
+}
+
+impl RunValue for Option<Origin> {}
+
+/// A byte range of the content and the edit that inserted it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlameSpan {
+    pub start: usize,
+    pub end: usize,
+    pub author: Option<String>,
+    pub version: Option<usize>,
+    pub timestamp_ms: Option<u64>,
+}
+
+/// The origin of each run of the content; `None` for text whose origin is
+/// not known, such as content restored from a snapshot without history.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Authorship {
+    runs: Runs<Option<Origin>>,
+}
+
+impl Authorship {
+    /// Authorship of `len` bytes of content of unknown origin.
+    pub fn unknown(len: usize) -> Self {
+        Authorship {
+            runs: Runs::filled(len, None),
+        }
+    }
+
+    /// Rebuilds authorship by replaying `ops` onto `base_len` bytes of
+    /// content of unknown origin.
+    pub fn replay(base_len: usize, ops: &[AppliedOp]) -> Self {
+        let mut runs = Runs::default();
+        for span in Attribution::replay(base_len, ops).spans() {
+            runs.push(span.range.len(), span.inserted_by.map(Origin::from));
+        }
+        Authorship { runs }
+    }
+
+    /// Length in bytes of the content the runs cover.
+    pub fn content_len(&self) -> usize {
+        self.runs.content_len()
+    }
+
+    /// Updates the runs for an operation that was just applied.
+    pub fn apply(&mut self, applied: &AppliedOp) {
+        let origin = || Some(Origin::from(applied));
+        match &applied.op {
+            Op::Insert { position, text } => self.runs.insert(*position, text.len(), origin()),
+            Op::Delete { position, text } => {
+                self.runs.delete(*position, text.len());
+            }
+            Op::Replace {
+                position,
+                deleted,
+                inserted,
+            } => {
+                self.runs.delete(*position, deleted.len());
+                self.runs.insert(*position, inserted.len(), origin());
+            }
+            Op::Format { .. } => {}
+        }
+    }
+
+    /// The content split into spans by the edit that inserted them, in
+    /// order. Neighbouring runs from the same edit are merged.
+    pub fn spans(&self) -> Vec<BlameSpan> {
+        let mut spans: Vec<BlameSpan> = Vec::new();
+        for (range, origin) in self.runs.iter() {
+            let origin = origin.as_ref();
+            match spans.last_mut() {
+                Some(last) if last.version == origin.map(|origin| origin.version) => {
+                    last.end = range.end;
+                }
+                _ => spans.push(BlameSpan {
+                    start: range.start,
+                    end: range.end,
+                    author: origin.and_then(|origin| origin.author.clone()),
+                    version: origin.map(|origin| origin.version),
+                    timestamp_ms: origin.map(|origin| origin.timestamp_ms),
+                }),
+            }
+        }
+        spans
+    }
+}
@@ -0,0 +1,498 @@
+//! Sharing documents between several server nodes.
+//!
+//! Every document has a single owner among the nodes, picked by rendezvous
+//! hashing of the node ids, so all nodes agree on it without talking to each
+//! other. The owner is the only node that applies changes. Other nodes
+//! forward their clients' messages to it, keep a replica of the document up
+//! to date from the messages the owner broadcasts, and pass those messages
+//! on to their own clients.
+//!
+//! A primary and its followers share documents the same way, except that
+//! the primary owns every document and followers only serve reads, see
+//! [`crate::replication`].
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, info, warn};
+
+use crate::blocks::BlockDocument;
+use crate::checkpoint::Checkpoints;
+use crate::client_ops::ClientOps;
+use crate::comments::Comments;
+use crate::config::ClusterPeer;
+use crate::history::AppliedOp;
+use crate::http::tokens_match;
+use crate::storage::Snapshot;
+use crate::suggestions::Suggestions;
+
+/// Error returned for changes made on a node that does not own the document.
+pub const NOT_OWNER: &str = "Another node owns this document.";
+
+/// How long to wait before reconnecting to a node that could not be reached.
+pub(crate) const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a node or follower has to send the API token once connected.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest message line accepted from another node. Snapshots carry a
+/// whole document with its history, so this is well above the default
+/// document size limit; a longer line drops the connection.
+const MAX_LINE: usize = 256 << 20;
+
+/// Messages queued for a node that cannot keep up or be reached, beyond
+/// which further messages to it are dropped. Its replicas fetch the
+/// documents again once they notice the gap.
+const NODE_QUEUE: usize = 1024;
+
+/// A message between nodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeMessage {
+    /// A message from a client connected to `from`, for the owner of the
+    /// document to handle.
+    Forward {
+        document: String,
+        from: String,
+        connection: String,
+        user: String,
+        message: serde_json::Value,
+    },
+    /// A client connected to `from` went away.
+    Left {
+        document: String,
+        from: String,
+        connection: String,
+    },
+    /// The owner's reply to a forwarded message, for the client that sent it.
+    Reply {
+        document: String,
+        connection: String,
+        message: String,
+    },
+    /// A message the owner sent to every client of a document. `except`
+    /// names the connection that caused it, as `node/address`. Block
+    /// documents are named `blocks/{id}`.
+    Broadcast {
+        document: String,
+        except: Option<String>,
+        message: String,
+    },
+    /// Asks the owner for its copy of a document.
+    SyncRequest { document: String, from: String },
+    /// The owner's copy of a document, in answer to a `SyncRequest`.
+    Snapshot {
+        document: String,
+        snapshot: Snapshot,
+    },
+    /// The owner's copy of a block document.
+    BlockSnapshot {
+        document: String,
+        blocks: BlockDocument,
+    },
+    /// The versions of the documents a follower has saved, sent once
+    /// after the API token so the primary only sends what it is missing.
+    Resume { versions: HashMap<String, usize> },
+    /// The operations a follower is missing from a document it has saved,
+    /// up to `version`, whose content has `checksum`. Comments, suggestions
+    /// and checkpoints are not operations, so the owner's copies come along.
+    OpLog {
+        document: String,
+        ops: Vec<AppliedOp>,
+        version: usize,
+        checksum: u32,
+        checkpoints: Checkpoints,
+        comments: Comments,
+        suggestions: Suggestions,
+        client_ops: ClientOps,
+    },
+}
+
+/// Carries messages between nodes.
+///
+/// Messages a node sends to another arrive in the order they were sent, but
+/// may be lost while the other node cannot be reached.
+pub trait Backplane: Send + Sync {
+    /// Sends a message to every other node.
+    fn publish(&self, message: &NodeMessage);
+
+    /// Sends a message to one node.
+    fn send(&self, node: &str, message: &NodeMessage);
+
+    /// Stops exchanging messages with other nodes.
+    fn close(&self) {}
+}
+
+/// The name block document `id` is shared under.
+pub fn block_document(id: &str) -> String {
+    format!("blocks/{}", id)
+}
+
+/// The node that owns a document: the one whose id scores highest when
+/// hashed together with the document id.
+pub fn owner<'a>(document: &str, nodes: &'a [String]) -> Option<&'a str> {
+    nodes
+        .iter()
+        .max_by_key(|node| {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(node.as_bytes());
+            hasher.update(b"/");
+            hasher.update(document.as_bytes());
+            (hasher.finalize(), node.as_str())
+        })
+        .map(String::as_str)
+}
+
+/// This node's place among the nodes sharing documents.
+#[derive(Clone)]
+pub struct Cluster {
+    node_id: String,
+    topology: Arc<Topology>,
+    /// Set once a follower has been promoted to own every document.
+    promoted: Arc<AtomicBool>,
+    backplane: Arc<dyn Backplane>,
+}
+
+enum Topology {
+    /// Documents are spread over these nodes, this one included.
+    Shared(Vec<String>),
+    /// This node owns every document and other nodes follow it.
+    Primary,
+    /// The node named owns every document and this one follows it.
+    Follower(String),
+}
+
+impl Cluster {
+    /// Spreads documents over this node and `peers`.
+    pub fn new(node_id: &str, peers: &[String], backplane: Arc<dyn Backplane>) -> Self {
+        let mut nodes = peers.to_vec();
+        nodes.push(node_id.to_string());
+        nodes.sort();
+        nodes.dedup();
+        Cluster::with_topology(node_id, Topology::Shared(nodes), backplane)
+    }
+
+    /// Owns every document, sending followers everything that changes.
+    pub fn primary(node_id: &str, backplane: Arc<dyn Backplane>) -> Self {
+        Cluster::with_topology(node_id, Topology::Primary, backplane)
+    }
+
+    /// Follows `primary`, which owns every document, until promoted.
+    pub fn follower(node_id: &str, primary: &str, backplane: Arc<dyn Backplane>) -> Self {
+        Cluster::with_topology(node_id, Topology::Follower(primary.to_string()), backplane)
+    }
+
+    fn with_topology(node_id: &str, topology: Topology, backplane: Arc<dyn Backplane>) -> Self {
+        Cluster {
+            node_id: node_id.to_string(),
+            topology: Arc::new(topology),
+            promoted: Arc::new(AtomicBool::new(false)),
+            backplane,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn owner(&self, document: &str) -> &str {
+        match &*self.topology {
+            Topology::Follower(primary) if !self.is_promoted() => primary,
+            Topology::Shared(nodes) => {
+                owner(document, nodes).expect("a cluster includes this node")
+            }
+            _ => &self.node_id,
+        }
+    }
+
+    pub fn owns(&self, document: &str) -> bool {
+        self.owner(document) == self.node_id
+    }
+
+    /// Whether this node follows a primary, serving reads only.
+    pub fn is_follower(&self) -> bool {
+        matches!(*self.topology, Topology::Follower(_)) && !self.is_promoted()
+    }
+
+    /// Whether clients' changes to documents another node owns are
+    /// forwarded to it, rather than refused.
+    pub fn forwards(&self) -> bool {
+        matches!(*self.topology, Topology::Shared(_))
+    }
+
+    /// Whether every document, block documents included, is replicated to
+    /// every node, rather than flat documents as clients open them.
+    pub fn replicates_everything(&self) -> bool {
+        !self.forwards()
+    }
+
+    fn is_promoted(&self) -> bool {
+        self.promoted.load(Ordering::SeqCst)
+    }
+
+    /// Makes a follower the owner of every document and stops following.
+    /// Returns false if this node is not a follower.
+    pub fn promote(&self) -> bool {
+        if !matches!(*self.topology, Topology::Follower(_))
+            || self.promoted.swap(true, Ordering::SeqCst)
+        {
+            return false;
+        }
+        self.backplane.close();
+        true
+    }
+
+    pub fn publish(&self, message: &NodeMessage) {
+        self.backplane.publish(message);
+    }
+
+    pub fn send(&self, node: &str, message: &NodeMessage) {
+        self.backplane.send(node, message);
+    }
+}
+
+/// Connects nodes running in the same process, mostly for tests.
+#[derive(Clone, Default)]
+pub struct LoopbackHub {
+    nodes: Arc<Mutex<HashMap<String, UnboundedSender<NodeMessage>>>>,
+}
+
+impl LoopbackHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a node to the hub, returning its backplane and the messages
+    /// other nodes send it.
+    pub fn join(&self, node_id: &str) -> (LoopbackBackplane, UnboundedReceiver<NodeMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.nodes.lock().unwrap().insert(node_id.to_string(), tx);
+        let backplane = LoopbackBackplane {
+            node_id: node_id.to_string(),
+            hub: self.clone(),
+        };
+        (backplane, rx)
+    }
+}
+
+pub struct LoopbackBackplane {
+    node_id: String,
+    hub: LoopbackHub,
+}
+
+impl Backplane for LoopbackBackplane {
+    fn publish(&self, message: &NodeMessage) {
+        for (node, tx) in self.hub.nodes.lock().unwrap().iter() {
+            if *node != self.node_id {
+                let _ = tx.send(message.clone());
+            }
+        }
+    }
+
+    fn send(&self, node: &str, message: &NodeMessage) {
+        if let Some(tx) = self.hub.nodes.lock().unwrap().get(node) {
+            let _ = tx.send(message.clone());
+        }
+    }
+}
+
+/// Connects nodes over TCP, sending each message as a line of JSON.
+///
+/// Every node keeps one outgoing connection to each other node and accepts
+/// theirs, so messages between two nodes always travel the same way. Like
+/// a follower, a node sends the API token as the first line of each
+/// connection, and connections that don't are dropped.
+pub struct TcpBackplane {
+    peers: HashMap<String, Sender<String>>,
+}
+
+impl TcpBackplane {
+    /// Accepts other nodes on `listener` and connects to each of `peers`,
+    /// authenticating both ways with the API `token`, and returns the
+    /// backplane and the messages other nodes send.
+    pub fn start(
+        listener: TcpListener,
+        peers: &[ClusterPeer],
+        token: &str,
+    ) -> (TcpBackplane, UnboundedReceiver<NodeMessage>) {
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(accept_nodes(listener, token.to_string(), incoming_tx));
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                let (tx, rx) = mpsc::channel(NODE_QUEUE);
+                tokio::spawn(send_to_node(peer.clone(), token.to_string(), rx));
+                (peer.id.clone(), tx)
+            })
+            .collect();
+        (TcpBackplane { peers }, incoming)
+    }
+}
+
+impl Backplane for TcpBackplane {
+    fn publish(&self, message: &NodeMessage) {
+        let line = encode_line(message);
+        for (node, tx) in &self.peers {
+            queue_for_node(node, tx, line.clone());
+        }
+    }
+
+    fn send(&self, node: &str, message: &NodeMessage) {
+        match self.peers.get(node) {
+            Some(tx) => queue_for_node(node, tx, encode_line(message)),
+            None => warn!(node, "Dropping message for an unknown node"),
+        }
+    }
+}
+
+fn queue_for_node(node: &str, tx: &Sender<String>, line: String) {
+    if let Err(TrySendError::Full(_)) = tx.try_send(line) {
+        warn!(node, "Dropping message for a node that fell behind");
+    }
+}
+
+pub(crate) fn encode_line(message: &NodeMessage) -> String {
+    let mut line = serde_json::to_string(message).expect("node messages serialize");
+    line.push('\n');
+    line
+}
+
+async fn accept_nodes(
+    listener: TcpListener,
+    token: String,
+    incoming: UnboundedSender<NodeMessage>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let token = token.clone();
+                let incoming = incoming.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    if !authenticate(&mut reader, Some(&token)).await {
+                        warn!(%addr, "Refused a node without the API token");
+                        return;
+                    }
+                    debug!(%addr, "Node connected");
+                    receive_from_node(reader, incoming).await;
+                });
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to accept node connection");
+                return;
+            }
+        }
+    }
+}
+
+/// Reads the first line a node or follower sends and checks it is the API
+/// token.
+pub(crate) async fn authenticate(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    token: Option<&str>,
+) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    let mut line = String::new();
+    // Tokens are short, so a longer line is not one.
+    let limit = token.len() as u64 + 2;
+    let mut first_line = (&mut *reader).take(limit);
+    match tokio::time::timeout(AUTH_TIMEOUT, first_line.read_line(&mut line)).await {
+        Ok(Ok(_)) => tokens_match(token, line.trim_end_matches(['\r', '\n'])),
+        _ => false,
+    }
+}
+
+/// Reads the next line a node sends into `line`. Returns false once the
+/// connection closes, fails or sends a line longer than [`MAX_LINE`].
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), line: &mut Vec<u8>) -> bool {
+    line.clear();
+    let mut limited = (&mut *reader).take(MAX_LINE as u64 + 1);
+    match limited.read_until(b'\n', line).await {
+        Ok(0) => false,
+        Ok(_) if line.len() > MAX_LINE => {
+            warn!("Dropping a node that sent an oversized message");
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            warn!(error = %e, "Error receiving from node");
+            false
+        }
+    }
+}
+
+/// Reads the message a follower sends right after the API token, giving
+/// up after as long as it has to send the token.
+pub(crate) async fn read_first_message(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Option<NodeMessage> {
+    let mut line = Vec::new();
+    match tokio::time::timeout(AUTH_TIMEOUT, read_line(reader, &mut line)).await {
+        Ok(true) => serde_json::from_slice(&line).ok(),
+        _ => None,
+    }
+}
+
+/// Passes the messages a node sends on `stream` to `incoming` until the
+/// connection closes or sends a line longer than [`MAX_LINE`].
+pub(crate) async fn receive_from_node(
+    stream: impl AsyncRead + Unpin,
+    incoming: UnboundedSender<NodeMessage>,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while read_line(&mut reader, &mut line).await {
+        match serde_json::from_slice(&line) {
+            Ok(message) => {
+                if incoming.send(message).is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!(error = %e, "Ignoring invalid node message"),
+        }
+    }
+}
+
+/// Writes the messages queued for a node to it, reconnecting whenever the
+/// connection drops. The message being written when it drops is retried.
+async fn send_to_node(peer: ClusterPeer, token: String, mut queue: Receiver<String>) {
+    let mut pending = None;
+    loop {
+        let mut stream = match TcpStream::connect(&peer.addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!(node = %peer.id, error = %e, "Failed to connect to node");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = stream.write_all(format!("{}\n", token).as_bytes()).await {
+            debug!(node = %peer.id, error = %e, "Failed to authenticate with node");
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+        info!(node = %peer.id, addr = %peer.addr, "Connected to node");
+        loop {
+            let line = match pending.take() {
+                Some(line) => line,
+                None => match queue.recv().await {
+                    Some(line) => line,
+                    None => return,
+                },
+            };
+            if let Err(e) = stream.write_all(line.as_bytes()).await {
+                warn!(node = %peer.id, error = %e, "Lost connection to node");
+                pending = Some(line);
+                break;
+            }
+        }
+    }
+}
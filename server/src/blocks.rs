@@ -0,0 +1,468 @@
+//! Block-structured documents: an ordered list of typed, nested blocks of
+//! text, for structured notes.
+//!
+//! Block documents live next to the flat [`DocumentState`](crate::DocumentState)
+//! documents, in their own rooms, and are changed with [`BlockOp`]s rather
+//! than text edits. Blocks are addressed by ids the server hands out, so an
+//! op keeps pointing at the same block however the blocks around it move.
+
+use serde::{Deserialize, Serialize};
+
+use crate::DOCUMENT_TOO_LARGE;
+
+pub const UNKNOWN_BLOCK: &str = "No block with that id exists.";
+pub const INVALID_POSITION: &str =
+    "Text position is outside the block or not a character boundary.";
+pub const INVALID_BLOCK: &str = "Headings must be level 1 to 6 and blocks nested at most 8 deep.";
+pub const NOTHING_TO_MERGE: &str = "The first block has nothing to merge into.";
+
+/// Deepest a block can be nested.
+const MAX_DEPTH: u8 = 8;
+const MAX_HEADING: u8 = 6;
+
+/// The code clients receive for a block error, or `None` for errors shared
+/// with text edits.
+pub fn error_code(error: &str) -> Option<&'static str> {
+    match error {
+        UNKNOWN_BLOCK => Some("unknown_block"),
+        INVALID_POSITION => Some("invalid_position"),
+        INVALID_BLOCK => Some("invalid_block"),
+        NOTHING_TO_MERGE => Some("nothing_to_merge"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BlockKind {
+    Paragraph,
+    Heading { level: u8 },
+    Bullet,
+    Numbered,
+    Quote,
+    Code,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    /// Assigned by the server; ignored when a client inserts a block.
+    #[serde(default)]
+    pub id: u64,
+    #[serde(flatten)]
+    pub kind: BlockKind,
+    /// How far the block is nested, for example a list item inside another.
+    #[serde(default)]
+    pub depth: u8,
+    #[serde(default)]
+    pub text: String,
+}
+
+impl Block {
+    fn is_valid(&self) -> bool {
+        let level_ok = match self.kind {
+            BlockKind::Heading { level } => (1..=MAX_HEADING).contains(&level),
+            _ => true,
+        };
+        level_ok && self.depth <= MAX_DEPTH
+    }
+}
+
+/// An operation on a block document. Text positions are byte offsets into
+/// the block's text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BlockOp {
+    InsertText {
+        block: u64,
+        position: usize,
+        text: String,
+    },
+    DeleteText {
+        block: u64,
+        position: usize,
+        length: usize,
+    },
+    /// Inserts `block` after the block `after`, or first if `after` is
+    /// missing.
+    InsertBlock {
+        #[serde(default)]
+        after: Option<u64>,
+        block: Block,
+    },
+    DeleteBlock {
+        block: u64,
+    },
+    /// Splits a block at `position`, moving the text after it into a new
+    /// block `new_block` placed right after.
+    Split {
+        block: u64,
+        position: usize,
+        #[serde(default)]
+        new_block: u64,
+    },
+    /// Appends a block's text to the block before it and removes it.
+    Merge {
+        block: u64,
+    },
+    /// Moves a block after the block `after`, or first if `after` is
+    /// missing.
+    Move {
+        block: u64,
+        #[serde(default)]
+        after: Option<u64>,
+    },
+    /// Changes a block's kind and nesting, keeping its text.
+    SetKind {
+        block: u64,
+        #[serde(flatten)]
+        kind: BlockKind,
+        #[serde(default)]
+        depth: u8,
+    },
+}
+
+/// A document made of ordered blocks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockDocument {
+    next_id: u64,
+    pub version: usize,
+    pub blocks: Vec<Block>,
+    /// Largest size in bytes the text of all blocks may grow to, if limited.
+    #[serde(skip)]
+    pub max_size: Option<usize>,
+}
+
+impl BlockDocument {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_size(max_size: usize) -> Self {
+        BlockDocument {
+            max_size: Some(max_size),
+            ..BlockDocument::new()
+        }
+    }
+
+    /// The size in bytes of the text of every block.
+    pub fn size(&self) -> usize {
+        self.blocks.iter().map(|block| block.text.len()).sum()
+    }
+
+    /// Roughly how many bytes the document takes up in memory.
+    pub fn memory_size(&self) -> usize {
+        self.size() + self.blocks.len() * std::mem::size_of::<Block>()
+    }
+
+    /// Fails if adding `added` bytes of text would exceed the size limit.
+    fn check_growth(&self, added: usize) -> Result<(), &'static str> {
+        match self.max_size {
+            Some(max_size) if self.size().saturating_add(added) > max_size => {
+                Err(DOCUMENT_TOO_LARGE)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn index(&self, id: u64) -> Result<usize, &'static str> {
+        self.blocks
+            .iter()
+            .position(|block| block.id == id)
+            .ok_or(UNKNOWN_BLOCK)
+    }
+
+    /// The index a block placed after `after` goes to.
+    fn index_after(&self, after: Option<u64>) -> Result<usize, &'static str> {
+        match after {
+            Some(after) => self.index(after).map(|index| index + 1),
+            None => Ok(0),
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Applies an op made against `version`, filling in the ids of any
+    /// blocks it creates.
+    pub fn apply(&mut self, op: &mut BlockOp, version: usize) -> Result<(), &'static str> {
+        if version != self.version {
+            return Err("Version mismatch");
+        }
+        match op {
+            BlockOp::InsertText {
+                block,
+                position,
+                text,
+            } => {
+                let index = self.index(*block)?;
+                self.check_growth(text.len())?;
+                let block = &mut self.blocks[index];
+                if !block.text.is_char_boundary(*position) {
+                    return Err(INVALID_POSITION);
+                }
+                block.text.insert_str(*position, text);
+            }
+            BlockOp::DeleteText {
+                block,
+                position,
+                length,
+            } => {
+                let index = self.index(*block)?;
+                let text = &mut self.blocks[index].text;
+                let Some(end) = position.checked_add(*length) else {
+                    return Err(INVALID_POSITION);
+                };
+                if text.get(*position..end).is_none() {
+                    return Err(INVALID_POSITION);
+                }
+                text.replace_range(*position..end, "");
+            }
+            BlockOp::InsertBlock { after, block } => {
+                if !block.is_valid() {
+                    return Err(INVALID_BLOCK);
+                }
+                self.check_growth(block.text.len())?;
+                let index = self.index_after(*after)?;
+                block.id = self.next_id();
+                self.blocks.insert(index, block.clone());
+            }
+            BlockOp::DeleteBlock { block } => {
+                let index = self.index(*block)?;
+                self.blocks.remove(index);
+            }
+            BlockOp::Split {
+                block,
+                position,
+                new_block,
+            } => {
+                let index = self.index(*block)?;
+                if !self.blocks[index].text.is_char_boundary(*position) {
+                    return Err(INVALID_POSITION);
+                }
+                *new_block = self.next_id();
+                let original = &mut self.blocks[index];
+                let text = original.text.split_off(*position);
+                // Pressing enter at the end of a heading starts a paragraph.
+                let kind = match original.kind {
+                    BlockKind::Heading { .. } => BlockKind::Paragraph,
+                    ref kind => kind.clone(),
+                };
+                let split = Block {
+                    id: *new_block,
+                    kind,
+                    depth: original.depth,
+                    text,
+                };
+                self.blocks.insert(index + 1, split);
+            }
+            BlockOp::Merge { block } => {
+                let index = self.index(*block)?;
+                if index == 0 {
+                    return Err(NOTHING_TO_MERGE);
+                }
+                let merged = self.blocks.remove(index);
+                self.blocks[index - 1].text.push_str(&merged.text);
+            }
+            BlockOp::Move { block, after } => {
+                if *after == Some(*block) {
+                    return Err(UNKNOWN_BLOCK);
+                }
+                let index = self.index(*block)?;
+                // Check the target exists before taking the block out.
+                self.index_after(*after)?;
+                let moved = self.blocks.remove(index);
+                let target = self.index_after(*after)?;
+                self.blocks.insert(target, moved);
+            }
+            BlockOp::SetKind { block, kind, depth } => {
+                let index = self.index(*block)?;
+                let updated = Block {
+                    kind: kind.clone(),
+                    depth: *depth,
+                    ..self.blocks[index].clone()
+                };
+                if !updated.is_valid() {
+                    return Err(INVALID_BLOCK);
+                }
+                self.blocks[index] = updated;
+            }
+        }
+        self.version += 1;
+        Ok(())
+    }
+
+    /// Replaces every block with `blocks`, as one change.
+    pub fn replace_all(&mut self, blocks: Vec<Block>) -> Result<(), &'static str> {
+        let size: usize = blocks.iter().map(|block| block.text.len()).sum();
+        if self.max_size.is_some_and(|max_size| size > max_size) {
+            return Err(DOCUMENT_TOO_LARGE);
+        }
+        self.blocks = blocks
+            .into_iter()
+            .map(|block| Block {
+                id: self.next_id(),
+                ..block
+            })
+            .collect();
+        self.version += 1;
+        Ok(())
+    }
+
+    /// The text of every block, one block per line.
+    pub fn to_plain_text(&self) -> String {
+        let lines: Vec<&str> = self
+            .blocks
+            .iter()
+            .map(|block| block.text.as_str())
+            .collect();
+        lines.join("\n")
+    }
+
+    /// The document as Markdown. List items follow each other directly;
+    /// other blocks are separated by a blank line.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = String::new();
+        let mut previous: Option<&Block> = None;
+        for block in &self.blocks {
+            if let Some(previous) = previous {
+                let both_items = is_list_item(&previous.kind) && is_list_item(&block.kind);
+                markdown.push_str(if both_items { "\n" } else { "\n\n" });
+            }
+            let indent = "  ".repeat(block.depth as usize);
+            match &block.kind {
+                BlockKind::Paragraph => markdown.push_str(&block.text),
+                BlockKind::Heading { level } => {
+                    markdown.push_str(&"#".repeat(*level as usize));
+                    markdown.push(' ');
+                    markdown.push_str(&block.text);
+                }
+                BlockKind::Bullet => {
+                    markdown.push_str(&format!("{}- {}", indent, block.text));
+                }
+                BlockKind::Numbered => {
+                    markdown.push_str(&format!("{}1. {}", indent, block.text));
+                }
+                BlockKind::Quote => {
+                    let quoted: Vec<String> = block
+                        .text
+                        .lines()
+                        .map(|line| format!("> {}", line))
+                        .collect();
+                    markdown.push_str(&quoted.join("\n"));
+                }
+                BlockKind::Code => {
+                    markdown.push_str(&format!("```\n{}\n```", block.text));
+                }
+            }
+            previous = Some(block);
+        }
+        markdown
+    }
+}
+
+fn is_list_item(kind: &BlockKind) -> bool {
+    matches!(kind, BlockKind::Bullet | BlockKind::Numbered)
+}
+
+fn block(kind: BlockKind, depth: u8, text: &str) -> Block {
+    Block {
+        id: 0,
+        kind,
+        depth,
+        text: text.to_string(),
+    }
+}
+
+/// Blocks for plain text: one paragraph per line.
+pub fn from_plain_text(text: &str) -> Vec<Block> {
+    text.split('\n')
+        .map(|line| block(BlockKind::Paragraph, 0, line))
+        .collect()
+}
+
+/// Blocks for Markdown. Headings, bullet and numbered lists nested by two
+/// spaces, quotes and fenced code are recognised; anything else becomes a
+/// paragraph, with consecutive lines joined.
+pub fn from_markdown(markdown: &str) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut lines = markdown.lines();
+    // Whether the last block is a paragraph or quote later lines continue.
+    let mut open = false;
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        let depth = ((line.len() - trimmed.len()) / 2).min(MAX_DEPTH as usize) as u8;
+
+        if trimmed.is_empty() {
+            open = false;
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            let code: Vec<&str> = lines
+                .by_ref()
+                .take_while(|line| !line.trim_start().starts_with("```"))
+                .collect();
+            blocks.push(block(BlockKind::Code, 0, &code.join("\n")));
+            open = false;
+            continue;
+        }
+        if let Some(quoted) = trimmed.strip_prefix('>') {
+            let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+            match blocks.last_mut() {
+                Some(last) if open && last.kind == BlockKind::Quote => {
+                    last.text.push('\n');
+                    last.text.push_str(quoted);
+                }
+                _ => blocks.push(block(BlockKind::Quote, 0, quoted)),
+            }
+            open = true;
+            continue;
+        }
+
+        let hashes = trimmed.chars().take_while(|c| *c == '#').count();
+        let item =
+            if (1..=MAX_HEADING as usize).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+                Some(block(
+                    BlockKind::Heading {
+                        level: hashes as u8,
+                    },
+                    0,
+                    &trimmed[hashes + 1..],
+                ))
+            } else if let Some(text) = trimmed
+                .strip_prefix("- ")
+                .or_else(|| trimmed.strip_prefix("* "))
+            {
+                Some(block(BlockKind::Bullet, depth, text))
+            } else {
+                numbered_item(trimmed).map(|text| block(BlockKind::Numbered, depth, text))
+            };
+        match item {
+            Some(item) => {
+                blocks.push(item);
+                open = false;
+            }
+            None => {
+                match blocks.last_mut() {
+                    Some(last) if open && last.kind == BlockKind::Paragraph => {
+                        last.text.push('\n');
+                        last.text.push_str(trimmed);
+                    }
+                    _ => blocks.push(block(BlockKind::Paragraph, 0, trimmed)),
+                }
+                open = true;
+            }
+        }
+    }
+    blocks
+}
+
+/// The text of a numbered list item such as `2. milk`.
+fn numbered_item(line: &str) -> Option<&str> {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits == 0 {
+        return None;
+    }
+    line[digits..].strip_prefix(". ")
+}
@@ -0,0 +1,150 @@
+//! Named checkpoints of a document and restoring to them.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::history::now_ms;
+use crate::Edit;
+
+pub const CHECKPOINT_EXISTS: &str = "A checkpoint with that name already exists.";
+pub const UNKNOWN_CHECKPOINT: &str = "No checkpoint with that name exists.";
+pub const INVALID_CHECKPOINT_NAME: &str = "Checkpoint names must be 1 to 100 characters long.";
+pub const TOO_MANY_CHECKPOINTS: &str =
+    "A document can have at most 100 checkpoints; delete one first.";
+
+const MAX_NAME_LENGTH: usize = 100;
+/// Checkpoints one document may have. Each keeps a full copy of the
+/// content, so they are limited like the document itself.
+pub const MAX_CHECKPOINTS: usize = 100;
+
+/// The code clients receive for a checkpoint error, or `None` for errors
+/// that come from applying the restore edit.
+pub fn error_code(error: &str) -> Option<&'static str> {
+    match error {
+        CHECKPOINT_EXISTS => Some("checkpoint_exists"),
+        UNKNOWN_CHECKPOINT => Some("unknown_checkpoint"),
+        INVALID_CHECKPOINT_NAME => Some("invalid_checkpoint_name"),
+        TOO_MANY_CHECKPOINTS => Some("too_many_checkpoints"),
+        _ => None,
+    }
+}
+
+/// The content of a document at a version someone chose to name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub name: String,
+    pub version: usize,
+    pub content: String,
+    pub author: Option<String>,
+    pub timestamp_ms: u64,
+}
+
+impl Checkpoint {
+    /// Describes the checkpoint to clients, leaving out its content.
+    pub fn summary(&self) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "version": self.version,
+            "author": self.author,
+            "timestamp_ms": self.timestamp_ms,
+        })
+    }
+}
+
+/// The checkpoints of one document, oldest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoints {
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl Checkpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn list(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Checkpoint> {
+        self.checkpoints
+            .iter()
+            .find(|checkpoint| checkpoint.name == name)
+    }
+
+    /// Names the given content and version. Fails once the document has
+    /// [`MAX_CHECKPOINTS`].
+    pub fn create(
+        &mut self,
+        name: &str,
+        version: usize,
+        content: &str,
+        author: Option<&str>,
+    ) -> Result<&Checkpoint, &'static str> {
+        let length = name.chars().count();
+        if length == 0 || length > MAX_NAME_LENGTH {
+            return Err(INVALID_CHECKPOINT_NAME);
+        }
+        if self.get(name).is_some() {
+            return Err(CHECKPOINT_EXISTS);
+        }
+        if self.checkpoints.len() >= MAX_CHECKPOINTS {
+            return Err(TOO_MANY_CHECKPOINTS);
+        }
+        self.checkpoints.push(Checkpoint {
+            name: name.to_string(),
+            version,
+            content: content.to_string(),
+            author: author.map(str::to_string),
+            timestamp_ms: now_ms(),
+        });
+        Ok(self.checkpoints.last().expect("just pushed"))
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<Checkpoint, &'static str> {
+        let index = self
+            .checkpoints
+            .iter()
+            .position(|checkpoint| checkpoint.name == name)
+            .ok_or(UNKNOWN_CHECKPOINT)?;
+        Ok(self.checkpoints.remove(index))
+    }
+}
+
+/// The single edit that turns `current` into `target` when applied at
+/// `version`: the differing middle, between the longest common prefix and
+/// suffix, is replaced. Returns `None` if the two are already equal.
+pub fn edit_between(current: &str, target: &str, version: usize) -> Option<Edit> {
+    if current == target {
+        return None;
+    }
+
+    let prefix = common_prefix(current, target);
+    let suffix = common_suffix(&current[prefix..], &target[prefix..]);
+    let deleted = current.len() - prefix - suffix;
+    let inserted = &target[prefix..target.len() - suffix];
+    Some(Edit {
+        position: prefix,
+        insert: (!inserted.is_empty()).then(|| inserted.to_string()),
+        delete: (deleted > 0).then_some(deleted),
+        version,
+    })
+}
+
+/// Length in bytes of the longest common prefix, on a character boundary.
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((index, _), _)| index)
+}
+
+/// Length in bytes of the longest common suffix, on a character boundary.
+fn common_suffix(a: &str, b: &str) -> usize {
+    a.chars()
+        .rev()
+        .zip(b.chars().rev())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .sum()
+}
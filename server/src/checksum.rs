@@ -0,0 +1,18 @@
+//! Checksums that let clients check their copy of a document against the
+//! server's.
+//!
+//! Computing one means reading the whole content, so only every
+//! [`CHECKSUM_INTERVAL`]th version carries one.
+
+/// Number of versions between edits that carry a checksum.
+pub const CHECKSUM_INTERVAL: usize = 16;
+
+/// The CRC-32 (IEEE) of the content's UTF-8 bytes.
+pub fn checksum(content: &str) -> u32 {
+    crc32fast::hash(content.as_bytes())
+}
+
+/// Whether the message announcing `version` carries a checksum.
+pub fn is_due(version: usize) -> bool {
+    version.is_multiple_of(CHECKSUM_INTERVAL)
+}
@@ -0,0 +1,81 @@
+//! Exactly-once application of edits that clients retry.
+//!
+//! A client can tag each edit with an id of its own choosing and a sequence
+//! number that grows with every edit it sends. The document remembers the
+//! last sequence number it applied for each client, so an edit sent again
+//! after a timeout or a reconnect is acknowledged instead of applied twice.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Longest client id accepted, in bytes.
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+/// How many clients a document remembers. The one that edited least
+/// recently is forgotten first.
+const MAX_CLIENTS: usize = 10_000;
+
+pub const INVALID_OP_ID: &str = "Client ids must be 1 to 64 bytes long.";
+
+/// Identifies one edit sent by one client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpId {
+    pub client_id: String,
+    pub seq: u64,
+}
+
+impl OpId {
+    pub fn is_valid(&self) -> bool {
+        !self.client_id.is_empty() && self.client_id.len() <= MAX_CLIENT_ID_LENGTH
+    }
+}
+
+/// The last edit applied for a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastApplied {
+    pub seq: u64,
+    /// The version the edit produced.
+    pub version: usize,
+}
+
+/// The last edit applied for each client of a document.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientOps {
+    last: HashMap<String, LastApplied>,
+}
+
+impl ClientOps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn last(&self, client_id: &str) -> Option<LastApplied> {
+        self.last.get(client_id).copied()
+    }
+
+    /// Whether the edit `id` names has already been applied.
+    pub fn is_applied(&self, id: &OpId) -> bool {
+        self.last(&id.client_id)
+            .is_some_and(|last| id.seq <= last.seq)
+    }
+
+    /// Records that the edit `id` names produced `version`.
+    pub fn record(&mut self, id: &OpId, version: usize) {
+        if self.last.len() >= MAX_CLIENTS && !self.last.contains_key(&id.client_id) {
+            let oldest = self
+                .last
+                .iter()
+                .min_by_key(|(_, last)| last.version)
+                .map(|(client_id, _)| client_id.clone());
+            if let Some(oldest) = oldest {
+                self.last.remove(&oldest);
+            }
+        }
+        self.last.insert(
+            id.client_id.clone(),
+            LastApplied {
+                seq: id.seq,
+                version,
+            },
+        );
+    }
+}
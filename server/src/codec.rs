@@ -0,0 +1,166 @@
+//! Wire encodings of the WebSocket protocol.
+//!
+//! Messages are JSON text by default. A client can ask for a binary encoding
+//! of the same messages by offering one of the subprotocols below in its
+//! `Sec-WebSocket-Protocol` header; the server then sends binary frames in
+//! that encoding and accepts both binary frames and JSON text from it.
+//!
+//! Independently of the encoding, a client can ask for large messages to be
+//! compressed. Those are sent as binary frames holding a zlib stream of the
+//! encoded message; every message is an object, which never starts with the
+//! zlib header byte in any encoding, so clients can tell the two apart.
+
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+
+pub const JSON_PROTOCOL: &str = "editor.json";
+pub const MSGPACK_PROTOCOL: &str = "editor.msgpack";
+pub const CBOR_PROTOCOL: &str = "editor.cbor";
+
+/// Value of the `compress` query parameter that turns compression on.
+pub const DEFLATE: &str = "deflate";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Codec {
+    /// Picks the first encoding the client offered that the server supports,
+    /// from the comma separated `Sec-WebSocket-Protocol` header. Returns
+    /// `None` if it offered only unsupported protocols.
+    pub fn negotiate(offered: Option<&str>) -> Option<Codec> {
+        let Some(offered) = offered else {
+            return Some(Codec::Json);
+        };
+        offered
+            .split(',')
+            .find_map(|protocol| match protocol.trim() {
+                JSON_PROTOCOL => Some(Codec::Json),
+                MSGPACK_PROTOCOL => Some(Codec::MessagePack),
+                CBOR_PROTOCOL => Some(Codec::Cbor),
+                _ => None,
+            })
+    }
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Codec::Json => JSON_PROTOCOL,
+            Codec::MessagePack => MSGPACK_PROTOCOL,
+            Codec::Cbor => CBOR_PROTOCOL,
+        }
+    }
+
+    /// Encodes a message for a peer using this codec.
+    pub fn encode(self, value: &Value) -> Message {
+        match self {
+            Codec::Json => Message::Text(value.to_string()),
+            Codec::MessagePack => Message::Binary(
+                rmp_serde::to_vec(value).expect("JSON values encode as MessagePack"),
+            ),
+            Codec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).expect("JSON values encode as CBOR");
+                Message::Binary(bytes)
+            }
+        }
+    }
+
+    /// Decodes a message from a peer using this codec. Text frames are
+    /// always read as JSON.
+    pub fn decode(self, msg: &Message) -> Result<Value, String> {
+        match (msg, self) {
+            (Message::Text(text), _) => serde_json::from_str(text).map_err(|e| e.to_string()),
+            (Message::Binary(bytes), Codec::MessagePack) => {
+                rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+            }
+            (Message::Binary(bytes), Codec::Cbor) => {
+                ciborium::from_reader(bytes.as_slice()).map_err(|e| e.to_string())
+            }
+            (Message::Binary(_), Codec::Json) => {
+                Err("Binary messages need a binary subprotocol".to_string())
+            }
+            _ => Err("Unexpected message kind".to_string()),
+        }
+    }
+}
+
+/// Compresses an encoded message if it is at least `threshold` bytes and
+/// compressing makes it smaller.
+pub fn compress(message: Message, threshold: usize) -> Message {
+    let bytes = match &message {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(bytes) => bytes.as_slice(),
+        _ => return message,
+    };
+    if bytes.len() < threshold {
+        return message;
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    let compressed = encoder
+        .write_all(bytes)
+        .and_then(|()| encoder.finish())
+        .expect("writing to a Vec does not fail");
+    if compressed.len() < bytes.len() {
+        Message::Binary(compressed)
+    } else {
+        message
+    }
+}
+
+/// One JSON message encoded for each codec at most once, for broadcasting
+/// to peers that use different codecs or compression.
+pub struct Encodings<'a> {
+    json: &'a str,
+    value: Option<Value>,
+    binary: Vec<(Codec, Message)>,
+    compressed: Vec<(Codec, Message)>,
+}
+
+impl<'a> Encodings<'a> {
+    pub fn new(json: &'a str) -> Self {
+        Encodings {
+            json,
+            value: None,
+            binary: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+
+    /// The message for a peer using `codec`, compressed if the peer asked
+    /// for messages over some size to be.
+    pub fn message_for(&mut self, codec: Codec, compress_over: Option<usize>) -> Message {
+        let Some(threshold) = compress_over else {
+            return self.message(codec);
+        };
+        if let Some((_, message)) = self.compressed.iter().find(|(c, _)| *c == codec) {
+            return message.clone();
+        }
+        let message = compress(self.message(codec), threshold);
+        self.compressed.push((codec, message.clone()));
+        message
+    }
+
+    pub fn message(&mut self, codec: Codec) -> Message {
+        if codec == Codec::Json {
+            return Message::Text(self.json.to_string());
+        }
+        if let Some((_, message)) = self.binary.iter().find(|(c, _)| *c == codec) {
+            return message.clone();
+        }
+        let json = self.json;
+        let value = self
+            .value
+            .get_or_insert_with(|| serde_json::from_str(json).unwrap_or(Value::Null));
+        let message = codec.encode(value);
+        self.binary.push((codec, message.clone()));
+        message
+    }
+}
@@ -0,0 +1,175 @@
+//! Comment threads anchored to ranges of a document.
+//!
+//! A thread's anchor moves with the text as edits are applied; a thread
+//! whose text is deleted entirely is marked orphaned and stays that way.
+
+use serde::{Deserialize, Serialize};
+
+use crate::anchor::Anchor;
+use crate::history::now_ms;
+use crate::ot::Op;
+
+pub const UNKNOWN_THREAD: &str = "No comment thread with that id exists.";
+pub const UNKNOWN_COMMENT: &str = "No comment with that id exists in the thread.";
+pub const INVALID_ANCHOR: &str = "Comments must be anchored to a non-empty range of the document.";
+pub const INVALID_COMMENT: &str = "Comments must be 1 to 10000 characters long.";
+pub const NOT_AUTHOR: &str = "Only the author of a comment can delete it.";
+
+const MAX_COMMENT_LENGTH: usize = 10_000;
+
+/// The code clients receive for a comment error.
+pub fn error_code(error: &str) -> &'static str {
+    match error {
+        UNKNOWN_THREAD => "unknown_thread",
+        UNKNOWN_COMMENT => "unknown_comment",
+        INVALID_ANCHOR => "invalid_anchor",
+        INVALID_COMMENT => "invalid_comment",
+        NOT_AUTHOR => "not_author",
+        _ => "invalid_request",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: u64,
+    pub author: Option<String>,
+    pub text: String,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Thread {
+    pub id: u64,
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    pub resolved: bool,
+    /// The opening comment followed by its replies.
+    pub comments: Vec<Comment>,
+}
+
+/// The comment threads of one document.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Comments {
+    next_id: u64,
+    threads: Vec<Thread>,
+}
+
+impl Comments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn threads(&self) -> &[Thread] {
+        &self.threads
+    }
+
+    /// Moves every anchor past an operation applied to the document.
+    pub fn transform(&mut self, op: &Op) {
+        for thread in &mut self.threads {
+            thread.anchor.transform(op);
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn new_comment(&mut self, author: Option<&str>, text: &str) -> Result<Comment, &'static str> {
+        let length = text.chars().count();
+        if length == 0 || length > MAX_COMMENT_LENGTH {
+            return Err(INVALID_COMMENT);
+        }
+        Ok(Comment {
+            id: self.next_id(),
+            author: author.map(str::to_string),
+            text: text.to_string(),
+            timestamp_ms: now_ms(),
+        })
+    }
+
+    fn thread_mut(&mut self, id: u64) -> Result<&mut Thread, &'static str> {
+        self.threads
+            .iter_mut()
+            .find(|thread| thread.id == id)
+            .ok_or(UNKNOWN_THREAD)
+    }
+
+    /// Starts a thread on `start..end` of `content`.
+    pub fn create(
+        &mut self,
+        content: &str,
+        start: usize,
+        end: usize,
+        author: Option<&str>,
+        text: &str,
+    ) -> Result<&Thread, &'static str> {
+        if start >= end || content.get(start..end).is_none() {
+            return Err(INVALID_ANCHOR);
+        }
+        let comment = self.new_comment(author, text)?;
+        let id = self.next_id();
+        self.threads.push(Thread {
+            id,
+            anchor: Anchor::new(start, end),
+            resolved: false,
+            comments: vec![comment],
+        });
+        Ok(self.threads.last().expect("just pushed"))
+    }
+
+    pub fn reply(
+        &mut self,
+        thread: u64,
+        author: Option<&str>,
+        text: &str,
+    ) -> Result<&Thread, &'static str> {
+        let comment = self.new_comment(author, text)?;
+        let thread = self.thread_mut(thread)?;
+        thread.comments.push(comment);
+        Ok(thread)
+    }
+
+    pub fn set_resolved(&mut self, thread: u64, resolved: bool) -> Result<&Thread, &'static str> {
+        let thread = self.thread_mut(thread)?;
+        thread.resolved = resolved;
+        Ok(thread)
+    }
+
+    /// Deletes a single comment, or the whole thread when `comment` is
+    /// `None` or is the opening comment. Only a comment's author may delete
+    /// it; deleting a thread needs the author of its opening comment.
+    ///
+    /// Returns the thread if it still exists.
+    pub fn delete(
+        &mut self,
+        thread_id: u64,
+        comment: Option<u64>,
+        author: Option<&str>,
+    ) -> Result<Option<&Thread>, &'static str> {
+        let thread_index = self
+            .threads
+            .iter()
+            .position(|thread| thread.id == thread_id)
+            .ok_or(UNKNOWN_THREAD)?;
+        let thread = &mut self.threads[thread_index];
+        let index = match comment {
+            Some(comment) => thread
+                .comments
+                .iter()
+                .position(|existing| existing.id == comment)
+                .ok_or(UNKNOWN_COMMENT)?,
+            None => 0,
+        };
+        if thread.comments[index].author.as_deref() != author {
+            return Err(NOT_AUTHOR);
+        }
+
+        if index > 0 {
+            thread.comments.remove(index);
+            return Ok(Some(&self.threads[thread_index]));
+        }
+        self.threads.remove(thread_index);
+        Ok(None)
+    }
+}
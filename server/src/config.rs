@@ -0,0 +1,277 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Runtime configuration for the server.
+///
+/// Every field can be overridden through an `EDITOR_*` environment variable,
+/// see [`ServerConfig::from_env`].
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub limits: LimitsConfig,
+    /// Directory the document is persisted to. Nothing is persisted when unset.
+    pub data_dir: Option<PathBuf>,
+    /// Documents nobody is connected to are saved and dropped from memory
+    /// after this long, when persistence is enabled.
+    pub idle_timeout: Duration,
+    /// Bytes of documents kept in memory, beyond which the least recently
+    /// used documents nobody is connected to are dropped early. Zero means
+    /// no limit.
+    pub memory_budget: usize,
+    /// How long a shutdown waits for connections to close before giving up on them.
+    pub shutdown_timeout: Duration,
+    /// Messages at least this many bytes are compressed for connections
+    /// that ask for compression.
+    pub compression_threshold: usize,
+    /// Documents larger than this many bytes are sent to joining
+    /// connections in chunks of this size.
+    pub initial_chunk_size: usize,
+    /// Bearer token every HTTP request other than a health probe must
+    /// carry. When unset, reads are open and changes are refused.
+    pub api_token: Option<String>,
+    pub cluster: ClusterConfig,
+    pub replication: ReplicationConfig,
+    pub logging: LoggingConfig,
+}
+
+/// How this server shares documents with other nodes.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// This node's id. The server runs on its own when unset.
+    pub node_id: Option<String>,
+    /// Address the backplane listens on for other nodes.
+    pub backplane_addr: String,
+    /// The other nodes.
+    pub peers: Vec<ClusterPeer>,
+}
+
+/// How this server replicates documents to a hot standby. A server either
+/// accepts followers or follows a primary, and neither is combined with a
+/// [`ClusterConfig`].
+#[derive(Debug, Clone, Default)]
+pub struct ReplicationConfig {
+    /// Address a primary accepts followers on. Unset unless this server is
+    /// a primary.
+    pub listen: Option<String>,
+    /// Replication address of the primary this server follows.
+    pub follow: Option<String>,
+}
+
+/// Another node, written `id=host:port` with the address of its backplane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterPeer {
+    pub id: String,
+    pub addr: String,
+}
+
+impl FromStr for ClusterPeer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((id, addr)) if !id.is_empty() && !addr.is_empty() => Ok(ClusterPeer {
+                id: id.to_string(),
+                addr: addr.to_string(),
+            }),
+            _ => Err(format!("Expected id=host:port, got {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoggingConfig {
+    /// `tracing_subscriber::EnvFilter` directives, e.g. `info` or
+    /// `warn,collaborative_editor_server=debug`.
+    pub filter: String,
+    pub format: LogFormat,
+    /// OTLP/gRPC collector traces are exported to when the server is built
+    /// with the `otel` feature.
+    pub otlp_endpoint: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Limits applied to every connection to protect the server from a single
+/// misbehaving client.
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Largest WebSocket message accepted, in bytes.
+    pub max_message_size: usize,
+    /// Largest single WebSocket frame accepted, in bytes.
+    pub max_frame_size: usize,
+    /// Largest document `apply_edit` will grow to, in bytes.
+    pub max_document_size: usize,
+    /// Sustained edits per second allowed on one connection.
+    pub connection_edits_per_sec: f64,
+    /// Edits a connection may send in a burst before being throttled.
+    pub connection_burst: f64,
+    /// Sustained edits per second allowed for one IP address across
+    /// connections.
+    pub user_edits_per_sec: f64,
+    /// Edits an IP address may send in a burst before being throttled.
+    pub user_burst: f64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: "0.0.0.0:8080".to_string(),
+            limits: LimitsConfig::default(),
+            data_dir: None,
+            idle_timeout: Duration::from_secs(300),
+            memory_budget: 0,
+            shutdown_timeout: Duration::from_secs(5),
+            compression_threshold: 8 << 10,
+            initial_chunk_size: 256 << 10,
+            api_token: None,
+            cluster: ClusterConfig::default(),
+            replication: ReplicationConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            node_id: None,
+            backplane_addr: "0.0.0.0:9090".to_string(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+            otlp_endpoint: "http://localhost:4317".to_string(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_size: 1 << 20,
+            max_frame_size: 1 << 20,
+            max_document_size: 16 << 20,
+            connection_edits_per_sec: 50.0,
+            connection_burst: 100.0,
+            user_edits_per_sec: 100.0,
+            user_burst: 200.0,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the defaults, overridden by any
+    /// `EDITOR_*` environment variables that are set.
+    pub fn from_env() -> Self {
+        let defaults = ServerConfig::default();
+        let limits = defaults.limits;
+        ServerConfig {
+            addr: env::var("EDITOR_ADDR").unwrap_or(defaults.addr),
+            limits: LimitsConfig {
+                max_message_size: env_or("EDITOR_MAX_MESSAGE_SIZE", limits.max_message_size),
+                max_frame_size: env_or("EDITOR_MAX_FRAME_SIZE", limits.max_frame_size),
+                max_document_size: env_or("EDITOR_MAX_DOCUMENT_SIZE", limits.max_document_size),
+                connection_edits_per_sec: env_or(
+                    "EDITOR_CONNECTION_EDITS_PER_SEC",
+                    limits.connection_edits_per_sec,
+                ),
+                connection_burst: env_or("EDITOR_CONNECTION_BURST", limits.connection_burst),
+                user_edits_per_sec: env_or("EDITOR_USER_EDITS_PER_SEC", limits.user_edits_per_sec),
+                user_burst: env_or("EDITOR_USER_BURST", limits.user_burst),
+            },
+            data_dir: env::var_os("EDITOR_DATA_DIR").map(PathBuf::from),
+            idle_timeout: Duration::from_secs(env_or(
+                "EDITOR_IDLE_TIMEOUT_SECS",
+                defaults.idle_timeout.as_secs(),
+            )),
+            memory_budget: env_or("EDITOR_MEMORY_BUDGET", defaults.memory_budget),
+            shutdown_timeout: Duration::from_secs(env_or(
+                "EDITOR_SHUTDOWN_TIMEOUT_SECS",
+                defaults.shutdown_timeout.as_secs(),
+            )),
+            compression_threshold: env_or(
+                "EDITOR_COMPRESSION_THRESHOLD",
+                defaults.compression_threshold,
+            ),
+            initial_chunk_size: env_or("EDITOR_INITIAL_CHUNK_SIZE", defaults.initial_chunk_size),
+            api_token: env::var("EDITOR_API_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            cluster: ClusterConfig {
+                node_id: env::var("EDITOR_NODE_ID").ok(),
+                backplane_addr: env::var("EDITOR_BACKPLANE_ADDR")
+                    .unwrap_or(defaults.cluster.backplane_addr),
+                peers: env_list("EDITOR_CLUSTER_PEERS"),
+            },
+            replication: ReplicationConfig {
+                listen: env::var("EDITOR_REPLICATION_ADDR").ok(),
+                follow: env::var("EDITOR_FOLLOW").ok(),
+            },
+            logging: LoggingConfig {
+                filter: env::var("EDITOR_LOG").unwrap_or(defaults.logging.filter),
+                format: env_or("EDITOR_LOG_FORMAT", defaults.logging.format),
+                otlp_endpoint: env::var("EDITOR_OTLP_ENDPOINT")
+                    .unwrap_or(defaults.logging.otlp_endpoint),
+            },
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                // Logging is configured from these values, so it is not set up yet.
+                eprintln!("Ignoring invalid value for {}: {}", key, value);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Reads a comma separated list, skipping entries that do not parse.
+fn env_list<T: FromStr<Err = String>>(key: &str) -> Vec<T> {
+    let Ok(value) = env::var(key) else {
+        return Vec::new();
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                eprintln!("Ignoring invalid entry in {}: {}", key, e);
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,307 @@
+//! Diffs between two versions or checkpoints of a document.
+
+use serde::Serialize;
+use similar::{DiffTag, TextDiff};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::attribution::Attribution;
+use crate::checkpoint::UNKNOWN_CHECKPOINT;
+use crate::history::HISTORY_UNAVAILABLE;
+use crate::DocumentState;
+
+/// Lines of unchanged context around each hunk.
+const CONTEXT_LINES: usize = 3;
+/// How long the diff algorithm may search for a minimal diff before settling
+/// for a larger one.
+const DIFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// One end of a diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEnd {
+    Version(usize),
+    Checkpoint(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    pub from: usize,
+    pub to: usize,
+    /// The diff in unified format, as produced by `diff -u`.
+    pub unified: String,
+    pub hunks: Vec<Hunk>,
+}
+
+/// A group of changed lines with their surrounding context. Line numbers
+/// start at 1.
+#[derive(Debug, Serialize)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Line {
+    /// `equal`, `delete` or `insert`.
+    pub tag: &'static str,
+    pub old_line: Option<usize>,
+    pub new_line: Option<usize>,
+    /// The line split into the parts that changed and the parts that did
+    /// not, for character-level highlighting.
+    pub segments: Vec<Segment>,
+    /// Who inserted or deleted the changed parts of the line. Empty for
+    /// unchanged lines, or when history between the two versions is not
+    /// available.
+    pub authors: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Segment {
+    pub text: String,
+    pub changed: bool,
+}
+
+/// Diffs the document between two points.
+pub fn diff_document(
+    doc: &DocumentState,
+    from: &DiffEnd,
+    to: &DiffEnd,
+) -> Result<Diff, &'static str> {
+    let (from, old) = resolve(doc, from)?;
+    let (to, new) = resolve(doc, to)?;
+
+    // Authors can only be attributed when replaying forwards through
+    // history that is still available.
+    let ops = if from <= to {
+        doc.ops_since(from).map(|ops| &ops[..to - from])
+    } else {
+        None
+    };
+    let attribution = ops.map(|ops| Attribution::replay(old.len(), ops));
+
+    let diff = TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_lines(old.as_str(), new.as_str());
+    let old_offsets = line_offsets(diff.old_slices());
+    let new_offsets = line_offsets(diff.new_slices());
+
+    let mut hunks = Vec::new();
+    for group in diff.grouped_ops(CONTEXT_LINES) {
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            continue;
+        };
+        let mut lines = Vec::new();
+        for op in &group {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            let old_lines = &diff.old_slices()[old_range.clone()];
+            let new_lines = &diff.new_slices()[new_range.clone()];
+            let (old_segments, new_segments) = match tag {
+                DiffTag::Replace => char_segments(old_lines, new_lines),
+                _ => (
+                    whole_lines(old_lines, tag == DiffTag::Delete),
+                    whole_lines(new_lines, tag == DiffTag::Insert),
+                ),
+            };
+
+            if tag == DiffTag::Equal {
+                for (index, segments) in new_segments.into_iter().enumerate() {
+                    lines.push(Line {
+                        tag: "equal",
+                        old_line: Some(old_range.start + index + 1),
+                        new_line: Some(new_range.start + index + 1),
+                        segments,
+                        authors: BTreeSet::new(),
+                    });
+                }
+                continue;
+            }
+            for (index, segments) in old_segments.into_iter().enumerate() {
+                let line = old_range.start + index;
+                let authors = attribution.as_ref().map(|attribution| {
+                    attribution.deleted_by(changed_range(&segments, old_offsets[line]))
+                });
+                lines.push(Line {
+                    tag: "delete",
+                    old_line: Some(line + 1),
+                    new_line: None,
+                    segments,
+                    authors: owned(authors),
+                });
+            }
+            for (index, segments) in new_segments.into_iter().enumerate() {
+                let line = new_range.start + index;
+                let authors = attribution.as_ref().map(|attribution| {
+                    attribution.inserted_by(changed_range(&segments, new_offsets[line]))
+                });
+                lines.push(Line {
+                    tag: "insert",
+                    old_line: None,
+                    new_line: Some(line + 1),
+                    segments,
+                    authors: owned(authors),
+                });
+            }
+        }
+
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+        hunks.push(Hunk {
+            old_start: old_range.start + 1,
+            old_lines: old_range.len(),
+            new_start: new_range.start + 1,
+            new_lines: new_range.len(),
+            lines,
+        });
+    }
+
+    let unified = diff
+        .unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(&format!("version {}", from), &format!("version {}", to))
+        .to_string();
+    Ok(Diff {
+        from,
+        to,
+        unified,
+        hunks,
+    })
+}
+
+/// The version and content at one end of a diff.
+fn resolve(doc: &DocumentState, end: &DiffEnd) -> Result<(usize, String), &'static str> {
+    match end {
+        DiffEnd::Version(version) => doc
+            .content_at(*version)
+            .map(|content| (*version, content))
+            .ok_or(HISTORY_UNAVAILABLE),
+        DiffEnd::Checkpoint(name) => doc
+            .checkpoints
+            .get(name)
+            .map(|checkpoint| (checkpoint.version, checkpoint.content.clone()))
+            .ok_or(UNKNOWN_CHECKPOINT),
+    }
+}
+
+fn owned(authors: Option<BTreeSet<&str>>) -> BTreeSet<String> {
+    authors.into_iter().flatten().map(str::to_string).collect()
+}
+
+/// Each line as a single segment.
+fn whole_lines(lines: &[&str], changed: bool) -> Vec<Vec<Segment>> {
+    lines
+        .iter()
+        .map(|line| {
+            vec![Segment {
+                text: line.to_string(),
+                changed,
+            }]
+        })
+        .collect()
+}
+
+/// Diffs a block of replaced lines character by character, returning the
+/// segments of each old line and of each new line.
+fn char_segments(old_lines: &[&str], new_lines: &[&str]) -> (Vec<Vec<Segment>>, Vec<Vec<Segment>>) {
+    let old_block = old_lines.concat();
+    let new_block = new_lines.concat();
+    let diff = TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_chars(old_block.as_str(), new_block.as_str());
+    let old_offsets = char_offsets(diff.old_slices());
+    let new_offsets = char_offsets(diff.new_slices());
+
+    let mut old_marks = Vec::new();
+    let mut new_marks = Vec::new();
+    for op in diff.ops() {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let changed = tag != DiffTag::Equal;
+        if !old_range.is_empty() {
+            old_marks.push((
+                old_offsets[old_range.start]..old_offsets[old_range.end],
+                changed,
+            ));
+        }
+        if !new_range.is_empty() {
+            new_marks.push((
+                new_offsets[new_range.start]..new_offsets[new_range.end],
+                changed,
+            ));
+        }
+    }
+    (
+        split_lines(old_lines, &old_block, &old_marks),
+        split_lines(new_lines, &new_block, &new_marks),
+    )
+}
+
+/// Byte offset of each character, followed by the total length.
+fn char_offsets(chars: &[&str]) -> Vec<usize> {
+    let mut offsets = line_offsets(chars);
+    offsets.push(chars.iter().map(|c| c.len()).sum());
+    offsets
+}
+
+/// Cuts marked ranges of a block into segments for each of its lines.
+fn split_lines(lines: &[&str], block: &str, marks: &[(Range<usize>, bool)]) -> Vec<Vec<Segment>> {
+    let mut offset = 0;
+    lines
+        .iter()
+        .map(|line| {
+            let line_range = offset..offset + line.len();
+            offset = line_range.end;
+            let mut segments: Vec<Segment> = Vec::new();
+            for (range, changed) in marks {
+                let start = range.start.max(line_range.start);
+                let end = range.end.min(line_range.end);
+                if start >= end {
+                    continue;
+                }
+                match segments.last_mut() {
+                    Some(last) if last.changed == *changed => {
+                        last.text.push_str(&block[start..end])
+                    }
+                    _ => segments.push(Segment {
+                        text: block[start..end].to_string(),
+                        changed: *changed,
+                    }),
+                }
+            }
+            segments
+        })
+        .collect()
+}
+
+/// Byte offset of the start of each line.
+fn line_offsets(lines: &[&str]) -> Vec<usize> {
+    let mut offset = 0;
+    lines
+        .iter()
+        .map(|line| {
+            let start = offset;
+            offset += line.len();
+            start
+        })
+        .collect()
+}
+
+/// The byte range covering the changed segments of a line starting at
+/// `line_start`, or the whole line if nothing within it was highlighted.
+fn changed_range(segments: &[Segment], line_start: usize) -> Range<usize> {
+    let mut offset = line_start;
+    let mut changed: Option<Range<usize>> = None;
+    for segment in segments {
+        let range = offset..offset + segment.text.len();
+        if segment.changed {
+            changed = Some(match changed {
+                Some(changed) => changed.start..range.end,
+                None => range.clone(),
+            });
+        }
+        offset = range.end;
+    }
+    changed.unwrap_or(line_start..offset)
+}
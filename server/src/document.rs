@@ -0,0 +1,470 @@
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::authorship::Authorship;
+use crate::checkpoint::{edit_between, Checkpoints, UNKNOWN_CHECKPOINT};
+use crate::checksum::{self, checksum};
+use crate::client_ops::{ClientOps, OpId};
+use crate::comments::Comments;
+use crate::formatting::{FormatEdit, Formatting, INVALID_FORMAT};
+use crate::history::{now_ms, AppliedOp, History, HISTORY_UNAVAILABLE};
+use crate::ot::{self, Op};
+use crate::suggestions::{Suggestion, Suggestions, SUGGESTION_ORPHANED, UNKNOWN_SUGGESTION};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Edit {
+    pub position: usize,
+    pub insert: Option<String>,
+    pub delete: Option<usize>,
+    pub version: usize,
+}
+
+impl Edit {
+    /// Number of bytes inserted or deleted by the edit.
+    pub fn op_size(&self) -> usize {
+        match (&self.insert, self.delete) {
+            (Some(insert), Some(delete)) => insert.len().saturating_add(delete),
+            (Some(insert), None) => insert.len(),
+            (None, Some(delete)) => delete,
+            (None, None) => 0,
+        }
+    }
+}
+
+/// Error returned by `apply_edit` when an insert would grow the document past
+/// its size limit.
+pub const DOCUMENT_TOO_LARGE: &str = "Document size limit exceeded.";
+
+/// Error returned by `rebase_pending` when the pending edits do not apply to
+/// the version they were made on.
+pub const INVALID_PENDING: &str = "Pending edits do not apply to the version they were made on.";
+
+pub struct DocumentState {
+    pub content: String,
+    pub version: usize,
+    /// Largest size in bytes the content may grow to, if limited.
+    pub max_size: Option<usize>,
+    pub history: History,
+    /// Which edit inserted each part of the content.
+    pub authorship: Authorship,
+    /// Rich text attributes of the content.
+    pub formatting: Formatting,
+    pub checkpoints: Checkpoints,
+    pub comments: Comments,
+    /// Proposed edits waiting to be accepted or rejected.
+    pub suggestions: Suggestions,
+    /// The last tagged edit applied for each client.
+    pub client_ops: ClientOps,
+}
+
+impl DocumentState {
+    pub fn new() -> Self {
+        DocumentState {
+            content: String::new(),
+            version: 0,
+            max_size: None,
+            history: History::new("", 0, now_ms()),
+            authorship: Authorship::default(),
+            formatting: Formatting::default(),
+            checkpoints: Checkpoints::new(),
+            comments: Comments::new(),
+            suggestions: Suggestions::new(),
+            client_ops: ClientOps::new(),
+        }
+    }
+
+    pub fn with_max_size(max_size: usize) -> Self {
+        DocumentState {
+            max_size: Some(max_size),
+            ..DocumentState::new()
+        }
+    }
+
+    pub fn apply_edit(&mut self, edit: &Edit) -> Result<(), &'static str> {
+        self.apply(edit, None)
+    }
+
+    /// Applies an edit made by `author`, who is recorded in the history.
+    pub fn apply_edit_by(&mut self, edit: &Edit, author: &str) -> Result<(), &'static str> {
+        self.apply(edit, Some(author))
+    }
+
+    /// Applies an edit tagged with `id` unless the client already had it
+    /// applied, returning whether it was applied now.
+    pub fn apply_edit_once(
+        &mut self,
+        edit: &Edit,
+        author: &str,
+        id: &OpId,
+    ) -> Result<bool, &'static str> {
+        if self.client_ops.is_applied(id) {
+            return Ok(false);
+        }
+        self.apply(edit, Some(author))?;
+        self.client_ops.record(id, self.version);
+        Ok(true)
+    }
+
+    fn apply(&mut self, edit: &Edit, author: Option<&str>) -> Result<(), &'static str> {
+        // Check for version consistency
+        if edit.version != self.version {
+            debug!(
+                edit_version = edit.version,
+                document_version = self.version,
+                "Version mismatch"
+            );
+            return Err("Version mismatch");
+        }
+
+        // An edit with both parts replaces the deleted range with the insert
+        if let (Some(insert), Some(delete)) = (&edit.insert, edit.delete) {
+            let Some(end) = edit.position.checked_add(delete) else {
+                return Err("Delete range is not valid UTF-8 boundaries.");
+            };
+            if !self.content.is_char_boundary(edit.position) || !self.content.is_char_boundary(end)
+            {
+                debug!(
+                    start = edit.position,
+                    end, "Replace range is not valid UTF-8 boundaries"
+                );
+                return Err("Delete range is not valid UTF-8 boundaries.");
+            }
+            if let Some(max_size) = self.max_size {
+                if self.content.len() - delete + insert.len() > max_size {
+                    debug!(
+                        insert_len = insert.len(),
+                        max_size, "Replace would exceed the document size limit"
+                    );
+                    return Err(DOCUMENT_TOO_LARGE);
+                }
+            }
+            let deleted = self.content[edit.position..end].to_string();
+            self.content.replace_range(edit.position..end, insert);
+            self.record(
+                Op::Replace {
+                    position: edit.position,
+                    deleted,
+                    inserted: insert.clone(),
+                },
+                author,
+            );
+        }
+        // Ensure valid UTF-8 character boundary for insertion
+        else if let Some(ref insert) = edit.insert {
+            if !self.content.is_char_boundary(edit.position) {
+                debug!(
+                    position = edit.position,
+                    "Insert position is not a valid UTF-8 boundary"
+                );
+                return Err("Insert position is not a valid UTF-8 boundary.");
+            }
+            if let Some(max_size) = self.max_size {
+                if self.content.len() + insert.len() > max_size {
+                    debug!(
+                        insert_len = insert.len(),
+                        max_size, "Insert would exceed the document size limit"
+                    );
+                    return Err(DOCUMENT_TOO_LARGE);
+                }
+            }
+            self.content.insert_str(edit.position, insert);
+            self.record(
+                Op::Insert {
+                    position: edit.position,
+                    text: insert.clone(),
+                },
+                author,
+            );
+        }
+        // Ensure valid UTF-8 character boundary for deletion
+        else if let Some(delete) = edit.delete {
+            let Some(end) = edit.position.checked_add(delete) else {
+                return Err("Delete range is not valid UTF-8 boundaries.");
+            };
+            if !self.content.is_char_boundary(edit.position) || !self.content.is_char_boundary(end)
+            {
+                debug!(
+                    start = edit.position,
+                    end, "Delete range is not valid UTF-8 boundaries"
+                );
+                return Err("Delete range is not valid UTF-8 boundaries.");
+            }
+            let deleted = self.content[edit.position..end].to_string();
+            self.content.replace_range(edit.position..end, "");
+            self.record(
+                Op::Delete {
+                    position: edit.position,
+                    text: deleted,
+                },
+                author,
+            );
+        } else {
+            // An edit with neither part still takes a version.
+            self.record(
+                Op::Insert {
+                    position: edit.position,
+                    text: String::new(),
+                },
+                author,
+            );
+        }
+
+        self.version += 1;
+        Ok(())
+    }
+
+    pub fn apply_format(&mut self, format: &FormatEdit) -> Result<(), &'static str> {
+        self.format(format, None)
+    }
+
+    /// Changes the formatting of a range on behalf of `author`. The text is
+    /// left as it is, but the change takes a version like any other edit.
+    pub fn apply_format_by(
+        &mut self,
+        format: &FormatEdit,
+        author: &str,
+    ) -> Result<(), &'static str> {
+        self.format(format, Some(author))
+    }
+
+    fn format(&mut self, format: &FormatEdit, author: Option<&str>) -> Result<(), &'static str> {
+        if format.version != self.version {
+            debug!(
+                edit_version = format.version,
+                document_version = self.version,
+                "Version mismatch"
+            );
+            return Err("Version mismatch");
+        }
+        let Some(end) = format.position.checked_add(format.length) else {
+            return Err(INVALID_FORMAT);
+        };
+        if format.length == 0
+            || !format.attributes.is_valid()
+            || !self.content.is_char_boundary(format.position)
+            || !self.content.is_char_boundary(end)
+        {
+            return Err(INVALID_FORMAT);
+        }
+        self.record(
+            Op::Format {
+                position: format.position,
+                length: format.length,
+                attributes: format.attributes.clone(),
+            },
+            author,
+        );
+        self.version += 1;
+        Ok(())
+    }
+
+    fn record(&mut self, op: Op, author: Option<&str>) {
+        let replaced = match &op {
+            Op::Format {
+                position, length, ..
+            } => self.formatting.spans_between(*position, position + length),
+            _ => Vec::new(),
+        };
+        let applied = AppliedOp {
+            version: self.version,
+            op,
+            author: author.map(str::to_string),
+            timestamp_ms: now_ms(),
+            replaced,
+        };
+        self.authorship.apply(&applied);
+        self.formatting.apply(&applied.op);
+        self.comments.transform(&applied.op);
+        self.suggestions.transform(&applied.op);
+        self.history.push(applied, &self.content);
+    }
+
+    /// Replaces the content with a snapshot taken at `version`. History
+    /// before the snapshot is not available.
+    pub fn restore(&mut self, content: String, version: usize) {
+        self.history = History::new(&content, version, now_ms());
+        self.authorship = Authorship::unknown(content.len());
+        self.formatting = Formatting::plain(content.len());
+        self.content = content;
+        self.version = version;
+    }
+
+    /// Replaces the document with the latest version of a saved history.
+    pub fn restore_history(&mut self, mut history: History) -> Result<(), &'static str> {
+        self.content = history.rebuild_snapshots()?;
+        self.version = history.latest_version();
+        let ops = history.ops_since(history.first_version()).unwrap_or(&[]);
+        self.authorship = Authorship::replay(history.base_content().len(), ops);
+        self.formatting = Formatting::replay(history.base_content().len(), ops);
+        // Histories saved before they were trimmed are replayed whole first.
+        history.trim();
+        self.history = history;
+        Ok(())
+    }
+
+    /// Roughly how many bytes the document takes up in memory, counting
+    /// its content and history.
+    pub fn memory_size(&self) -> usize {
+        self.content.len() + self.history.memory_size()
+    }
+
+    /// The operation that was applied to the document at `version`.
+    pub fn applied_op(&self, version: usize) -> Option<&AppliedOp> {
+        self.history.op(version)
+    }
+
+    /// The operations applied since `version`, or `None` if history does not
+    /// reach back that far.
+    pub fn ops_since(&self, version: usize) -> Option<&[AppliedOp]> {
+        self.history.ops_since(version)
+    }
+
+    /// The edit that rolls the content back to a checkpoint, or `None` if it
+    /// already matches.
+    pub fn checkpoint_restore_edit(&self, name: &str) -> Result<Option<Edit>, &'static str> {
+        let checkpoint = self.checkpoints.get(name).ok_or(UNKNOWN_CHECKPOINT)?;
+        Ok(edit_between(
+            &self.content,
+            &checkpoint.content,
+            self.version,
+        ))
+    }
+
+    /// Records an edit by `author` as a pending suggestion instead of
+    /// applying it.
+    pub fn suggest(&mut self, edit: &Edit, author: &str) -> Result<&Suggestion, &'static str> {
+        if edit.version != self.version {
+            return Err("Version mismatch");
+        }
+        self.suggestions.create(&self.content, edit, Some(author))
+    }
+
+    /// Applies a pending suggestion as an edit by its author and removes it,
+    /// returning the edit.
+    pub fn accept_suggestion(&mut self, id: u64) -> Result<Edit, &'static str> {
+        let suggestion = self.suggestions.get(id).ok_or(UNKNOWN_SUGGESTION)?;
+        if suggestion.anchor.orphaned {
+            return Err(SUGGESTION_ORPHANED);
+        }
+        let edit = suggestion.edit(self.version);
+        let author = suggestion.author.clone();
+        self.apply(&edit, author.as_deref())?;
+        self.suggestions.remove(id)?;
+        Ok(edit)
+    }
+
+    /// Applies edits a client made one after another on `version` without
+    /// hearing back, e.g. before reconnecting, on behalf of `author`. They
+    /// are transformed past everything applied since; the edits actually
+    /// applied are returned. The `version` of each pending edit is ignored.
+    ///
+    /// If `first` tags the first pending edit, the rest being numbered on
+    /// from it, those the server applied before the client lost touch are
+    /// skipped.
+    pub fn rebase_pending(
+        &mut self,
+        version: usize,
+        pending: &[Edit],
+        author: &str,
+        first: Option<&OpId>,
+    ) -> Result<Vec<Edit>, &'static str> {
+        self.rebase(version, pending, Some(author), first)
+    }
+
+    /// Applies edits made one after another on `version`, all or nothing,
+    /// transformed past everything applied since like
+    /// [`DocumentState::rebase_pending`]. Returns the edits applied.
+    pub fn apply_edits(
+        &mut self,
+        version: usize,
+        edits: &[Edit],
+        author: Option<&str>,
+    ) -> Result<Vec<Edit>, &'static str> {
+        self.rebase(version, edits, author, None)
+    }
+
+    fn rebase(
+        &mut self,
+        version: usize,
+        pending: &[Edit],
+        author: Option<&str>,
+        first: Option<&OpId>,
+    ) -> Result<Vec<Edit>, &'static str> {
+        let last_id = first.map(|id| OpId {
+            client_id: id.client_id.clone(),
+            seq: (id.seq + pending.len() as u64).saturating_sub(1),
+        });
+        let last_applied = first.and_then(|id| Some((id, self.client_ops.last(&id.client_id)?)));
+        let (version, pending) = match last_applied {
+            Some((id, last)) if last.seq >= id.seq => {
+                let done = usize::try_from(last.seq - id.seq + 1).unwrap_or(usize::MAX);
+                (last.version, &pending[done.min(pending.len())..])
+            }
+            _ => (version, pending),
+        };
+
+        let mut content = self.content_at(version).ok_or(HISTORY_UNAVAILABLE)?;
+        let mut ops = Vec::with_capacity(pending.len());
+        for edit in pending {
+            let op = Op::from_edit(edit, &content).ok_or(INVALID_PENDING)?;
+            op.apply(&mut content).map_err(|_| INVALID_PENDING)?;
+            ops.push(op);
+        }
+        let concurrent: Vec<Op> = self
+            .ops_since(version)
+            .ok_or(HISTORY_UNAVAILABLE)?
+            .iter()
+            .map(|applied| applied.op.clone())
+            .collect();
+        let rebased = ot::rebase(&ops, &concurrent);
+
+        // Check the size up front so the edits are applied all or nothing.
+        if let Some(max_size) = self.max_size {
+            let mut len = self.content.len();
+            for op in &rebased {
+                let edit = op.to_edit(0);
+                len = len - edit.delete.unwrap_or(0) + edit.insert.map_or(0, |text| text.len());
+                if len > max_size {
+                    return Err(DOCUMENT_TOO_LARGE);
+                }
+            }
+        }
+        let mut applied = Vec::with_capacity(rebased.len());
+        for op in rebased {
+            let edit = op.to_edit(self.version);
+            self.apply(&edit, author)?;
+            applied.push(edit);
+        }
+        if let Some(id) = last_id.filter(|_| !pending.is_empty()) {
+            self.client_ops.record(&id, self.version);
+        }
+        Ok(applied)
+    }
+
+    /// The checksum to send with the message announcing `version`, if that
+    /// is the current version and one is due.
+    pub fn checksum_for(&self, version: usize) -> Option<u32> {
+        (version == self.version && checksum::is_due(version)).then(|| checksum(&self.content))
+    }
+
+    /// The checksum of the content at `version`, if history reaches back
+    /// that far.
+    pub fn checksum_at(&self, version: usize) -> Option<u32> {
+        if version == self.version {
+            Some(checksum(&self.content))
+        } else {
+            self.content_at(version).map(|content| checksum(&content))
+        }
+    }
+
+    /// The content at an earlier `version`, if history reaches back that far.
+    pub fn content_at(&self, version: usize) -> Option<String> {
+        self.history.content_at(version)
+    }
+}
+
+impl Default for DocumentState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,236 @@
+//! Rich text attributes over ranges of a document.
+//!
+//! The content stays plain text; formatting is kept alongside it as runs of
+//! bytes sharing the same [`Attributes`]. Format operations change some
+//! attributes over a range, and text inserted next to formatted text picks
+//! up the formatting of the character before it, so typing at the end of a
+//! bold word stays bold. Links are the exception: text typed at either end
+//! of a link is not part of it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::AppliedOp;
+use crate::ot::Op;
+use crate::runs::{RunValue, Runs};
+
+pub const INVALID_FORMAT: &str = "Formats must change at least one attribute of a valid range.";
+
+/// Deepest heading level.
+const MAX_HEADING: u8 = 6;
+/// Longest link target accepted, in bytes.
+const MAX_LINK_LENGTH: usize = 2048;
+
+/// The formatting of a run of text. Unset attributes are left out when
+/// serialized.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bold: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub italic: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub code: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<u8>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Attributes {
+    pub fn is_plain(&self) -> bool {
+        *self == Attributes::default()
+    }
+
+    /// Applies a format's changes.
+    fn change(&mut self, changes: &AttributeChanges) {
+        if let Some(bold) = changes.bold {
+            self.bold = bold;
+        }
+        if let Some(italic) = changes.italic {
+            self.italic = italic;
+        }
+        if let Some(code) = changes.code {
+            self.code = code;
+        }
+        if let Some(link) = &changes.link {
+            self.link = (!link.is_empty()).then(|| link.clone());
+        }
+        if let Some(heading) = changes.heading {
+            self.heading = (heading > 0).then_some(heading);
+        }
+    }
+}
+
+/// The attributes a format operation changes. Attributes left out are kept
+/// as they are; `false`, an empty link and heading level 0 remove one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributeChanges {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<u8>,
+}
+
+impl AttributeChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == AttributeChanges::default()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.is_empty()
+            && self.heading.is_none_or(|heading| heading <= MAX_HEADING)
+            && self
+                .link
+                .as_ref()
+                .is_none_or(|link| link.len() <= MAX_LINK_LENGTH)
+    }
+
+    /// The changes that set every attribute these change back to its value
+    /// in `previous`.
+    pub fn restoring(&self, previous: &Attributes) -> AttributeChanges {
+        AttributeChanges {
+            bold: self.bold.map(|_| previous.bold),
+            italic: self.italic.map(|_| previous.italic),
+            code: self.code.map(|_| previous.code),
+            link: self
+                .link
+                .as_ref()
+                .map(|_| previous.link.clone().unwrap_or_default()),
+            heading: self.heading.map(|_| previous.heading.unwrap_or(0)),
+        }
+    }
+}
+
+/// A request to change the formatting of `length` bytes at `position` of
+/// the document at `version`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FormatEdit {
+    pub position: usize,
+    pub length: usize,
+    pub attributes: AttributeChanges,
+    pub version: usize,
+}
+
+/// A byte range of the content and its formatting.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatSpan {
+    pub start: usize,
+    pub end: usize,
+    pub attributes: Attributes,
+}
+
+impl RunValue for Attributes {}
+
+/// The formatting of a document, kept up to date as operations are applied.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Formatting {
+    runs: Runs<Attributes>,
+}
+
+impl Formatting {
+    /// Formatting of `len` bytes of plain text.
+    pub fn plain(len: usize) -> Self {
+        Formatting {
+            runs: Runs::filled(len, Attributes::default()),
+        }
+    }
+
+    /// Rebuilds formatting by replaying `ops` onto `base_len` bytes of plain
+    /// text.
+    pub fn replay(base_len: usize, ops: &[AppliedOp]) -> Self {
+        let mut formatting = Formatting::plain(base_len);
+        for applied in ops {
+            formatting.apply(&applied.op);
+        }
+        formatting
+    }
+
+    /// Length in bytes of the content the formatting covers.
+    pub fn content_len(&self) -> usize {
+        self.runs.content_len()
+    }
+
+    /// Updates the runs for an operation that was just applied.
+    pub fn apply(&mut self, op: &Op) {
+        match op {
+            Op::Insert { position, text } => self.insert(*position, text.len()),
+            Op::Delete { position, text } => self.delete(*position, text.len()),
+            Op::Replace {
+                position,
+                deleted,
+                inserted,
+            } => {
+                self.delete(*position, deleted.len());
+                self.insert(*position, inserted.len());
+            }
+            Op::Format {
+                position,
+                length,
+                attributes,
+            } => self.format(*position, *length, attributes),
+        }
+    }
+
+    fn insert(&mut self, position: usize, len: usize) {
+        let before = position
+            .checked_sub(1)
+            .and_then(|at| self.runs.value_at(at));
+        let after = self.runs.value_at(position);
+        let mut attributes = before.or(after).cloned().unwrap_or_default();
+        if before.and_then(|a| a.link.as_ref()) != after.and_then(|a| a.link.as_ref()) {
+            attributes.link = None;
+        }
+        self.runs.insert(position, len, attributes);
+        self.runs.merge();
+    }
+
+    fn delete(&mut self, position: usize, len: usize) {
+        self.runs.delete(position, len);
+        self.runs.merge();
+    }
+
+    fn format(&mut self, position: usize, len: usize, changes: &AttributeChanges) {
+        self.runs
+            .update(position, len, |attributes| attributes.change(changes));
+        self.runs.merge();
+    }
+
+    /// The formatted parts of the content, in order. Plain text is left out.
+    pub fn spans(&self) -> Vec<FormatSpan> {
+        self.runs
+            .iter()
+            .filter(|(_, attributes)| !attributes.is_plain())
+            .map(|(range, attributes)| FormatSpan {
+                start: range.start,
+                end: range.end,
+                attributes: attributes.clone(),
+            })
+            .collect()
+    }
+
+    /// The runs covering `start..end`, cut to that range. Plain text is
+    /// included.
+    pub fn spans_between(&self, start: usize, end: usize) -> Vec<FormatSpan> {
+        self.runs
+            .iter()
+            .map(|(range, attributes)| (range.start.max(start), range.end.min(end), attributes))
+            .filter(|(run_start, run_end, _)| run_start < run_end)
+            .map(|(start, end, attributes)| FormatSpan {
+                start,
+                end,
+                attributes: attributes.clone(),
+            })
+            .collect()
+    }
+}
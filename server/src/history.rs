@@ -0,0 +1,241 @@
+//! The operation history of a document.
+//!
+//! Every applied operation is kept with its author and the time it was
+//! applied. A copy of the content is also taken every [`SNAPSHOT_INTERVAL`]
+//! operations, so the content at any retained version is rebuilt by replaying
+//! at most that many operations onto the nearest snapshot. Only the last
+//! [`MAX_HISTORY_OPS`] or so operations are retained; older ones are dropped
+//! a snapshot interval at a time, and the versions before them with them.
+
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::formatting::FormatSpan;
+use crate::ot::Op;
+
+/// Number of operations between in-memory content snapshots.
+pub const SNAPSHOT_INTERVAL: usize = 100;
+
+/// Operations retained in history. Up to [`SNAPSHOT_INTERVAL`] more are
+/// kept until the oldest can be dropped along with their snapshot.
+pub const MAX_HISTORY_OPS: usize = 1000;
+
+/// Number of history entries returned by a query that does not set a limit.
+pub const DEFAULT_HISTORY_PAGE: usize = 100;
+/// Most history entries returned by one query.
+pub const MAX_HISTORY_PAGE: usize = 1000;
+
+pub const HISTORY_UNAVAILABLE: &str = "That point is not in the document history.";
+
+/// A point in a document's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryPoint {
+    Version(usize),
+    /// Milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+/// Milliseconds since the Unix epoch, the unit history timestamps use.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// An edit as it was applied to a document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppliedOp {
+    /// Version of the document the operation was applied to; applying it
+    /// produced `version + 1`.
+    pub version: usize,
+    #[serde(flatten)]
+    pub op: Op,
+    /// The user who made the edit, if known.
+    pub author: Option<String>,
+    pub timestamp_ms: u64,
+    /// For a format, the formatting its range had before, so it can be
+    /// undone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaced: Vec<FormatSpan>,
+}
+
+impl AppliedOp {
+    /// The operations that revert this one.
+    pub fn invert(&self) -> Vec<Op> {
+        self.op.invert(&self.replaced)
+    }
+}
+
+/// The content of a document at some version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistorySnapshot {
+    pub version: usize,
+    pub timestamp_ms: u64,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    /// The oldest version history reaches back to.
+    base: HistorySnapshot,
+    /// Every operation applied since `base`, oldest first.
+    ops: Vec<AppliedOp>,
+    /// Periodic copies of the content after `base`, oldest first. Rebuilt
+    /// rather than persisted.
+    #[serde(skip)]
+    snapshots: Vec<HistorySnapshot>,
+}
+
+impl History {
+    /// Starts a history whose oldest retained version is `version`.
+    pub fn new(content: &str, version: usize, timestamp_ms: u64) -> Self {
+        let base = HistorySnapshot {
+            version,
+            timestamp_ms,
+            content: content.to_string(),
+        };
+        History {
+            base,
+            ops: Vec::new(),
+            snapshots: Vec::new(),
+        }
+    }
+
+    /// Recomputes the content snapshots, e.g. after loading the history
+    /// from disk, and returns the content at the latest version.
+    pub fn rebuild_snapshots(&mut self) -> Result<String, &'static str> {
+        let mut content = self.base.content.clone();
+        let mut snapshots = Vec::new();
+        for (index, applied) in self.ops.iter().enumerate() {
+            if applied.version != self.base.version + index {
+                return Err("History versions are not consecutive.");
+            }
+            applied.op.apply(&mut content)?;
+            if let Some(snapshot) = self.snapshot_due(applied, &content) {
+                snapshots.push(snapshot);
+            }
+        }
+        self.snapshots = snapshots;
+        Ok(content)
+    }
+
+    fn snapshot_due(&self, applied: &AppliedOp, content: &str) -> Option<HistorySnapshot> {
+        let version = applied.version + 1;
+        (version - self.base.version)
+            .is_multiple_of(SNAPSHOT_INTERVAL)
+            .then(|| HistorySnapshot {
+                version,
+                timestamp_ms: applied.timestamp_ms,
+                content: content.to_string(),
+            })
+    }
+
+    /// Records an operation, given the content it produced.
+    pub fn push(&mut self, applied: AppliedOp, content: &str) {
+        if let Some(snapshot) = self.snapshot_due(&applied, content) {
+            self.snapshots.push(snapshot);
+        }
+        self.ops.push(applied);
+        self.trim();
+    }
+
+    /// Drops the oldest operations, a snapshot interval at a time, while
+    /// more than [`MAX_HISTORY_OPS`] would be left. The next snapshot
+    /// becomes the base.
+    pub fn trim(&mut self) {
+        while let Some(next) = self.snapshots.first() {
+            let dropped = next.version - self.base.version;
+            if self.ops.len() - dropped < MAX_HISTORY_OPS {
+                return;
+            }
+            self.ops.drain(..dropped);
+            self.base = self.snapshots.remove(0);
+        }
+    }
+
+    /// Roughly how many bytes the history takes up in memory.
+    pub fn memory_size(&self) -> usize {
+        let snapshots: usize = self.snapshots.iter().map(|s| s.content.len()).sum();
+        let ops: usize = self
+            .ops
+            .iter()
+            .map(|applied| mem::size_of::<AppliedOp>() + applied.op.text_len())
+            .sum();
+        self.base.content.len() + snapshots + ops
+    }
+
+    /// The oldest version whose content is still available.
+    pub fn first_version(&self) -> usize {
+        self.base.version
+    }
+
+    /// The content at [`History::first_version`].
+    pub fn base_content(&self) -> &str {
+        &self.base.content
+    }
+
+    /// The version after the last recorded operation.
+    pub fn latest_version(&self) -> usize {
+        self.base.version + self.ops.len()
+    }
+
+    /// The operation that was applied to the document at `version`.
+    pub fn op(&self, version: usize) -> Option<&AppliedOp> {
+        self.ops.get(version.checked_sub(self.base.version)?)
+    }
+
+    /// The operations applied since `version`, or `None` if history does not
+    /// reach back that far.
+    pub fn ops_since(&self, version: usize) -> Option<&[AppliedOp]> {
+        self.ops.get(version.checked_sub(self.base.version)?..)
+    }
+
+    /// The content of the document at `version`, or `None` if that version
+    /// is older than the history or has not been reached yet.
+    pub fn content_at(&self, version: usize) -> Option<String> {
+        if version < self.base.version || version > self.latest_version() {
+            return None;
+        }
+        let index = self
+            .snapshots
+            .partition_point(|snapshot| snapshot.version <= version);
+        let snapshot = match index.checked_sub(1) {
+            Some(index) => &self.snapshots[index],
+            None => &self.base,
+        };
+
+        let mut content = snapshot.content.clone();
+        let start = snapshot.version - self.base.version;
+        let end = version - self.base.version;
+        for applied in &self.ops[start..end] {
+            applied.op.apply(&mut content).ok()?;
+        }
+        Some(content)
+    }
+
+    /// The version the document was at, at `timestamp_ms`, or `None` if
+    /// that is before the history begins.
+    pub fn version_at(&self, timestamp_ms: u64) -> Option<usize> {
+        if timestamp_ms < self.base.timestamp_ms {
+            return None;
+        }
+        let applied = self
+            .ops
+            .partition_point(|applied| applied.timestamp_ms <= timestamp_ms);
+        Some(self.base.version + applied)
+    }
+
+    /// The version the document had at `point`, together with its content.
+    pub fn checkout(&self, point: HistoryPoint) -> Result<(usize, String), &'static str> {
+        let version = match point {
+            HistoryPoint::Version(version) => version,
+            HistoryPoint::Time(timestamp_ms) => {
+                self.version_at(timestamp_ms).ok_or(HISTORY_UNAVAILABLE)?
+            }
+        };
+        let content = self.content_at(version).ok_or(HISTORY_UNAVAILABLE)?;
+        Ok((version, content))
+    }
+}
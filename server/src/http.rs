@@ -0,0 +1,826 @@
+//! Minimal HTTP/1.1 handling for the requests that arrive on the WebSocket
+//! listener but are not WebSocket upgrades.
+
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::backplane::NOT_OWNER;
+use crate::blocks;
+use crate::checkpoint::{self, Checkpoint};
+use crate::diff::{self, DiffEnd};
+use crate::history::{HistoryPoint, DEFAULT_HISTORY_PAGE, HISTORY_UNAVAILABLE, MAX_HISTORY_PAGE};
+use crate::replication::READ_ONLY;
+use crate::room::{self, RoomHandle};
+use crate::{Edit, ServerState, DOCUMENT_TOO_LARGE};
+
+/// Largest request head (request line plus headers) accepted, in bytes.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Looks up a header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads a parameter from the query string.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let query = self.query.as_deref()?;
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+
+    fn content_length(&self) -> io::Result<usize> {
+        match self.header("content-length") {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| invalid_data("Invalid Content-Length header")),
+            None => Ok(0),
+        }
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        HttpResponse {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::text(404, "Not Found\n")
+    }
+
+    /// A JSON error body, `{"error": message}`.
+    pub fn error(status: u16, message: &str) -> Self {
+        HttpResponse::json(status, &json!({ "error": message }))
+    }
+}
+
+/// Reads a request head from the stream.
+///
+/// Returns the parsed request, without its body, together with every byte
+/// read so far. Upgrade requests replay those bytes into the WebSocket
+/// handshake through [`PrefixedStream`]; plain requests pass them on to
+/// [`read_body`].
+pub async fn read_request<S>(stream: &mut S) -> io::Result<(HttpRequest, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffered = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffered.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buffered) {
+            Ok(httparse::Status::Complete(_)) => {
+                let target = parsed.path.unwrap_or("/");
+                let (path, query) = match target.split_once('?') {
+                    Some((path, query)) => (path, Some(query.to_string())),
+                    None => (target, None),
+                };
+                let request = HttpRequest {
+                    method: parsed.method.unwrap_or("GET").to_string(),
+                    path: path.to_string(),
+                    query,
+                    headers: parsed
+                        .headers
+                        .iter()
+                        .map(|header| {
+                            (
+                                header.name.to_string(),
+                                String::from_utf8_lossy(header.value).into_owned(),
+                            )
+                        })
+                        .collect(),
+                    body: Vec::new(),
+                };
+                return Ok((request, buffered));
+            }
+            Ok(httparse::Status::Partial) if buffered.len() < MAX_HEAD_SIZE => continue,
+            Ok(httparse::Status::Partial) => return Err(invalid_data("Request head too large")),
+            Err(e) => return Err(invalid_data(e)),
+        }
+    }
+}
+
+/// Reads the body of a plain request, starting with whatever followed the
+/// head in `buffered`.
+pub async fn read_body<S>(
+    stream: &mut S,
+    request: &mut HttpRequest,
+    buffered: &[u8],
+    max_size: usize,
+) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let head_len = head_length(buffered).ok_or_else(|| invalid_data("Incomplete head"))?;
+    let length = request.content_length()?;
+    if length > max_size {
+        return Err(invalid_data("Request body too large"));
+    }
+
+    let mut body = buffered[head_len..].to_vec();
+    body.truncate(length);
+    if body.len() < length {
+        let already_read = body.len();
+        body.resize(length, 0);
+        stream.read_exact(&mut body[already_read..]).await?;
+    }
+    request.body = body;
+    Ok(())
+}
+
+fn head_length(buffered: &[u8]) -> Option<usize> {
+    buffered
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+pub async fn write_response<S>(stream: &mut S, response: &HttpResponse) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Routes a plain HTTP request to its handler.
+pub async fn route(request: &HttpRequest, peer: IpAddr, state: &ServerState) -> HttpResponse {
+    let segments: Vec<String> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    // Probes stay open, and without a token there is nothing to protect
+    // reads with; changes are refused outright then.
+    let probe = matches!(segments.as_slice(), ["healthz"] | ["readyz"]);
+    let open = request.method == "GET" && state.config.api_token.is_none();
+    if !probe && !open && !is_authorized(request, state.config.api_token.as_deref()) {
+        return HttpResponse::error(401, "Missing or invalid API token");
+    }
+    // Ids name files in the data directory, and `%2F` decodes to a slash.
+    if let ["documents" | "blocks", id, ..] = segments.as_slice() {
+        if !room::is_valid_room_id(id) {
+            return HttpResponse::error(400, "Invalid document id");
+        }
+    }
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["documents"]) => list_documents(state).await,
+        ("GET", ["documents", id]) => get_document(state, id).await,
+        ("PUT", ["documents", id]) => edit_document(request, peer, state, id, true).await,
+        ("PATCH", ["documents", id]) => edit_document(request, peer, state, id, false).await,
+        ("DELETE", ["documents", id]) => delete_document(state, id).await,
+        ("GET", ["documents", id, "comments"]) => document_comments(state, id).await,
+        ("GET", ["blocks", id]) => get_blocks(request, state, id).await,
+        ("PUT", ["blocks", id]) => put_blocks(request, state, id).await,
+        ("GET", ["documents", id, "suggestions"]) => document_suggestions(state, id).await,
+        ("GET", ["documents", id, "formatting"]) => document_formatting(state, id).await,
+        ("GET", ["documents", id, "blame"]) => document_blame(state, id).await,
+        ("GET", ["documents", id, "diff"]) => document_diff(request, state, id).await,
+        ("GET", ["documents", id, "checkpoints"]) => list_checkpoints(state, id).await,
+        ("POST", ["documents", id, "checkpoints"]) => {
+            create_checkpoint(request, peer, state, id).await
+        }
+        ("DELETE", ["documents", id, "checkpoints", name]) => {
+            checkpoint_action(state, id, |room| async move {
+                room.delete_checkpoint(name).await?;
+                Ok(json!({ "deleted": name }))
+            })
+            .await
+        }
+        ("POST", ["documents", id, "checkpoints", name, "restore"]) => {
+            let author = request.query_param("user");
+            checkpoint_action(state, id, |room| async move {
+                let edit = room.restore_checkpoint(name, author.as_deref()).await?;
+                if edit.is_some() {
+                    state.metrics.edits_applied_total.inc();
+                }
+                let version = room.document.read().await.version;
+                Ok(json!({ "restored": name, "version": version }))
+            })
+            .await
+        }
+        ("GET", ["documents", id, "content"]) => document_content(request, state, id).await,
+        ("GET", ["documents", id, "history"]) => document_history(request, state, id).await,
+        ("GET", ["metrics"]) => {
+            let rooms = state.rooms_snapshot().await;
+            state.metrics.sample_rooms(&rooms).await;
+            HttpResponse {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: state.metrics.encode().into_bytes(),
+            }
+        }
+        ("POST", ["promote"]) => match state.promote().await {
+            true => HttpResponse::json(200, &json!({ "promoted": true })),
+            false => HttpResponse::error(409, "This server is not a follower"),
+        },
+        ("GET", ["healthz"]) => HttpResponse::json(200, &json!({ "status": "ok" })),
+        ("GET", ["readyz"]) => {
+            let readiness = state.readiness();
+            let status = if readiness.is_ready() { 200 } else { 503 };
+            let body = json!({
+                "status": if readiness.is_ready() { "ready" } else { "not_ready" },
+                "checks": {
+                    "recovery": if readiness.recovered { "complete" } else { "in_progress" },
+                    "storage": match &readiness.storage {
+                        Ok(()) => "ok".to_string(),
+                        Err(e) => e.clone(),
+                    },
+                    "shutting_down": readiness.shutting_down,
+                },
+            });
+            HttpResponse::json(status, &body)
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+/// Whether a request carries `Authorization: Bearer` with the API token.
+/// Nothing is authorized when no token is configured.
+fn is_authorized(request: &HttpRequest, token: Option<&str>) -> bool {
+    let given = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (token, given) {
+        (Some(token), Some(given)) => tokens_match(token, given.trim()),
+        _ => false,
+    }
+}
+
+/// Compares a token with the expected one. Every byte is compared, so the
+/// time taken says nothing about how much of the token was right.
+pub(crate) fn tokens_match(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Parses the point in history a request asks for: `version=N`, `at=T` in
+/// milliseconds since the Unix epoch, or neither for the latest version.
+fn history_point(request: &HttpRequest) -> Result<Option<HistoryPoint>, HttpResponse> {
+    let parse = |name: &str| {
+        request
+            .query_param(name)
+            .map(|value| value.parse::<u64>())
+            .transpose()
+            .map_err(|_| HttpResponse::error(400, &format!("Invalid {} parameter", name)))
+    };
+    match (parse("version")?, parse("at")?) {
+        (Some(_), Some(_)) => Err(HttpResponse::error(
+            400,
+            "Pass either version or at, not both",
+        )),
+        (Some(version), None) => Ok(Some(HistoryPoint::Version(version as usize))),
+        (None, Some(at)) => Ok(Some(HistoryPoint::Time(at))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// `GET /documents`: the ids of every document, in memory or saved.
+async fn list_documents(state: &ServerState) -> HttpResponse {
+    match state.document_ids().await {
+        Ok(ids) => HttpResponse::json(200, &json!({ "documents": ids })),
+        Err(e) => HttpResponse::error(500, &e.to_string()),
+    }
+}
+
+/// `GET /documents/{id}`: a document's current content and version.
+async fn get_document(state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    HttpResponse::json(
+        200,
+        &json!({ "document": id, "version": doc.version, "content": doc.content }),
+    )
+}
+
+/// `PUT` or `PATCH /documents/{id}` with a `{"version": N, "edits": [...]}`
+/// body: applies edits made one after another on version `N`, transformed
+/// past any made since, and broadcasts them to connected clients. `PUT`
+/// creates the document if needed, `PATCH` only changes an existing one.
+async fn edit_document(
+    request: &HttpRequest,
+    peer: IpAddr,
+    state: &ServerState,
+    id: &str,
+    create: bool,
+) -> HttpResponse {
+    let Some((version, edits)) = edits_from_body(&request.body) else {
+        return HttpResponse::error(400, "Pass the version the edits were made on and the edits");
+    };
+    if let Err(response) = check_rate_limit(state, peer) {
+        return response;
+    }
+    let user = request.query_param("user");
+    let room = match create {
+        true => match state.room(id).await {
+            Ok(room) => room,
+            Err(e) => return HttpResponse::error(500, &e.to_string()),
+        },
+        false => match state.find_room(id).await {
+            Some(room) => room,
+            None => return HttpResponse::error(404, "Unknown document"),
+        },
+    };
+    match room.apply_edits(version, &edits, user.as_deref()).await {
+        Ok((applied, version)) => {
+            state
+                .metrics
+                .edits_applied_total
+                .inc_by(applied.len() as u64);
+            HttpResponse::json(
+                200,
+                &json!({ "document": id, "version": version, "applied": applied.len() }),
+            )
+        }
+        Err(e) => {
+            let status = match e {
+                NOT_OWNER | READ_ONLY | HISTORY_UNAVAILABLE => 409,
+                DOCUMENT_TOO_LARGE => 413,
+                _ => 400,
+            };
+            HttpResponse::error(status, e)
+        }
+    }
+}
+
+/// Reads the base version and edits of an [`edit_document`] request. The
+/// edits need no `version` of their own.
+fn edits_from_body(body: &[u8]) -> Option<(usize, Vec<Edit>)> {
+    let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let version = body["version"].as_u64()? as usize;
+    let edits = body["edits"]
+        .as_array()?
+        .iter()
+        .map(|edit| {
+            let mut edit = edit.clone();
+            if edit.get("version").is_none() {
+                edit["version"] = version.into();
+            }
+            serde_json::from_value(edit).ok()
+        })
+        .collect::<Option<Vec<Edit>>>()?;
+    Some((version, edits))
+}
+
+/// `DELETE /documents/{id}`: deletes a document, disconnecting its clients.
+async fn delete_document(state: &ServerState, id: &str) -> HttpResponse {
+    if let Some(room) = state.find_room(id).await {
+        if room.is_replica() {
+            return HttpResponse::error(409, "Only the document's owner can delete it");
+        }
+    }
+    match state.delete_document(id).await {
+        Ok(true) => HttpResponse::json(200, &json!({ "deleted": id })),
+        Ok(false) => HttpResponse::error(404, "Unknown document"),
+        Err(e) => HttpResponse::error(500, &e.to_string()),
+    }
+}
+
+/// `GET /documents/{id}/content`: the content of a document, now or at an
+/// earlier point in its history.
+async fn document_content(request: &HttpRequest, state: &ServerState, id: &str) -> HttpResponse {
+    let point = match history_point(request) {
+        Ok(point) => point,
+        Err(response) => return response,
+    };
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+
+    let doc = room.document.read().await;
+    let result = match point {
+        Some(point) => doc.history.checkout(point),
+        None => Ok((doc.version, doc.content.clone())),
+    };
+    match result {
+        Ok((version, content)) => HttpResponse::json(
+            200,
+            &json!({ "document": id, "version": version, "content": content }),
+        ),
+        Err(e) => HttpResponse::error(404, e),
+    }
+}
+
+/// `GET /documents/{id}/history?since=N&limit=L`: the operations applied to
+/// a document from version `since` on, oldest first.
+async fn document_history(request: &HttpRequest, state: &ServerState, id: &str) -> HttpResponse {
+    let since = request
+        .query_param("since")
+        .map(|value| value.parse::<usize>());
+    let limit = request
+        .query_param("limit")
+        .map(|value| value.parse::<usize>());
+    let (since, limit) = match (since.transpose(), limit.transpose()) {
+        (Ok(since), Ok(limit)) => (
+            since,
+            limit.unwrap_or(DEFAULT_HISTORY_PAGE).min(MAX_HISTORY_PAGE),
+        ),
+        _ => return HttpResponse::error(400, "Invalid since or limit parameter"),
+    };
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+
+    let doc = room.document.read().await;
+    let history = &doc.history;
+    let since = since.unwrap_or(history.first_version());
+    let Some(ops) = history.ops_since(since) else {
+        return HttpResponse::error(404, HISTORY_UNAVAILABLE);
+    };
+    HttpResponse::json(
+        200,
+        &json!({
+            "document": id,
+            "first_version": history.first_version(),
+            "latest_version": history.latest_version(),
+            "ops": &ops[..ops.len().min(limit)],
+        }),
+    )
+}
+
+/// `GET /documents/{id}/comments`: the document's comment threads, with
+/// their anchors in the current content.
+async fn document_comments(state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    let body = json!({
+        "document": id,
+        "version": doc.version,
+        "threads": doc.comments.threads(),
+    });
+    HttpResponse::json(200, &body)
+}
+
+/// `GET /blocks/{id}`: a block document as JSON, or converted with
+/// `?format=markdown` or `?format=text`.
+async fn get_blocks(request: &HttpRequest, state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_block_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    let (content_type, body) = match request.query_param("format").as_deref() {
+        None | Some("json") => {
+            let body = json!({
+                "document": id,
+                "version": doc.version,
+                "blocks": doc.blocks,
+            });
+            return HttpResponse::json(200, &body);
+        }
+        Some("markdown") => ("text/markdown; charset=utf-8", doc.to_markdown()),
+        Some("text") => ("text/plain; charset=utf-8", doc.to_plain_text()),
+        Some(_) => return HttpResponse::error(400, "Format must be json, markdown or text"),
+    };
+    HttpResponse {
+        status: 200,
+        content_type,
+        body: body.into_bytes(),
+    }
+}
+
+/// `PUT /blocks/{id}?format=markdown` or `?format=text`: replaces a block
+/// document, creating it if needed, with the request body converted to
+/// blocks. Connected clients receive the new blocks.
+async fn put_blocks(request: &HttpRequest, state: &ServerState, id: &str) -> HttpResponse {
+    let Ok(text) = std::str::from_utf8(&request.body) else {
+        return HttpResponse::error(400, "Body must be UTF-8");
+    };
+    let blocks = match request.query_param("format").as_deref() {
+        Some("markdown") => blocks::from_markdown(text),
+        Some("text") => blocks::from_plain_text(text),
+        _ => return HttpResponse::error(400, "Format must be markdown or text"),
+    };
+    let room = match state.block_room(id).await {
+        Ok(room) => room,
+        Err(e) => return HttpResponse::error(500, &e.to_string()),
+    };
+    if room.is_replica() {
+        return HttpResponse::error(409, READ_ONLY);
+    }
+    let mut doc = room.document.write().await;
+    if let Err(e) = doc.replace_all(blocks) {
+        return HttpResponse::error(413, e);
+    }
+    let body = json!({
+        "type": "blocks",
+        "version": doc.version,
+        "blocks": doc.blocks,
+    });
+    room.broadcast_all(&body.to_string()).await;
+    HttpResponse::json(200, &body)
+}
+
+/// `GET /documents/{id}/suggestions`: the document's pending suggestions,
+/// with their ranges in the current content.
+async fn document_suggestions(state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    let body = json!({
+        "document": id,
+        "version": doc.version,
+        "suggestions": doc.suggestions.list(),
+    });
+    HttpResponse::json(200, &body)
+}
+
+/// `GET /documents/{id}/formatting`: the formatted spans of the current
+/// content.
+async fn document_formatting(state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    let body = json!({
+        "document": id,
+        "version": doc.version,
+        "spans": doc.formatting.spans(),
+    });
+    HttpResponse::json(200, &body)
+}
+
+/// `GET /documents/{id}/blame`: the content split into spans by the edit
+/// that inserted them, with its author, version and time.
+async fn document_blame(state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    let body = json!({
+        "document": id,
+        "version": doc.version,
+        "spans": doc.authorship.spans(),
+    });
+    HttpResponse::json(200, &body)
+}
+
+/// Parses one end of a diff from the `{name}=N` or `{name}_checkpoint=NAME`
+/// query parameter.
+fn diff_end(request: &HttpRequest, name: &str) -> Result<Option<DiffEnd>, HttpResponse> {
+    let checkpoint = request.query_param(&format!("{}_checkpoint", name));
+    match (request.query_param(name), checkpoint) {
+        (Some(version), None) => version
+            .parse()
+            .map(|version| Some(DiffEnd::Version(version)))
+            .map_err(|_| HttpResponse::error(400, &format!("Invalid {} parameter", name))),
+        (None, Some(checkpoint)) => Ok(Some(DiffEnd::Checkpoint(checkpoint))),
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => Err(HttpResponse::error(
+            400,
+            &format!("Pass either {0} or {0}_checkpoint, not both", name),
+        )),
+    }
+}
+
+/// `GET /documents/{id}/diff?from=N&to=M`: the diff between two versions,
+/// as JSON or, with `format=unified`, as a unified diff. Either end can be
+/// a checkpoint instead, with `from_checkpoint` and `to_checkpoint`; `to`
+/// defaults to the latest version.
+async fn document_diff(request: &HttpRequest, state: &ServerState, id: &str) -> HttpResponse {
+    let (from, to) = match (diff_end(request, "from"), diff_end(request, "to")) {
+        (Ok(Some(from)), Ok(to)) => (from, to),
+        (Ok(None), _) => return HttpResponse::error(400, "Missing from parameter"),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+
+    let doc = room.document.read().await;
+    let to = to.unwrap_or(DiffEnd::Version(doc.version));
+    match diff::diff_document(&doc, &from, &to) {
+        Ok(diff) if request.query_param("format").as_deref() == Some("unified") => HttpResponse {
+            status: 200,
+            content_type: "text/x-diff; charset=utf-8",
+            body: diff.unified.into_bytes(),
+        },
+        Ok(diff) => HttpResponse::json(200, &json!({ "document": id, "diff": diff })),
+        Err(e) => HttpResponse::error(404, e),
+    }
+}
+
+/// `GET /documents/{id}/checkpoints`: the document's checkpoints, oldest
+/// first, without their content.
+async fn list_checkpoints(state: &ServerState, id: &str) -> HttpResponse {
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    let doc = room.document.read().await;
+    let checkpoints: Vec<_> = doc
+        .checkpoints
+        .list()
+        .iter()
+        .map(Checkpoint::summary)
+        .collect();
+    HttpResponse::json(200, &json!({ "checkpoints": checkpoints }))
+}
+
+/// `POST /documents/{id}/checkpoints` with a `{"name": ...}` body: names the
+/// document's current content.
+async fn create_checkpoint(
+    request: &HttpRequest,
+    peer: IpAddr,
+    state: &ServerState,
+    id: &str,
+) -> HttpResponse {
+    let body: serde_json::Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(_) => return HttpResponse::error(400, "Expected a JSON body"),
+    };
+    let Some(name) = body["name"].as_str() else {
+        return HttpResponse::error(400, "Missing checkpoint name");
+    };
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    if let Err(response) = check_rate_limit(state, peer) {
+        return response;
+    }
+    let author = request.query_param("user");
+    match room.create_checkpoint(name, author.as_deref()).await {
+        Ok(summary) => HttpResponse::json(201, &summary),
+        Err(e) => checkpoint_error(e),
+    }
+}
+
+/// Counts a change against the rate limit of the address it came from.
+/// `?user=` only names the author, and anyone can pick any name.
+fn check_rate_limit(state: &ServerState, peer: IpAddr) -> Result<(), HttpResponse> {
+    if state.user_limits.try_acquire(&peer.to_string()) {
+        return Ok(());
+    }
+    state.metrics.reject("user_rate_limited");
+    Err(HttpResponse::error(429, "Too many edits from this address"))
+}
+
+/// Runs a checkpoint operation against an existing document.
+async fn checkpoint_action<F, Fut>(state: &ServerState, id: &str, action: F) -> HttpResponse
+where
+    F: FnOnce(RoomHandle) -> Fut,
+    Fut: Future<Output = Result<serde_json::Value, &'static str>>,
+{
+    let Some(room) = state.find_room(id).await else {
+        return HttpResponse::error(404, "Unknown document");
+    };
+    match action(room).await {
+        Ok(body) => HttpResponse::json(200, &body),
+        Err(e) => checkpoint_error(e),
+    }
+}
+
+fn checkpoint_error(error: &'static str) -> HttpResponse {
+    let status = match checkpoint::error_code(error) {
+        Some("unknown_checkpoint") => 404,
+        Some("checkpoint_exists" | "too_many_checkpoints") => 409,
+        Some(_) => 400,
+        // The restore edit itself was rejected, e.g. by the size limit.
+        None => 409,
+    };
+    HttpResponse::error(status, error)
+}
+
+/// A stream that yields some already-read bytes before reading from the
+/// inner stream, so a request head consumed while routing can be handed to
+/// the WebSocket handshake intact.
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        PrefixedStream {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position < this.prefix.len() {
+            let remaining = &this.prefix[this.position..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            this.position += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
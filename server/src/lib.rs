@@ -1,213 +1,2687 @@
-use futures_util::StreamExt;
-use futures_util::TryStreamExt;
-use serde::{Deserialize, Serialize};
+pub mod anchor;
+pub mod attribution;
+pub mod authorship;
+pub mod backplane;
+pub mod blocks;
+pub mod checkpoint;
+pub mod checksum;
+pub mod client_ops;
+pub mod codec;
+pub mod comments;
+pub mod config;
+pub mod diff;
+pub mod document;
+pub mod formatting;
+pub mod history;
+pub mod http;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod ot;
+pub mod replication;
+pub mod room;
+pub mod runs;
+pub mod storage;
+pub mod suggestions;
+pub mod telemetry;
+pub mod undo;
+
+use backplane::{block_document, Cluster, NodeMessage, TcpBackplane};
+use blocks::{BlockDocument, BlockOp};
+use checkpoint::Checkpoint;
+use client_ops::{OpId, INVALID_OP_ID};
+use codec::{Codec, Encodings, DEFLATE};
+use config::ServerConfig;
+use diff::DiffEnd;
+pub use document::{DocumentState, Edit, DOCUMENT_TOO_LARGE, INVALID_PENDING};
+use formatting::{FormatEdit, FormatSpan, INVALID_FORMAT};
+use futures_util::{SinkExt, StreamExt};
+use history::{
+    AppliedOp, HistoryPoint, DEFAULT_HISTORY_PAGE, HISTORY_UNAVAILABLE, MAX_HISTORY_PAGE,
+};
+use http::{HttpRequest, HttpResponse, PrefixedStream};
+use limits::{TokenBucket, UserRateLimits};
+use metrics::{rejection_reason, Metrics};
+use replication::{Followers, PrimaryLink, FOLLOWER, PRIMARY, READ_ONLY};
+use room::{
+    applied_edit, edit_message, format_message, BlockRoom, Peer, Room, RoomHandle, DEFAULT_ROOM,
+};
 use serde_json::json;
 use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::{Duration, Instant};
+use storage::{Snapshot, Storage};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinSet;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tokio_tungstenite::accept_async;
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Request as WsRequest, Response as WsResponse,
+};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use undo::{Change, UndoStack, NOTHING_TO_REDO, NOTHING_TO_UNDO};
 
 pub type Tx = mpsc::UnboundedSender<tokio_tungstenite::tungstenite::Message>;
-pub type PeerMap = Arc<RwLock<HashMap<String, Tx>>>;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct Edit {
-    pub position: usize,
-    pub insert: Option<String>,
-    pub delete: Option<usize>,
-    pub version: usize,
+/// How long a client has to send its request head after connecting.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often documents are checked for being idle or over the memory
+/// budget.
+const UNLOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a connection to a document another node owns waits for that
+/// node's copy before joining with what this node has.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Per-connection state of a WebSocket client.
+struct Session {
+    addr: SocketAddr,
+    user: String,
+    bucket: TokenBucket,
+    undo: UndoStack,
+    /// Whether edits from this connection are recorded as suggestions
+    /// rather than applied.
+    suggesting: bool,
+    /// The encoding negotiated for this connection's messages.
+    codec: Codec,
+    /// Whether the connection asked for large messages to be compressed.
+    compress: bool,
+    /// The version the client already has, if it is reconnecting.
+    since: Option<usize>,
+    /// The node the client is connected to, if its messages are forwarded
+    /// from another node.
+    node: Option<String>,
+}
+
+impl Session {
+    /// Names the connection among the peers of a room, and across nodes
+    /// for connections forwarded from another node.
+    fn key(&self) -> String {
+        match &self.node {
+            Some(node) => format!("{}/{}", node, self.addr),
+            None => self.addr.to_string(),
+        }
+    }
+}
+
+/// The outcome of a readiness check.
+pub struct Readiness {
+    pub recovered: bool,
+    pub storage: Result<(), String>,
+    pub shutting_down: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.recovered && self.storage.is_ok() && !self.shutting_down
+    }
 }
 
-pub struct DocumentState {
-    pub content: String,
-    pub version: usize,
+/// State shared by every connection handled by the server.
+pub struct ServerState {
+    next_session_id: AtomicU64,
+    pub config: ServerConfig,
+    pub rooms: RwLock<HashMap<String, Arc<Room>>>,
+    pub block_rooms: RwLock<HashMap<String, Arc<BlockRoom>>>,
+    pub user_limits: UserRateLimits,
+    pub metrics: Metrics,
+    pub storage: Option<Storage>,
+    /// Set once shutdown has begun; connections completing their handshake
+    /// afterwards are closed straight away.
+    pub shutting_down: AtomicBool,
+    /// Set once persisted documents have been loaded back into memory.
+    pub recovered: AtomicBool,
+    /// Held while a document is loaded from storage or unloaded to it, so
+    /// a document is never loaded while it is being saved or copied.
+    pub(crate) loading: tokio::sync::Mutex<()>,
+    /// The other nodes documents are shared with, if any: a cluster, or a
+    /// primary and its followers.
+    pub cluster: Option<Cluster>,
 }
 
-impl DocumentState {
-    pub fn new() -> Self {
-        DocumentState {
-            content: String::new(),
-            version: 0,
+impl ServerState {
+    pub fn new(config: ServerConfig) -> Self {
+        let limits = &config.limits;
+        ServerState {
+            next_session_id: AtomicU64::new(1),
+            rooms: RwLock::new(HashMap::new()),
+            block_rooms: RwLock::new(HashMap::new()),
+            user_limits: UserRateLimits::new(limits.user_burst, limits.user_edits_per_sec),
+            metrics: Metrics::new(),
+            storage: config.data_dir.as_ref().map(Storage::new),
+            shutting_down: AtomicBool::new(false),
+            recovered: AtomicBool::new(false),
+            loading: tokio::sync::Mutex::new(()),
+            cluster: None,
+            config,
         }
     }
 
-    pub fn apply_edit(&mut self, edit: &Edit) -> Result<(), &'static str> {
-        // Check for version consistency
-        if edit.version != self.version {
-            eprintln!(
-                "Version mismatch: edit version {} does not match document version {}",
-                edit.version, self.version
-            );
-            return Err("Version mismatch");
+    /// Shares documents with other nodes. Messages from them are handled by
+    /// [`run_backplane`].
+    pub fn with_cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Hands out a unique id for each connection, used to tell sessions of
+    /// the same user apart in logs.
+    pub fn next_session_id(&self) -> u64 {
+        self.next_session_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the room for a document, loading it from storage or creating
+    /// an empty one if it is not in memory. A replica of a document another
+    /// node owns is brought up to date first.
+    pub async fn room(&self, id: &str) -> io::Result<RoomHandle> {
+        let room = self.room_entry(id).await?;
+        if !room.is_synced() {
+            self.sync_replica(&room, id).await;
+        }
+        Ok(room)
+    }
+
+    /// Returns the room for a document like [`ServerState::room`], without
+    /// waiting for the owner's copy.
+    async fn room_entry(&self, id: &str) -> io::Result<RoomHandle> {
+        if let Some(room) = self.loaded_room(id).await {
+            return Ok(room);
+        }
+        if !room::is_valid_room_id(id) {
+            return Err(invalid_document_id());
+        }
+        let _loading = self.loading.lock().await;
+        if let Some(room) = self.loaded_room(id).await {
+            return Ok(room);
+        }
+        let doc = self
+            .load_document(id)?
+            .unwrap_or_else(|| self.new_document());
+        Ok(self.insert_room(id, doc).await)
+    }
+
+    /// Reads a document that is not in memory from storage. The `loading`
+    /// lock must be held until the document is inserted into `rooms`.
+    fn load_document(&self, id: &str) -> io::Result<Option<DocumentState>> {
+        if !room::is_valid_room_id(id) {
+            return Err(invalid_document_id());
+        }
+        let Some(storage) = &self.storage else {
+            return Ok(None);
+        };
+        let Some(snapshot) = storage.load_snapshot(id)? else {
+            return Ok(None);
+        };
+        debug!(document = %id, version = snapshot.version, "Loaded document");
+        let mut doc = self.new_document();
+        if let Err(e) = snapshot.restore_into(&mut doc) {
+            warn!(document = %id, error = e, "Discarding document history");
+        }
+        Ok(Some(doc))
+    }
+
+    /// Adds a room for a document, unless another was added meanwhile.
+    async fn insert_room(&self, id: &str, doc: DocumentState) -> RoomHandle {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(self.new_room(id, doc)));
+        RoomHandle::new(room)
+    }
+
+    /// Wraps a document in a room, which replicates the owner's copy if
+    /// another node owns it.
+    fn new_room(&self, id: &str, doc: DocumentState) -> Room {
+        match &self.cluster {
+            Some(cluster) => Room::shared(id, id, doc, cluster.clone()),
+            None => Room::new(id, doc),
+        }
+    }
+
+    /// Asks the owner of a replicated document, shared as `name`, for its
+    /// copy and waits a while for it to arrive.
+    async fn sync_replica<D>(&self, room: &Room<D>, name: &str) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        let owner = cluster.owner(name);
+        request_sync(cluster, name);
+        if !room.wait_until_synced(SYNC_TIMEOUT).await {
+            warn!(document = %name, owner, "Timed out waiting for the document from its owner");
+        }
+    }
+
+    /// Returns the room for a document if it exists, loading it from
+    /// storage if need be, without creating one.
+    pub async fn find_room(&self, id: &str) -> Option<RoomHandle> {
+        if let Some(room) = self.loaded_room(id).await {
+            return Some(room);
+        }
+        if !room::is_valid_room_id(id) {
+            return None;
+        }
+        let _loading = self.loading.lock().await;
+        if let Some(room) = self.loaded_room(id).await {
+            return Some(room);
+        }
+        match self.load_document(id) {
+            Ok(doc) => Some(self.insert_room(id, doc?).await),
+            Err(e) => {
+                error!(document = %id, error = %e, "Failed to load document");
+                None
+            }
+        }
+    }
+
+    /// Returns the room for a document if it is in memory.
+    async fn loaded_room(&self, id: &str) -> Option<RoomHandle> {
+        let room = RoomHandle::new(self.rooms.read().await.get(id)?);
+        room.touch();
+        Some(room)
+    }
+
+    /// Lists the ids of every document, whether in memory or only in
+    /// storage.
+    pub async fn document_ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = match &self.storage {
+            Some(storage) => storage.list_documents()?,
+            None => Vec::new(),
+        };
+        ids.extend(self.rooms.read().await.keys().cloned());
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Lists the ids of every block document, whether in memory or only in
+    /// storage.
+    pub async fn block_document_ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = match &self.storage {
+            Some(storage) => storage.list_block_documents()?,
+            None => Vec::new(),
+        };
+        ids.extend(self.block_rooms.read().await.keys().cloned());
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Deletes a document from memory and storage, disconnecting its
+    /// clients. Returns false if there was no such document.
+    pub async fn delete_document(&self, id: &str) -> io::Result<bool> {
+        let _loading = self.loading.lock().await;
+        let stored = match &self.storage {
+            Some(storage) => storage.delete_snapshot(id)?,
+            None => false,
+        };
+        let Some(room) = self.rooms.write().await.remove(id) else {
+            return Ok(stored);
+        };
+        for peer in room.peers.read().await.values() {
+            let close = CloseFrame {
+                code: CloseCode::Normal,
+                reason: "document deleted".into(),
+            };
+            let _ = peer.send(Message::Close(Some(close)));
+        }
+        info!(document = %id, "Deleted document");
+        Ok(true)
+    }
+
+    /// Saves and drops from memory the documents nobody has used for the
+    /// idle timeout, then the least recently used ones until the documents
+    /// left fit the memory budget. Flat and block documents are dropped
+    /// alike, only when they can be saved, and never while a connection or
+    /// request is using them. Returns how many were dropped.
+    pub async fn unload_idle(&self) -> usize {
+        if self.storage.is_none() {
+            return 0;
+        }
+        // Each room with whether it holds a block document.
+        let mut rooms = Vec::new();
+        for room in self.rooms_snapshot().await {
+            let size = room.document.read().await.memory_size();
+            rooms.push((false, room.id.clone(), room.idle_for(), size));
+        }
+        for room in self.block_rooms_snapshot().await {
+            let size = room.document.read().await.memory_size();
+            rooms.push((true, room.id.clone(), room.idle_for(), size));
+        }
+        // Least recently used first.
+        rooms.sort_by_key(|(_, _, idle_for, _)| std::cmp::Reverse(*idle_for));
+        let budget = self.config.memory_budget;
+        let mut total: usize = rooms.iter().map(|(_, _, _, size)| size).sum();
+        let mut unloaded = 0;
+        for (blocks, id, idle_for, size) in rooms {
+            let over_budget = budget > 0 && total > budget;
+            if idle_for < self.config.idle_timeout && !over_budget {
+                continue;
+            }
+            let dropped = match blocks {
+                false => self.unload(&self.rooms, &id, Storage::save_snapshot).await,
+                true => {
+                    self.unload(&self.block_rooms, &id, Storage::save_blocks)
+                        .await
+                }
+            };
+            if dropped {
+                total -= size;
+                unloaded += 1;
+            }
+        }
+        unloaded
+    }
+
+    /// Saves a document with `save` and drops its room from `rooms`, unless
+    /// a connection or request holds it.
+    async fn unload<D>(
+        &self,
+        rooms: &RwLock<HashMap<String, Arc<Room<D>>>>,
+        id: &str,
+        save: impl FnOnce(&Storage, &str, &D) -> io::Result<()>,
+    ) -> bool {
+        let Some(storage) = &self.storage else {
+            return false;
+        };
+        let _loading = self.loading.lock().await;
+        let room = {
+            let mut rooms = rooms.write().await;
+            // Handles are only taken while `rooms` is locked, so none can
+            // be handed out between this check and the removal.
+            match rooms.get(id) {
+                Some(room) if !room.in_use() => rooms.remove(id),
+                _ => None,
+            }
+        };
+        let Some(room) = room else {
+            return false;
+        };
+        let saved = save(storage, id, &*room.document.read().await);
+        if let Err(e) = saved {
+            error!(document = %id, error = %e, "Failed to save document, keeping it in memory");
+            rooms.write().await.insert(id.to_string(), room);
+            return false;
         }
+        debug!(document = %id, "Unloaded idle document");
+        self.metrics.documents_unloaded_total.inc();
+        true
+    }
+
+    /// Lists the rooms currently held in memory.
+    pub async fn rooms_snapshot(&self) -> Vec<Arc<Room>> {
+        self.rooms.read().await.values().cloned().collect()
+    }
+
+    /// Returns the room for a block document, loading it from storage or
+    /// creating an empty one if it is not in memory. A follower's replica is
+    /// brought up to date first.
+    pub async fn block_room(&self, id: &str) -> io::Result<RoomHandle<BlockDocument>> {
+        let room = self.block_room_entry(id).await?;
+        if !room.is_synced() {
+            self.sync_replica(&room, &block_document(id)).await;
+        }
+        Ok(room)
+    }
+
+    async fn block_room_entry(&self, id: &str) -> io::Result<RoomHandle<BlockDocument>> {
+        if let Some(room) = self.loaded_block_room(id).await {
+            return Ok(room);
+        }
+        if !room::is_valid_room_id(id) {
+            return Err(invalid_document_id());
+        }
+        let _loading = self.loading.lock().await;
+        if let Some(room) = self.loaded_block_room(id).await {
+            return Ok(room);
+        }
+        let doc = self
+            .load_blocks(id)?
+            .unwrap_or_else(|| self.new_block_document());
+        Ok(self.insert_block_room(id, doc).await)
+    }
+
+    /// Reads a block document that is not in memory from storage, like
+    /// [`ServerState::load_document`].
+    fn load_blocks(&self, id: &str) -> io::Result<Option<BlockDocument>> {
+        let Some(storage) = &self.storage else {
+            return Ok(None);
+        };
+        let Some(mut doc) = storage.load_blocks(id)? else {
+            return Ok(None);
+        };
+        debug!(document = %id, version = doc.version, "Loaded block document");
+        doc.max_size = Some(self.config.limits.max_document_size);
+        Ok(Some(doc))
+    }
+
+    async fn insert_block_room(&self, id: &str, doc: BlockDocument) -> RoomHandle<BlockDocument> {
+        let mut rooms = self.block_rooms.write().await;
+        let room = rooms
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(self.new_block_room(id, doc)));
+        RoomHandle::new(room)
+    }
 
-        // Ensure valid UTF-8 character boundary for insertion
-        if let Some(ref insert) = edit.insert {
-            if !self.content.is_char_boundary(edit.position) {
-                eprintln!(
-                    "Insert position is not a valid UTF-8 boundary: {}",
-                    edit.position
-                );
-                return Err("Insert position is not a valid UTF-8 boundary.");
+    /// Wraps a block document in a room. Block documents are only shared
+    /// between a primary and its followers.
+    fn new_block_room(&self, id: &str, doc: BlockDocument) -> BlockRoom {
+        match &self.cluster {
+            Some(cluster) if cluster.replicates_everything() => {
+                Room::shared(id, &block_document(id), doc, cluster.clone())
             }
-            self.content.insert_str(edit.position, insert);
+            _ => Room::new(id, doc),
+        }
+    }
+
+    /// Returns the room for a block document if it exists, loading it from
+    /// storage if need be, without creating one.
+    pub async fn find_block_room(&self, id: &str) -> Option<RoomHandle<BlockDocument>> {
+        if let Some(room) = self.loaded_block_room(id).await {
+            return Some(room);
+        }
+        if !room::is_valid_room_id(id) {
+            return None;
+        }
+        let _loading = self.loading.lock().await;
+        if let Some(room) = self.loaded_block_room(id).await {
+            return Some(room);
         }
-        // Ensure valid UTF-8 character boundary for deletion
-        else if let Some(delete) = edit.delete {
-            let end = edit.position + delete;
-            if !self.content.is_char_boundary(edit.position) || !self.content.is_char_boundary(end)
-            {
-                eprintln!(
-                    "Delete range is not valid UTF-8 boundaries: {} to {}",
-                    edit.position, end
-                );
-                return Err("Delete range is not valid UTF-8 boundaries.");
+        match self.load_blocks(id) {
+            Ok(doc) => Some(self.insert_block_room(id, doc?).await),
+            Err(e) => {
+                error!(document = %id, error = %e, "Failed to load block document");
+                None
             }
-            self.content.replace_range(edit.position..end, "");
         }
+    }
+
+    /// Returns the room for a block document if it is in memory.
+    async fn loaded_block_room(&self, id: &str) -> Option<RoomHandle<BlockDocument>> {
+        let room = RoomHandle::new(self.block_rooms.read().await.get(id)?);
+        room.touch();
+        Some(room)
+    }
+
+    /// Lists the block document rooms currently held in memory.
+    pub async fn block_rooms_snapshot(&self) -> Vec<Arc<BlockRoom>> {
+        self.block_rooms.read().await.values().cloned().collect()
+    }
+
+    fn new_document(&self) -> DocumentState {
+        DocumentState::with_max_size(self.config.limits.max_document_size)
+    }
+
+    fn new_block_document(&self) -> BlockDocument {
+        BlockDocument::with_max_size(self.config.limits.max_document_size)
+    }
+
+    /// Checks persisted documents can be listed, then marks the server as
+    /// recovered. Documents are loaded when first used.
+    pub async fn restore(&self) -> io::Result<()> {
+        if let Some(storage) = &self.storage {
+            let documents = storage.list_documents()?;
+            let block_documents = storage.list_block_documents()?;
+            info!(
+                documents = documents.len(),
+                block_documents = block_documents.len(),
+                "Found persisted documents"
+            );
+        }
+        self.recovered.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether the server can take traffic: documents have been recovered,
+    /// storage is usable and shutdown has not begun.
+    pub fn readiness(&self) -> Readiness {
+        Readiness {
+            recovered: self.recovered.load(Ordering::SeqCst),
+            storage: match &self.storage {
+                Some(storage) => storage.check().map_err(|e| e.to_string()),
+                None => Ok(()),
+            },
+            shutting_down: self.shutting_down.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Makes a follower the primary: it stops following and accepts
+    /// changes to every document from then on. Returns false if this
+    /// server is not a follower.
+    pub async fn promote(&self) -> bool {
+        if !self.cluster.as_ref().is_some_and(Cluster::promote) {
+            return false;
+        }
+        // Connections waiting for a copy from the old primary stop waiting.
+        for room in self.rooms_snapshot().await {
+            room.set_synced(true);
+        }
+        for room in self.block_rooms_snapshot().await {
+            room.set_synced(true);
+        }
+        info!("Promoted to primary");
+        true
+    }
 
-        self.version += 1;
+    /// Writes every document to storage, if persistence is enabled.
+    ///
+    /// Holds `loading` like [`ServerState::unload`] and deletes do, so no
+    /// two saves of a document share its temporary file and a document
+    /// deleted meanwhile is not written back.
+    pub async fn flush(&self) -> io::Result<()> {
+        if let Some(storage) = &self.storage {
+            let _loading = self.loading.lock().await;
+            for room in self.rooms_snapshot().await {
+                storage.save_snapshot(&room.id, &*room.document.read().await)?;
+            }
+            for room in self.block_rooms_snapshot().await {
+                storage.save_blocks(&room.id, &*room.document.read().await)?;
+            }
+        }
         Ok(())
     }
 }
 
 pub async fn run_server() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "0.0.0.0:8080";
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    println!("Listening on: {}", addr);
+    let config = ServerConfig::from_env();
+    let _telemetry = logging::init(&config.logging);
+    let listener = TcpListener::bind(&config.addr).await?;
+    info!(addr = %config.addr, "Listening");
 
-    let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
-    let document = Arc::new(RwLock::new(DocumentState::new()));
+    let replication = config.replication.clone();
+    let replicating = replication.listen.is_some() || replication.follow.is_some();
+    if (config.cluster.node_id.is_some() && replicating)
+        || (replication.listen.is_some() && replication.follow.is_some())
+    {
+        return Err("A server is either a cluster node, a primary or a follower".into());
+    }
+    if replicating && config.api_token.is_none() {
+        return Err("Followers authenticate with EDITOR_API_TOKEN, which is not set".into());
+    }
+    if config.cluster.node_id.is_some() && config.api_token.is_none() {
+        return Err("Nodes authenticate with EDITOR_API_TOKEN, which is not set".into());
+    }
 
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, peers.clone(), document.clone()));
+    let mut state = ServerState::new(config);
+    let mut node_messages = None;
+    let mut primary = None;
+    if let Some(node_id) = &state.config.cluster.node_id {
+        let cluster_config = &state.config.cluster;
+        let backplane_listener = TcpListener::bind(&cluster_config.backplane_addr).await?;
+        info!(addr = %cluster_config.backplane_addr, node = %node_id, "Backplane listening");
+        let token = state.config.api_token.as_deref().unwrap_or_default();
+        let (backplane, messages) =
+            TcpBackplane::start(backplane_listener, &cluster_config.peers, token);
+        let peers: Vec<String> = cluster_config
+            .peers
+            .iter()
+            .map(|peer| peer.id.clone())
+            .collect();
+        let cluster = Cluster::new(node_id, &peers, Arc::new(backplane));
+        state = state.with_cluster(cluster);
+        node_messages = Some(messages);
+    } else if let Some(addr) = &replication.listen {
+        let replication_listener = TcpListener::bind(addr).await?;
+        info!(%addr, "Accepting followers");
+        let followers = Arc::new(Followers::new());
+        let (requests, messages) = mpsc::unbounded_channel();
+        state = state.with_cluster(Cluster::primary(PRIMARY, followers.clone()));
+        node_messages = Some(messages);
+        primary = Some((replication_listener, followers, requests));
+    } else if let Some(addr) = &replication.follow {
+        info!(%addr, "Following primary");
+        let token = state.config.api_token.as_deref().unwrap_or_default();
+        let (link, messages) = PrimaryLink::connect(addr, token, state.storage.clone());
+        state = state.with_cluster(Cluster::follower(FOLLOWER, PRIMARY, Arc::new(link)));
+        node_messages = Some(messages);
     }
 
+    let state = Arc::new(state);
+    if let Some(messages) = node_messages {
+        tokio::spawn(run_backplane(state.clone(), messages));
+    }
+    if let Some((listener, followers, requests)) = primary {
+        tokio::spawn(replication::serve_followers(
+            listener,
+            state.clone(),
+            followers,
+            requests,
+        ));
+    }
+    if state.storage.is_some() {
+        tokio::spawn(unload_idle_documents(state.clone()));
+    }
+    // Start answering health checks straight away; WebSocket connections are
+    // turned away until recovery completes.
+    let server = serve(listener, state.clone(), shutdown_signal());
+    tokio::pin!(server);
+    tokio::select! {
+        restored = state.restore() => restored?,
+        _ = &mut server => return Ok(()),
+    }
+    server.await;
+    state.flush().await?;
     Ok(())
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    peers: PeerMap,
-    document: Arc<RwLock<DocumentState>>,
+/// Periodically saves and drops from memory the documents nobody is using,
+/// see [`ServerState::unload_idle`].
+pub async fn unload_idle_documents(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(UNLOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let unloaded = state.unload_idle().await;
+        if unloaded > 0 {
+            info!(documents = unloaded, "Unloaded idle documents");
+        }
+    }
+}
+
+/// Completes when the process receives SIGINT or, on Unix, SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(error = %e, "Failed to listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
+
+/// Accepts connections on an already bound listener until `shutdown`
+/// completes, then asks every peer to disconnect and waits up to the
+/// configured shutdown timeout for them to go.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    shutdown: impl Future<Output = ()>,
 ) {
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let span = info_span!(
+                        "connection",
+                        %peer,
+                        session = state.next_session_id(),
+                        document = field::Empty,
+                        user = field::Empty,
+                    );
+                    connections.spawn(handle_connection(stream, state.clone()).instrument(span));
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to accept connection");
+                    break;
+                }
+            },
+            // Reap finished connections so the set does not grow forever.
+            Some(_) = connections.join_next() => (),
+            _ = &mut shutdown => break,
+        }
+    }
+    drop(listener);
+
+    info!(
+        connections = state.metrics.active_connections.get(),
+        "Shutting down"
+    );
+    state.shutting_down.store(true, Ordering::SeqCst);
+    let mut peer_maps: Vec<_> = state
+        .rooms_snapshot()
+        .await
+        .iter()
+        .map(|room| room.peers.clone())
+        .collect();
+    peer_maps.extend(
+        state
+            .block_rooms_snapshot()
+            .await
+            .iter()
+            .map(|room| room.peers.clone()),
+    );
+    for peers in peer_maps {
+        for peer in peers.read().await.values() {
+            let _ = peer.send(Message::Close(Some(restart_close_frame())));
+        }
+    }
+
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(state.config.shutdown_timeout, drain)
+        .await
+        .is_err()
+    {
+        warn!(
+            connections = connections.len(),
+            timeout = ?state.config.shutdown_timeout,
+            "Connections still open after the shutdown timeout, dropping them"
+        );
+        connections.shutdown().await;
+    }
+}
+
+/// Error for ids that could name a file outside the data directory, which
+/// are never loaded.
+fn invalid_document_id() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Invalid document id")
+}
+
+fn restart_close_frame() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Restart,
+        reason: "server restarting".into(),
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<ServerState>) {
     let addr = stream
         .peer_addr()
         .expect("Connected streams should have a peer address");
-    println!("Peer address: {}", addr);
 
-    let ws_stream = match accept_async(stream).await {
+    let (mut request, buffered) =
+        match tokio::time::timeout(REQUEST_HEAD_TIMEOUT, http::read_request(&mut stream)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                debug!(error = %e, "Failed to read request");
+                return;
+            }
+            Err(_) => {
+                debug!("Timed out waiting for a request");
+                return;
+            }
+        };
+
+    if !request.is_websocket_upgrade() {
+        let max_body_size = state.config.limits.max_message_size;
+        let response =
+            match http::read_body(&mut stream, &mut request, &buffered, max_body_size).await {
+                Ok(()) => http::route(&request, addr.ip(), &state).await,
+                Err(e) => HttpResponse::text(400, format!("{}\n", e)),
+            };
+        debug!(
+            method = %request.method,
+            path = %request.path,
+            status = response.status,
+            "HTTP request"
+        );
+        if let Err(e) = http::write_response(&mut stream, &response).await {
+            warn!(error = %e, "Failed to write HTTP response");
+        }
+        return;
+    }
+
+    if !state.recovered.load(Ordering::SeqCst) {
+        let response = HttpResponse::text(503, "Server is still recovering\n");
+        let _ = http::write_response(&mut stream, &response).await;
+        return;
+    }
+
+    let target = match target_from_request(&request) {
+        Some(target) => target,
+        None => {
+            let response = HttpResponse::text(400, "Invalid document id\n");
+            let _ = http::write_response(&mut stream, &response).await;
+            return;
+        }
+    };
+    // WebSocket connections don't need the API token: it authorizes
+    // operators and services over HTTP, not the editors of a document.
+    // Anonymous connections are named by their IP address.
+    let user = user_from_request(&request).unwrap_or_else(|| addr.ip().to_string());
+
+    let limits = &state.config.limits;
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(limits.max_message_size),
+        max_frame_size: Some(limits.max_frame_size),
+        ..WebSocketConfig::default()
+    };
+    // Clients offering only protocols we don't speak get no protocol header
+    // back and plain JSON, leaving it to them to give up on the connection.
+    let offered = request.header("sec-websocket-protocol");
+    let negotiated = Codec::negotiate(offered);
+    let codec = negotiated.unwrap_or_default();
+    // The error type is fixed by tungstenite's handshake callback.
+    #[allow(clippy::result_large_err)]
+    let select_protocol = |_: &WsRequest, mut response: WsResponse| {
+        if let (Some(_), Some(codec)) = (offered, negotiated) {
+            response.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(codec.protocol()),
+            );
+        }
+        Ok(response)
+    };
+    let stream = PrefixedStream::new(buffered, stream);
+    let handshake = accept_hdr_async_with_config(stream, select_protocol, Some(ws_config));
+    let ws_stream = match handshake.instrument(info_span!("handshake")).await {
         Ok(ws) => ws,
         Err(e) => {
-            eprintln!("WebSocket handshake failed: {}", e); // Log the handshake failure
+            warn!(error = %e, "WebSocket handshake failed"); // Log the handshake failure
             return; // Don't panic, just return and let the server continue
         }
     };
 
-    println!("New WebSocket connection: {}", addr);
+    let span = Span::current();
+    match &target {
+        Target::Document(id) => span.record("document", id.as_str()),
+        Target::Blocks(id) => span.record("document", block_document(id)),
+    };
+    span.record("user", user.as_str());
+    info!("New WebSocket connection");
+    state.metrics.connections_total.inc();
+    state.metrics.active_connections.inc();
 
-    let (tx, rx) = mpsc::unbounded_channel();
-    peers.write().await.insert(addr.to_string(), tx.clone());
+    let mut session = Session {
+        addr,
+        user,
+        bucket: TokenBucket::new(limits.connection_burst, limits.connection_edits_per_sec),
+        undo: UndoStack::new(),
+        suggesting: false,
+        codec,
+        compress: request.query_param("compress").as_deref() == Some(DEFLATE),
+        since: request
+            .query_param("since")
+            .and_then(|since| since.parse().ok()),
+        node: None,
+    };
+    match target {
+        Target::Document(id) => {
+            let room = match state.room(&id).await {
+                Ok(room) => room,
+                Err(e) => {
+                    error!(error = %e, "Failed to load document");
+                    let close = CloseFrame {
+                        code: CloseCode::Error,
+                        reason: "Failed to load document".into(),
+                    };
+                    let mut ws_stream = ws_stream;
+                    let _ = ws_stream.close(Some(close)).await;
+                    state.metrics.active_connections.dec();
+                    return;
+                }
+            };
+            run_peer(ws_stream, &mut session, &room, &state).await;
+            let forwarded = state
+                .cluster
+                .as_ref()
+                .filter(|c| c.forwards() && !c.owns(&id));
+            if let Some(cluster) = forwarded {
+                let left = NodeMessage::Left {
+                    document: id,
+                    from: cluster.node_id().to_string(),
+                    connection: session.addr.to_string(),
+                };
+                cluster.send(cluster.owner(&room.id), &left);
+            }
+        }
+        Target::Blocks(id) => {
+            let room = match state.block_room(&id).await {
+                Ok(room) => room,
+                Err(e) => {
+                    error!(error = %e, "Failed to load document");
+                    let close = CloseFrame {
+                        code: CloseCode::Error,
+                        reason: "Failed to load document".into(),
+                    };
+                    let mut ws_stream = ws_stream;
+                    let _ = ws_stream.close(Some(close)).await;
+                    state.metrics.active_connections.dec();
+                    return;
+                }
+            };
+            run_peer(ws_stream, &mut session, &room, &state).await
+        }
+    }
+    info!("Disconnected");
+    state.metrics.active_connections.dec();
+}
+
+/// A kind of document a room can hold, and how connections to it talk to
+/// the server.
+trait RoomDocument: Sized {
+    /// What a new connection needs to start from, copied out while the
+    /// document is locked.
+    type Snapshot: Send;
+
+    /// `since` is the version the client already has, if it said so.
+    fn snapshot(&self, since: Option<usize>) -> Self::Snapshot;
+
+    /// The messages that send a snapshot to a new connection, in order.
+    fn initial_messages(snapshot: Self::Snapshot, chunk_size: usize) -> Vec<serde_json::Value>;
+
+    async fn handle_message(
+        msg: Message,
+        session: &mut Session,
+        room: &Room<Self>,
+        state: &ServerState,
+        peer: &Peer,
+    );
+}
+
+impl RoomDocument for DocumentState {
+    type Snapshot = Join;
+
+    fn snapshot(&self, since: Option<usize>) -> Join {
+        // Clients from a version history no longer reaches get everything.
+        match since.and_then(|since| Some((since, self.ops_since(since)?))) {
+            Some((since, ops)) => Join::CatchUp(json!({
+                "type": "catch_up",
+                "since": since,
+                "version": self.version,
+                "edits": ops.iter().map(applied_edit).collect::<Vec<_>>(),
+                "formatting": self.formatting.spans(),
+            })),
+            None => Join::Full(self.content.clone(), self.version, self.formatting.spans()),
+        }
+    }
+
+    fn initial_messages(snapshot: Join, chunk_size: usize) -> Vec<serde_json::Value> {
+        let (content, version, formatting) = match snapshot {
+            Join::CatchUp(message) => return vec![message],
+            Join::Full(content, version, formatting) => (content, version, formatting),
+        };
+        if content.len() <= chunk_size {
+            return vec![json!({
+                "type": "initial",
+                "content": content,
+                "version": version,
+                "formatting": formatting,
+            })];
+        }
+        let chunks = content_chunks(&content, chunk_size);
+        let mut messages = Vec::with_capacity(chunks.len() + 2);
+        messages.push(json!({
+            "type": "initial_start",
+            "version": version,
+            "length": content.len(),
+            "chunks": chunks.len(),
+            "formatting": formatting,
+        }));
+        messages.extend(chunks.iter().enumerate().map(|(index, chunk)| {
+            json!({
+                "type": "initial_chunk",
+                "version": version,
+                "index": index,
+                "content": chunk,
+            })
+        }));
+        messages.push(json!({ "type": "initial_end", "version": version }));
+        messages
+    }
+
+    async fn handle_message(
+        msg: Message,
+        session: &mut Session,
+        room: &Room,
+        state: &ServerState,
+        peer: &Peer,
+    ) {
+        match &state.cluster {
+            Some(cluster) if cluster.forwards() && !cluster.owns(&room.id) => {
+                forward(msg, session, room, cluster, peer)
+            }
+            _ => handle_message(msg, session, room, state, peer).await,
+        }
+    }
+}
 
-    // Send the initial document state to the new client
-    let initial_message = {
-        let doc = document.read().await;
+impl RoomDocument for BlockDocument {
+    type Snapshot = serde_json::Value;
+
+    fn snapshot(&self, _since: Option<usize>) -> Self::Snapshot {
         json!({
             "type": "initial",
-            "content": doc.content,
-            "version": doc.version,
+            "version": self.version,
+            "blocks": self.blocks,
         })
-    };
-    if let Err(e) = tx.send(Message::Text(initial_message.to_string())) {
-        eprintln!("Failed to send initial content to {}: {}", addr, e);
     }
 
-    let (outgoing, incoming) = ws_stream.split();
+    fn initial_messages(snapshot: Self::Snapshot, _chunk_size: usize) -> Vec<serde_json::Value> {
+        vec![snapshot]
+    }
 
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        let peers = peers.clone();
-        let document = document.clone();
+    async fn handle_message(
+        msg: Message,
+        session: &mut Session,
+        room: &BlockRoom,
+        state: &ServerState,
+        peer: &Peer,
+    ) {
+        handle_block_message(msg, session, room, state, peer).await
+    }
+}
 
-        async move {
-            match msg.to_text() {
-                Ok(text) => {
-                    let data: serde_json::Value = match serde_json::from_str(text) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            eprintln!("Failed to parse message: {}", e);
-                            return Ok(());
-                        }
-                    };
+/// What a connection joining a flat document is sent.
+enum Join {
+    /// The whole document: its content, version and formatting.
+    Full(String, usize, Vec<FormatSpan>),
+    /// A `catch_up` message with the edits made since the version the
+    /// client has.
+    CatchUp(serde_json::Value),
+}
 
-                    if data["type"] == "edit" {
-                        let edit_json = &data["edit"];
-                        let edit: Edit = match serde_json::from_value(edit_json.clone()) {
-                            Ok(edit) => edit,
-                            Err(e) => {
-                                eprintln!("Failed to parse edit: {}", e);
-                                return Ok(());
-                            }
-                        };
+/// Splits content into pieces of at most `chunk_size` bytes, or a single
+/// character where one is longer, without splitting any character.
+fn content_chunks(content: &str, chunk_size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < content.len() {
+        let mut end = (start + chunk_size).min(content.len());
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == start {
+            end = start + content[start..].chars().next().map_or(1, char::len_utf8);
+        }
+        chunks.push(&content[start..end]);
+        start = end;
+    }
+    chunks
+}
 
-                        let mut doc = document.write().await;
-                        match doc.apply_edit(&edit) {
-                            Ok(_) => {
-                                let new_version = doc.version;
-                                let broadcast_msg = json!({
-                                    "type": "edit",
-                                    "edit": {
-                                        "position": edit.position,
-                                        "insert": edit.insert,
-                                        "delete": edit.delete,
-                                        "version": new_version,
-                                    }
-                                })
-                                .to_string();
-
-                                // Broadcast to all peers except the sender
-                                let peers_guard = peers.read().await;
-                                for (peer_addr, recp) in peers_guard.iter() {
-                                    if peer_addr != &addr.to_string() {
-                                        if let Err(e) =
-                                            recp.send(Message::Text(broadcast_msg.clone()))
-                                        {
-                                            eprintln!(
-                                                "Failed to send message to {}: {}",
-                                                peer_addr, e
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Error applying edit: {}", e);
-                                // Optionally, send error back to client
-                            }
-                        }
-                    }
+/// Joins a connection to a room: sends it the initial state, handles each
+/// message it sends and forwards everything broadcast to the room until
+/// either side goes away.
+async fn run_peer<D: RoomDocument>(
+    ws_stream: WebSocketStream<PrefixedStream<TcpStream>>,
+    session: &mut Session,
+    room: &Room<D>,
+    state: &ServerState,
+) {
+    let addr = session.addr;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut peer = Peer::with_codec(tx, session.codec);
+    if session.compress {
+        peer = peer.with_compression(state.config.compression_threshold);
+    }
+    // Joining while the document is locked means every change after the
+    // snapshot is queued for the peer, and none before it.
+    let snapshot = {
+        let doc = room.document.read().await;
+        room.peers
+            .write()
+            .await
+            .insert(addr.to_string(), peer.clone());
+        doc.snapshot(session.since)
+    };
+    // Shutdown may have started while this connection was handshaking, in
+    // which case it missed the close frame sent to every peer.
+    if state.shutting_down.load(Ordering::SeqCst) {
+        let _ = peer.send(Message::Close(Some(restart_close_frame())));
+    }
+
+    let (mut outgoing, mut incoming) = ws_stream.split();
+
+    // Send the initial document state straight to the socket, one chunk at a
+    // time; changes made meanwhile wait in the queue until it has all gone.
+    for message in D::initial_messages(snapshot, state.config.initial_chunk_size) {
+        if let Err(e) = outgoing.send(peer.encode(&message)).await {
+            error!(error = %e, "Failed to send initial content");
+            room.peers.write().await.remove(&addr.to_string());
+            return;
+        }
+    }
+
+    let broadcast_incoming = async {
+        while let Some(msg) = incoming.next().await {
+            match msg {
+                Ok(msg) => D::handle_message(msg, session, room, state, &peer).await,
+                Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size })) => {
+                    warn!(size, max_size, "Message too large");
+                    state.metrics.oversized_messages_total.inc();
+                    let close = CloseFrame {
+                        code: CloseCode::Size,
+                        reason: "Message too large".into(),
+                    };
+                    let _ = peer.send(Message::Close(Some(close)));
+                    break;
+                }
+                Err(e) => {
+                    warn!(error = %e, "Error receiving message");
+                    break;
                 }
-                Err(e) => eprintln!("Received non-text message: {}", e),
             }
-            Ok(())
         }
-    });
+    };
 
-    let receive_from_others = UnboundedReceiverStream::new(rx).map(Ok).forward(outgoing);
+    let sent = peer.clone();
+    let receive_from_others = UnboundedReceiverStream::new(rx)
+        .inspect(move |_| sent.mark_sent())
+        .map(Ok)
+        .forward(outgoing);
+    tokio::pin!(receive_from_others);
 
+    let mut outgoing_closed = false;
     tokio::select! {
         _ = broadcast_incoming => (),
-        _ = receive_from_others => (),
+        _ = &mut receive_from_others => outgoing_closed = true,
     }
 
-    println!("{} disconnected", &addr);
-    peers.write().await.remove(&addr.to_string());
+    room.peers.write().await.remove(&addr.to_string());
+    room.touch();
+
+    // Give queued messages, such as a close frame explaining why the
+    // connection is being dropped, a chance to reach the client.
+    drop(peer);
+    if !outgoing_closed {
+        let _ = tokio::time::timeout(Duration::from_secs(1), receive_from_others).await;
+    }
+}
+
+/// Passes a message from a client on to the node that owns its document.
+fn forward(msg: Message, session: &Session, room: &Room, cluster: &Cluster, peer: &Peer) {
+    let message = match peer.codec().decode(&msg) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!(error = %e, "Failed to parse message");
+            return;
+        }
+    };
+    let forwarded = NodeMessage::Forward {
+        document: room.id.clone(),
+        from: cluster.node_id().to_string(),
+        connection: session.addr.to_string(),
+        user: session.user.clone(),
+        message,
+    };
+    cluster.send(cluster.owner(&room.id), &forwarded);
+}
+
+fn request_sync(cluster: &Cluster, document: &str) {
+    let request = NodeMessage::SyncRequest {
+        document: document.to_string(),
+        from: cluster.node_id().to_string(),
+    };
+    cluster.send(cluster.owner(document), &request);
+}
+
+/// Handles the messages other nodes send this one until the backplane
+/// closes: messages forwarded to the documents this node owns, and the
+/// owners' broadcasts for the documents it replicates.
+pub async fn run_backplane(
+    state: Arc<ServerState>,
+    mut messages: mpsc::UnboundedReceiver<NodeMessage>,
+) {
+    let Some(cluster) = state.cluster.clone() else {
+        return;
+    };
+    // Sessions of the clients whose messages other nodes forward, by
+    // `node/address`.
+    let mut sessions: HashMap<String, Session> = HashMap::new();
+    while let Some(message) = messages.recv().await {
+        match message {
+            NodeMessage::Forward {
+                document,
+                from,
+                connection,
+                user,
+                message,
+            } => {
+                let Ok(addr) = connection.parse() else {
+                    warn!(node = %from, connection, "Ignoring a message from an invalid address");
+                    continue;
+                };
+                if !cluster.owns(&document) {
+                    warn!(%document, node = %from, "Ignoring a message for a document owned elsewhere");
+                    continue;
+                }
+                let limits = &state.config.limits;
+                let session = sessions
+                    .entry(format!("{}/{}", from, connection))
+                    .or_insert_with(|| Session {
+                        addr,
+                        user: user.clone(),
+                        bucket: TokenBucket::new(
+                            limits.connection_burst,
+                            limits.connection_edits_per_sec,
+                        ),
+                        undo: UndoStack::new(),
+                        suggesting: false,
+                        codec: Codec::Json,
+                        compress: false,
+                        since: None,
+                        node: Some(from.clone()),
+                    });
+                let room = match state.room(&document).await {
+                    Ok(room) => room,
+                    Err(e) => {
+                        error!(%document, error = %e, "Failed to load document");
+                        continue;
+                    }
+                };
+                let (tx, mut replies) = mpsc::unbounded_channel();
+                let peer = Peer::new(tx);
+                let span = info_span!("forwarded", node = %from, %document, %user);
+                handle_message(
+                    Message::Text(message.to_string()),
+                    session,
+                    &room,
+                    &state,
+                    &peer,
+                )
+                .instrument(span)
+                .await;
+                while let Ok(reply) = replies.try_recv() {
+                    if let Message::Text(reply) = reply {
+                        let reply = NodeMessage::Reply {
+                            document: document.clone(),
+                            connection: connection.clone(),
+                            message: reply,
+                        };
+                        cluster.send(&from, &reply);
+                    }
+                }
+            }
+            NodeMessage::Left {
+                from, connection, ..
+            } => {
+                sessions.remove(&format!("{}/{}", from, connection));
+            }
+            NodeMessage::Reply {
+                document,
+                connection,
+                message,
+            } => {
+                let Some(room) = state.loaded_room(&document).await else {
+                    continue;
+                };
+                let peers = room.peers.read().await;
+                if let Some(peer) = peers.get(&connection) {
+                    let encoded =
+                        Encodings::new(&message).message_for(peer.codec(), peer.compress_over());
+                    let _ = peer.send(encoded);
+                }
+            }
+            NodeMessage::Broadcast {
+                document,
+                except,
+                message,
+            } => {
+                if cluster.owns(&document) {
+                    continue;
+                }
+                // Only connections to this node are named with its id.
+                let except = except
+                    .as_deref()
+                    .and_then(|sender| sender.strip_prefix(cluster.node_id()))
+                    .and_then(|sender| sender.strip_prefix('/'));
+                if let Some(id) = document.strip_prefix("blocks/") {
+                    let Some(room) = replica_block_room(&state, &cluster, id).await else {
+                        continue;
+                    };
+                    let mut doc = room.document.write().await;
+                    if !room.is_synced() {
+                        continue;
+                    }
+                    if !replicate_blocks(&mut doc, &message) {
+                        warn!(%document, version = doc.version, "Replica diverged from its owner");
+                        state.metrics.divergences_total.inc();
+                        room.set_synced(false);
+                        request_sync(&cluster, &document);
+                        continue;
+                    }
+                    room.deliver(except, &message).await;
+                    continue;
+                }
+                let room = match state.loaded_room(&document).await {
+                    Some(room) => room,
+                    // Followers replicate documents clients have not opened.
+                    None if cluster.replicates_everything() => {
+                        let room = match state.room_entry(&document).await {
+                            Ok(room) => room,
+                            Err(e) => {
+                                error!(%document, error = %e, "Failed to load document");
+                                continue;
+                            }
+                        };
+                        request_sync(&cluster, &document);
+                        room
+                    }
+                    None => continue,
+                };
+                // Delivering while the document is locked keeps joining
+                // connections from missing the message or seeing it twice.
+                let mut doc = room.document.write().await;
+                if !room.is_synced() {
+                    continue;
+                }
+                match replicate(&mut doc, &message) {
+                    Replicated::Applied => (),
+                    // Comments, suggestions and checkpoints are copied
+                    // from the owner rather than replayed.
+                    Replicated::NotAnEdit => request_sync(&cluster, &document),
+                    Replicated::Stale => continue,
+                    Replicated::Diverged => {
+                        warn!(%document, version = doc.version, "Replica diverged from its owner");
+                        state.metrics.divergences_total.inc();
+                        room.set_synced(false);
+                        request_sync(&cluster, &document);
+                        continue;
+                    }
+                }
+                room.deliver(except, &message).await;
+            }
+            NodeMessage::SyncRequest { document, from } => {
+                if !cluster.owns(&document) {
+                    continue;
+                }
+                let reply = match document.strip_prefix("blocks/") {
+                    Some(id) => {
+                        let room = match state.block_room(id).await {
+                            Ok(room) => room,
+                            Err(e) => {
+                                error!(%document, error = %e, "Failed to load document");
+                                continue;
+                            }
+                        };
+                        let doc = room.document.read().await;
+                        NodeMessage::BlockSnapshot {
+                            document,
+                            blocks: doc.clone(),
+                        }
+                    }
+                    None => {
+                        let room = match state.room(&document).await {
+                            Ok(room) => room,
+                            Err(e) => {
+                                error!(%document, error = %e, "Failed to load document");
+                                continue;
+                            }
+                        };
+                        let doc = room.document.read().await;
+                        NodeMessage::Snapshot {
+                            document,
+                            snapshot: Snapshot::of(&doc),
+                        }
+                    }
+                };
+                cluster.send(&from, &reply);
+            }
+            NodeMessage::Snapshot { document, snapshot } => {
+                if cluster.owns(&document) {
+                    continue;
+                }
+                let Some(room) = replica_room(&state, &cluster, &document).await else {
+                    continue;
+                };
+                let mut doc = room.document.write().await;
+                if let Err(e) = snapshot.restore_into(&mut doc) {
+                    warn!(%document, error = e, "Discarding the owner's history of a document");
+                }
+                debug!(%document, version = doc.version, "Replica synced");
+                if !room.is_synced() {
+                    room.set_synced(true);
+                    // Connections that joined while the replica was out of
+                    // date start over from the owner's copy.
+                    room.deliver(None, &full_state_message(&doc).to_string())
+                        .await;
+                }
+            }
+            NodeMessage::OpLog {
+                document,
+                ops,
+                version,
+                checksum,
+                checkpoints,
+                comments,
+                suggestions,
+                client_ops,
+            } => {
+                if cluster.owns(&document) {
+                    continue;
+                }
+                let Some(room) = replica_room(&state, &cluster, &document).await else {
+                    continue;
+                };
+                let mut doc = room.document.write().await;
+                if !catch_up(&mut doc, &ops, version, checksum) {
+                    warn!(%document, version = doc.version, "Replica could not resume from its owner's operations");
+                    state.metrics.divergences_total.inc();
+                    room.set_synced(false);
+                    request_sync(&cluster, &document);
+                    continue;
+                }
+                doc.checkpoints = checkpoints;
+                doc.comments = comments;
+                doc.suggestions = suggestions;
+                doc.client_ops = client_ops;
+                debug!(%document, version = doc.version, ops = ops.len(), "Replica resumed");
+                room.set_synced(true);
+                // Connections that joined meanwhile start over from the
+                // caught up copy.
+                room.deliver(None, &full_state_message(&doc).to_string())
+                    .await;
+            }
+            // Only a primary reads this, from followers as they connect.
+            NodeMessage::Resume { .. } => (),
+            NodeMessage::BlockSnapshot { document, blocks } => {
+                let Some(id) = document.strip_prefix("blocks/") else {
+                    continue;
+                };
+                if cluster.owns(&document) {
+                    continue;
+                }
+                let Some(room) = replica_block_room(&state, &cluster, id).await else {
+                    continue;
+                };
+                let mut doc = room.document.write().await;
+                let max_size = doc.max_size;
+                *doc = blocks;
+                doc.max_size = max_size;
+                debug!(%document, version = doc.version, "Replica synced");
+                if !room.is_synced() {
+                    room.set_synced(true);
+                    let message = json!({
+                        "type": "blocks",
+                        "version": doc.version,
+                        "blocks": doc.blocks,
+                    });
+                    room.deliver(None, &message.to_string()).await;
+                }
+            }
+        }
+    }
+}
+
+/// The room replicating a document, loaded or created if this node follows
+/// a primary and does not have it in memory.
+async fn replica_room(state: &ServerState, cluster: &Cluster, id: &str) -> Option<RoomHandle> {
+    if let Some(room) = state.loaded_room(id).await {
+        return Some(room);
+    }
+    if !cluster.replicates_everything() {
+        return None;
+    }
+    match state.room_entry(id).await {
+        Ok(room) => Some(room),
+        Err(e) => {
+            error!(document = %id, error = %e, "Failed to load document");
+            None
+        }
+    }
+}
+
+/// The room replicating a block document, created if this node follows a
+/// primary and has not seen the document yet.
+async fn replica_block_room(
+    state: &ServerState,
+    cluster: &Cluster,
+    id: &str,
+) -> Option<RoomHandle<BlockDocument>> {
+    if !cluster.replicates_everything() {
+        return None;
+    }
+    match state.block_room_entry(id).await {
+        Ok(room) => Some(room),
+        Err(e) => {
+            error!(document = %id, error = %e, "Failed to load document");
+            None
+        }
+    }
+}
+
+/// Applies a message the owner of a block document broadcast to a replica
+/// of it, returning false if the replica cannot follow it.
+fn replicate_blocks(doc: &mut BlockDocument, message: &str) -> bool {
+    let Ok(message) = serde_json::from_str::<serde_json::Value>(message) else {
+        return true;
+    };
+    let Some(version) = message["version"].as_u64().map(|version| version as usize) else {
+        return true;
+    };
+    // Messages the replica already has are skipped.
+    if version <= doc.version {
+        return true;
+    }
+    match message["type"].as_str() {
+        Some("block_edit") => {
+            let Ok(mut op) = serde_json::from_value::<BlockOp>(message["op"].clone()) else {
+                return false;
+            };
+            doc.apply(&mut op, version - 1).is_ok() && doc.version == version
+        }
+        Some("blocks") => match serde_json::from_value(message["blocks"].clone()) {
+            Ok(blocks) => doc.replace_all(blocks).is_ok() && doc.version == version,
+            Err(_) => false,
+        },
+        _ => true,
+    }
+}
+
+/// What a replica made of a message its document's owner broadcast.
+enum Replicated {
+    Applied,
+    /// An edit the replica already has.
+    Stale,
+    /// An edit the replica cannot apply, or which left it with different
+    /// content than the owner.
+    Diverged,
+    NotAnEdit,
+}
+
+/// Applies an edit the owner of a document broadcast to a replica of it.
+fn replicate(doc: &mut DocumentState, message: &str) -> Replicated {
+    let Ok(message) = serde_json::from_str::<serde_json::Value>(message) else {
+        return Replicated::NotAnEdit;
+    };
+    replicate_edit(doc, &message)
+}
+
+/// Applies the operations a replica saved by a follower is missing,
+/// skipping the ones it already has. Returns false unless they bring it to
+/// `version` with content matching `expected`.
+fn catch_up(doc: &mut DocumentState, ops: &[AppliedOp], version: usize, expected: u32) -> bool {
+    for op in ops {
+        if op.version < doc.version {
+            continue;
+        }
+        let message = json!({ "type": "edit", "edit": applied_edit(op) });
+        if op.version > doc.version || !matches!(replicate_edit(doc, &message), Replicated::Applied)
+        {
+            return false;
+        }
+    }
+    doc.version == version && checksum::checksum(&doc.content) == expected
+}
+
+fn replicate_edit(doc: &mut DocumentState, message: &serde_json::Value) -> Replicated {
+    if message["type"] != "edit" {
+        return Replicated::NotAnEdit;
+    }
+    let edit = &message["edit"];
+    let Some(new_version) = edit["version"].as_u64().map(|version| version as usize) else {
+        return Replicated::Diverged;
+    };
+    if new_version <= doc.version {
+        return Replicated::Stale;
+    }
+    let version = new_version - 1;
+    let applied = match edit.get("format").filter(|format| !format.is_null()) {
+        Some(format) => serde_json::from_value::<FormatEdit>(json!({
+            "position": edit["position"],
+            "length": format["length"],
+            "attributes": format["attributes"],
+            "version": version,
+        }))
+        .map_err(|_| INVALID_FORMAT)
+        .and_then(|format| doc.apply_format(&format)),
+        None => {
+            let mut edit = edit.clone();
+            edit["version"] = version.into();
+            serde_json::from_value::<Edit>(edit)
+                .map_err(|_| "Invalid edit")
+                .and_then(|edit| doc.apply_edit(&edit))
+        }
+    };
+    let matches = message["checksum"]
+        .as_u64()
+        .is_none_or(|expected| u64::from(checksum::checksum(&doc.content)) == expected);
+    match applied {
+        Ok(()) if matches => Replicated::Applied,
+        _ => Replicated::Diverged,
+    }
+}
+
+async fn handle_message(
+    msg: Message,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let parsed = info_span!("parse", bytes = msg.len()).in_scope(|| peer.codec().decode(&msg));
+    let data = match parsed {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "Failed to parse message");
+            return;
+        }
+    };
+    if !check_writable(&data, room, peer) {
+        return;
+    }
+
+    match data["type"].as_str() {
+        Some("edit") => {
+            let edit_json = &data["edit"];
+            let edit: Edit = match serde_json::from_value(edit_json.clone()) {
+                Ok(edit) => edit,
+                Err(e) => {
+                    warn!(error = %e, "Failed to parse edit");
+                    return;
+                }
+            };
+
+            let Some(op_id) = op_id(&data, peer) else {
+                return;
+            };
+            let span = info_span!(
+                "edit",
+                version = edit.version,
+                op_size = edit.op_size(),
+                outcome = field::Empty,
+            );
+            telemetry::set_remote_parent(&span, data["traceparent"].as_str());
+            if session.suggesting {
+                handle_suggest(edit, session, room, state, peer)
+                    .instrument(span)
+                    .await;
+            } else {
+                handle_edit(edit, op_id, session, room, state, peer)
+                    .instrument(span)
+                    .await;
+            }
+        }
+        Some("format") => {
+            let format: FormatEdit = match serde_json::from_value(data["format"].clone()) {
+                Ok(format) => format,
+                Err(e) => {
+                    warn!(error = %e, "Failed to parse format");
+                    send_error(peer, "invalid_format", INVALID_FORMAT);
+                    return;
+                }
+            };
+            let span = info_span!(
+                "format",
+                version = format.version,
+                length = format.length,
+                outcome = field::Empty,
+            );
+            telemetry::set_remote_parent(&span, data["traceparent"].as_str());
+            handle_format(format, session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        Some(kind @ ("undo" | "redo")) => {
+            let span = info_span!("undo", kind, outcome = field::Empty);
+            telemetry::set_remote_parent(&span, data["traceparent"].as_str());
+            handle_undo(kind == "redo", session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        Some("content_at") => send_content_at(&data, room, peer).await,
+        Some("history") => send_history(&data, room, peer).await,
+        Some("diff") => send_diff(&data, room, peer).await,
+        Some("blame") => {
+            let doc = room.document.read().await;
+            let reply = json!({
+                "type": "blame",
+                "version": doc.version,
+                "spans": doc.authorship.spans(),
+            });
+            send_json(peer, &reply);
+        }
+        Some("formatting") => {
+            let doc = room.document.read().await;
+            let reply = json!({
+                "type": "formatting",
+                "version": doc.version,
+                "spans": doc.formatting.spans(),
+            });
+            send_json(peer, &reply);
+        }
+        Some("verify") => handle_verify(&data, room, state, peer).await,
+        Some("request_full_state") => {
+            let doc = room.document.read().await;
+            send_json(peer, &full_state_message(&doc));
+        }
+        Some("resume") => {
+            let span = info_span!("resume", outcome = field::Empty);
+            telemetry::set_remote_parent(&span, data["traceparent"].as_str());
+            handle_resume(&data, session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        Some(kind) if kind.starts_with("comment_") => {
+            let span = info_span!("comment", kind, outcome = field::Empty);
+            handle_comment(kind, &data, session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        Some("suggestion_mode") => {
+            session.suggesting = data["enabled"].as_bool().unwrap_or(true);
+            let reply = json!({
+                "type": "suggestion_mode",
+                "enabled": session.suggesting,
+            });
+            send_json(peer, &reply);
+        }
+        Some(kind) if kind.starts_with("suggestion_") => {
+            let span = info_span!("suggestion", kind, outcome = field::Empty);
+            handle_suggestion(kind, &data, session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        Some(kind) if kind.starts_with("checkpoint_") => {
+            let span = info_span!("checkpoint", kind, outcome = field::Empty);
+            handle_checkpoint(kind, &data, session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        _ => debug!(kind = ?data["type"], "Ignoring message"),
+    }
+}
+
+/// Handles a message on a block document connection: `block_edit` applies
+/// a [`BlockOp`], `import` replaces the document with converted Markdown or
+/// plain text and `export` converts it back.
+async fn handle_block_message(
+    msg: Message,
+    session: &mut Session,
+    room: &BlockRoom,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let data = match peer.codec().decode(&msg) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "Failed to parse message");
+            return;
+        }
+    };
+    if !check_writable(&data, room, peer) {
+        return;
+    }
+
+    match data["type"].as_str() {
+        Some("block_edit") => {
+            let (op, version) = match (
+                serde_json::from_value::<BlockOp>(data["op"].clone()),
+                data["version"].as_u64(),
+            ) {
+                (Ok(op), Some(version)) => (op, version as usize),
+                _ => {
+                    send_error(peer, "invalid_request", "Expected an op and a version");
+                    return;
+                }
+            };
+            let span = info_span!("block_edit", version, outcome = field::Empty);
+            handle_block_edit(op, version, session, room, state, peer)
+                .instrument(span)
+                .await;
+        }
+        Some("import") => {
+            let text = data["text"].as_str().unwrap_or_default();
+            let blocks = match data["format"].as_str() {
+                Some("markdown") => blocks::from_markdown(text),
+                Some("text") => blocks::from_plain_text(text),
+                _ => {
+                    send_error(peer, "invalid_request", "Format must be markdown or text");
+                    return;
+                }
+            };
+            if !check_rate_limits(session, state, peer) {
+                return;
+            }
+            let mut doc = room.document.write().await;
+            if let Err(e) = doc.replace_all(blocks) {
+                reject_edit(e, state, peer);
+                return;
+            }
+            state.metrics.edits_applied_total.inc();
+            let message = json!({
+                "type": "blocks",
+                "version": doc.version,
+                "blocks": doc.blocks,
+            });
+            room.broadcast_all(&message.to_string()).await;
+        }
+        Some("export") => {
+            let doc = room.document.read().await;
+            let text = match data["format"].as_str() {
+                Some("markdown") => doc.to_markdown(),
+                Some("text") => doc.to_plain_text(),
+                _ => {
+                    send_error(peer, "invalid_request", "Format must be markdown or text");
+                    return;
+                }
+            };
+            let reply = json!({
+                "type": "export",
+                "format": data["format"],
+                "version": doc.version,
+                "text": text,
+            });
+            send_json(peer, &reply);
+        }
+        _ => debug!(kind = ?data["type"], "Ignoring message"),
+    }
+}
+
+/// Applies an op to a block document and broadcasts it, with the ids of
+/// any blocks it created, to every peer including the sender.
+async fn handle_block_edit(
+    mut op: BlockOp,
+    version: usize,
+    session: &mut Session,
+    room: &BlockRoom,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    if !check_rate_limits(session, state, peer) {
+        return;
+    }
+    let mut doc = room.document.write().await;
+    match doc.apply(&mut op, version) {
+        Ok(()) => {
+            span.record("outcome", "applied");
+            state.metrics.edits_applied_total.inc();
+            let message = json!({
+                "type": "block_edit",
+                "op": op,
+                "version": doc.version,
+                "author": session.user,
+            });
+            room.broadcast_all(&message.to_string()).await;
+        }
+        Err(e) => match blocks::error_code(e) {
+            Some(code) => {
+                span.record("outcome", code);
+                send_error(peer, code, e);
+            }
+            None => reject_edit(e, state, peer),
+        },
+    }
+}
+
+/// Replies with an error, and returns false, if a message would change a
+/// document this node only follows.
+fn check_writable<D>(data: &serde_json::Value, room: &Room<D>, peer: &Peer) -> bool {
+    let query = data["type"].as_str().is_some_and(replication::is_query);
+    if room.is_replica() && !query {
+        send_error(peer, "read_only", READ_ONLY);
+        return false;
+    }
+    true
+}
+
+/// Takes a token from the connection's rate limit and its address's,
+/// replying with an error if either is exhausted. `?user=` only names the
+/// author, and anyone can pick any name, so it gets no allowance of its own.
+fn check_rate_limits(session: &mut Session, state: &ServerState, peer: &Peer) -> bool {
+    let span = Span::current();
+    let metrics = &state.metrics;
+    if !session.bucket.try_acquire() {
+        span.record("outcome", "connection_rate_limited");
+        metrics.reject("connection_rate_limited");
+        send_error(peer, "rate_limited", "Too many edits on this connection");
+        warn!("Edit rejected");
+        return false;
+    }
+    if !state
+        .user_limits
+        .try_acquire(&session.addr.ip().to_string())
+    {
+        span.record("outcome", "user_rate_limited");
+        metrics.reject("user_rate_limited");
+        send_error(peer, "rate_limited", "Too many edits from this address");
+        warn!("Edit rejected");
+        return false;
+    }
+    true
+}
+
+/// Reads the optional `client_id` and `seq` an edit message is tagged
+/// with, replying with an error and returning `None` if they are invalid.
+fn op_id(data: &serde_json::Value, peer: &Peer) -> Option<Option<OpId>> {
+    if data.get("client_id").is_none() && data.get("seq").is_none() {
+        return Some(None);
+    }
+    match serde_json::from_value::<OpId>(data.clone()) {
+        Ok(id) if id.is_valid() => Some(Some(id)),
+        _ => {
+            send_error(peer, "invalid_op_id", INVALID_OP_ID);
+            None
+        }
+    }
+}
+
+/// Acknowledges a tagged edit. `version` is the version the edit produced,
+/// if known, and `checksum` the checksum of that version if one is due.
+fn send_ack(
+    peer: &Peer,
+    id: &OpId,
+    version: Option<usize>,
+    checksum: Option<u32>,
+    duplicate: bool,
+) {
+    let mut ack = json!({
+        "type": "ack",
+        "client_id": id.client_id,
+        "seq": id.seq,
+        "version": version,
+        "duplicate": duplicate,
+    });
+    if let Some(checksum) = checksum {
+        ack["checksum"] = checksum.into();
+    }
+    send_json(peer, &ack);
+}
+
+async fn handle_edit(
+    edit: Edit,
+    op_id: Option<OpId>,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    let metrics = &state.metrics;
+    if !check_rate_limits(session, state, peer) {
+        return;
+    }
+
+    let started = Instant::now();
+    let mut doc = room.document.write().await;
+    let result = info_span!("apply_edit").in_scope(|| match &op_id {
+        Some(id) => doc.apply_edit_once(&edit, &session.user, id),
+        None => doc.apply_edit_by(&edit, &session.user).map(|()| true),
+    });
+    metrics
+        .apply_duration_seconds
+        .observe(started.elapsed().as_secs_f64());
+    match result {
+        Ok(false) => {
+            // A retry of an edit that was already applied.
+            span.record("outcome", "duplicate");
+            if let Some(id) = &op_id {
+                let last = doc.client_ops.last(&id.client_id);
+                let version = last
+                    .filter(|last| last.seq == id.seq)
+                    .map(|last| last.version);
+                send_ack(peer, id, version, None, true);
+            }
+        }
+        Ok(true) => {
+            span.record("outcome", "applied");
+            metrics.edits_applied_total.inc();
+            session.undo.record(edit.version);
+            let new_version = doc.version;
+            let checksum = doc.checksum_for(new_version);
+            let broadcast_msg = edit_message(&edit, new_version, checksum);
+
+            // Broadcast to all peers except the sender
+            let started = Instant::now();
+            room.broadcast(&session.key(), &broadcast_msg)
+                .instrument(info_span!("broadcast"))
+                .await;
+            metrics
+                .broadcast_duration_seconds
+                .observe(started.elapsed().as_secs_f64());
+            if let Some(id) = &op_id {
+                send_ack(peer, id, Some(new_version), checksum, false);
+            }
+            debug!(new_version, "Edit applied");
+        }
+        Err(e) => reject_edit(e, state, peer),
+    }
+}
+
+/// Applies a formatting change and broadcasts it to every other peer.
+async fn handle_format(
+    format: FormatEdit,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    let metrics = &state.metrics;
+    if !check_rate_limits(session, state, peer) {
+        return;
+    }
+
+    let started = Instant::now();
+    let mut doc = room.document.write().await;
+    let result = info_span!("apply_edit").in_scope(|| doc.apply_format_by(&format, &session.user));
+    metrics
+        .apply_duration_seconds
+        .observe(started.elapsed().as_secs_f64());
+    match result {
+        Ok(()) => {
+            span.record("outcome", "applied");
+            session.undo.record(format.version);
+            metrics.edits_applied_total.inc();
+            let started = Instant::now();
+            room.broadcast(
+                &session.key(),
+                &format_message(&format, doc.version, doc.checksum_for(doc.version)),
+            )
+            .instrument(info_span!("broadcast"))
+            .await;
+            metrics
+                .broadcast_duration_seconds
+                .observe(started.elapsed().as_secs_f64());
+        }
+        Err(e) => {
+            if e == INVALID_FORMAT {
+                send_error(peer, "invalid_format", INVALID_FORMAT);
+            }
+            reject_edit(e, state, peer)
+        }
+    }
+}
+
+/// Undoes or redoes the session's last step. The resulting edits are
+/// broadcast to every peer, the sender included, since its client did not
+/// make them itself.
+async fn handle_undo(
+    redo: bool,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    let metrics = &state.metrics;
+    if !check_rate_limits(session, state, peer) {
+        return;
+    }
+
+    let started = Instant::now();
+    let mut doc = room.document.write().await;
+    let result = info_span!("apply_edit").in_scope(|| {
+        if redo {
+            session.undo.redo(&mut doc, &session.user)
+        } else {
+            session.undo.undo(&mut doc, &session.user)
+        }
+    });
+    metrics
+        .apply_duration_seconds
+        .observe(started.elapsed().as_secs_f64());
+    match result {
+        Ok(changes) => {
+            span.record("outcome", "applied");
+            let started = Instant::now();
+            for change in &changes {
+                metrics.edits_applied_total.inc();
+                let new_version = change.version() + 1;
+                let checksum = doc.checksum_for(new_version);
+                let message = match change {
+                    Change::Edit(edit) => edit_message(edit, new_version, checksum),
+                    Change::Format(format) => format_message(format, new_version, checksum),
+                };
+                room.broadcast_all(&message)
+                    .instrument(info_span!("broadcast"))
+                    .await;
+            }
+            metrics
+                .broadcast_duration_seconds
+                .observe(started.elapsed().as_secs_f64());
+            debug!(new_version = doc.version, "Undo applied");
+        }
+        Err(NOTHING_TO_UNDO) => {
+            span.record("outcome", "nothing_to_undo");
+            send_error(peer, "nothing_to_undo", NOTHING_TO_UNDO);
+        }
+        Err(NOTHING_TO_REDO) => {
+            span.record("outcome", "nothing_to_redo");
+            send_error(peer, "nothing_to_redo", NOTHING_TO_REDO);
+        }
+        Err(e) => reject_edit(e, state, peer),
+    }
+}
+
+/// The whole current state of a document, sent to a client whose copy
+/// cannot be trusted.
+fn full_state_message(doc: &DocumentState) -> serde_json::Value {
+    json!({
+        "type": "full_state",
+        "content": doc.content,
+        "version": doc.version,
+        "formatting": doc.formatting.spans(),
+        "checksum": checksum::checksum(&doc.content),
+    })
+}
+
+/// Answers `{"type": "verify", "version": N, "checksum": C}`, a client's
+/// checksum of its copy at version N, with `verified` if it matches.
+/// Otherwise the client has diverged: that is logged and counted, and the
+/// client is sent the full current state.
+async fn handle_verify(data: &serde_json::Value, room: &Room, state: &ServerState, peer: &Peer) {
+    let (Some(version), Some(reported)) = (data["version"].as_u64(), data["checksum"].as_u64())
+    else {
+        send_error(peer, "invalid_request", "Pass the version and its checksum");
+        return;
+    };
+    let doc = room.document.read().await;
+    let expected = doc.checksum_at(version as usize);
+    if expected.map(u64::from) == Some(reported) {
+        send_json(peer, &json!({ "type": "verified", "version": version }));
+        return;
+    }
+    state.metrics.divergences_total.inc();
+    warn!(
+        version,
+        reported,
+        expected,
+        document_version = doc.version,
+        "Client copy diverged from the document"
+    );
+    send_json(peer, &full_state_message(&doc));
+}
+
+/// Applies `{"type": "resume", "version": N, "edits": [...]}`, the edits a
+/// reconnecting client made on version N that were never acknowledged,
+/// rebased past everything applied since. Like undo, the resulting edits are
+/// broadcast to every peer, the sender included; the sender then gets a
+/// `resumed` reply.
+async fn handle_resume(
+    data: &serde_json::Value,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    let metrics = &state.metrics;
+    let pending = serde_json::from_value::<Vec<Edit>>(data["edits"].clone());
+    let Some(first) = op_id(data, peer) else {
+        return;
+    };
+    let (Some(version), Ok(pending)) = (data["version"].as_u64(), pending) else {
+        send_error(
+            peer,
+            "invalid_request",
+            "Pass the version the edits were made on and the edits",
+        );
+        return;
+    };
+    if !check_rate_limits(session, state, peer) {
+        return;
+    }
+
+    let started = Instant::now();
+    let mut doc = room.document.write().await;
+    let result = info_span!("apply_edit")
+        .in_scope(|| doc.rebase_pending(version as usize, &pending, &session.user, first.as_ref()));
+    metrics
+        .apply_duration_seconds
+        .observe(started.elapsed().as_secs_f64());
+    match result {
+        Ok(edits) => {
+            span.record("outcome", "applied");
+            let started = Instant::now();
+            for edit in &edits {
+                metrics.edits_applied_total.inc();
+                session.undo.record(edit.version);
+                let checksum = doc.checksum_for(edit.version + 1);
+                room.broadcast_all(&edit_message(edit, edit.version + 1, checksum))
+                    .instrument(info_span!("broadcast"))
+                    .await;
+            }
+            metrics
+                .broadcast_duration_seconds
+                .observe(started.elapsed().as_secs_f64());
+            let reply = json!({
+                "type": "resumed",
+                "version": doc.version,
+                "applied": edits.len(),
+            });
+            send_json(peer, &reply);
+            debug!(new_version = doc.version, "Pending edits applied");
+        }
+        Err(HISTORY_UNAVAILABLE) => {
+            span.record("outcome", "history_unavailable");
+            send_error(peer, "history_unavailable", HISTORY_UNAVAILABLE);
+        }
+        Err(INVALID_PENDING) => {
+            span.record("outcome", "invalid_pending");
+            send_error(peer, "invalid_pending", INVALID_PENDING);
+        }
+        Err(e) => reject_edit(e, state, peer),
+    }
+}
+
+fn reject_edit(error: &'static str, state: &ServerState, peer: &Peer) {
+    let reason = rejection_reason(error);
+    Span::current().record("outcome", reason);
+    state.metrics.reject(reason);
+    if error == DOCUMENT_TOO_LARGE {
+        send_error(peer, "document_too_large", DOCUMENT_TOO_LARGE);
+    }
+    // Optionally, send other errors back to the client as well
+    warn!(error, "Edit rejected");
+}
+
+/// Answers `{"type": "content_at", "version": N}` or `{"type":
+/// "content_at", "at": T}` with the document as it was at that point.
+async fn send_content_at(data: &serde_json::Value, room: &Room, peer: &Peer) {
+    let point = match (data["version"].as_u64(), data["at"].as_u64()) {
+        (Some(version), None) => HistoryPoint::Version(version as usize),
+        (None, Some(at)) => HistoryPoint::Time(at),
+        _ => {
+            send_error(peer, "invalid_request", "Pass either version or at");
+            return;
+        }
+    };
+
+    let result = room.document.read().await.history.checkout(point);
+    match result {
+        Ok((version, content)) => {
+            let reply = json!({
+                "type": "content_at",
+                "version": version,
+                "content": content,
+            });
+            send_json(peer, &reply);
+        }
+        Err(e) => send_error(peer, "history_unavailable", e),
+    }
+}
+
+/// Answers `{"type": "history", "since": N, "limit": L}` with the
+/// operations applied from version `since` on.
+async fn send_history(data: &serde_json::Value, room: &Room, peer: &Peer) {
+    let limit = data["limit"]
+        .as_u64()
+        .map_or(DEFAULT_HISTORY_PAGE, |limit| limit as usize)
+        .min(MAX_HISTORY_PAGE);
+
+    let doc = room.document.read().await;
+    let since = data["since"]
+        .as_u64()
+        .map_or(doc.history.first_version(), |since| since as usize);
+    match doc.history.ops_since(since) {
+        Some(ops) => {
+            let reply = json!({
+                "type": "history",
+                "first_version": doc.history.first_version(),
+                "latest_version": doc.history.latest_version(),
+                "ops": &ops[..ops.len().min(limit)],
+            });
+            send_json(peer, &reply);
+        }
+        None => send_error(peer, "history_unavailable", HISTORY_UNAVAILABLE),
+    }
+}
+
+/// Answers `{"type": "diff", "from": N, "to": M}` with the diff between two
+/// versions. `from_checkpoint` and `to_checkpoint` name checkpoints instead,
+/// and a missing `to` means the latest version.
+async fn send_diff(data: &serde_json::Value, room: &Room, peer: &Peer) {
+    let end = |name: &str| {
+        let checkpoint = format!("{}_checkpoint", name);
+        match (data[name].as_u64(), data[checkpoint.as_str()].as_str()) {
+            (Some(version), None) => Some(Some(DiffEnd::Version(version as usize))),
+            (None, Some(checkpoint)) => Some(Some(DiffEnd::Checkpoint(checkpoint.to_string()))),
+            (None, None) => Some(None),
+            (Some(_), Some(_)) => None,
+        }
+    };
+    let (Some(Some(from)), Some(to)) = (end("from"), end("to")) else {
+        send_error(
+            peer,
+            "invalid_request",
+            "Pass one of from or from_checkpoint",
+        );
+        return;
+    };
+
+    let doc = room.document.read().await;
+    let to = to.unwrap_or(DiffEnd::Version(doc.version));
+    match info_span!("diff").in_scope(|| diff::diff_document(&doc, &from, &to)) {
+        Ok(diff) => send_json(peer, &json!({ "type": "diff", "diff": diff })),
+        Err(e) => send_error(
+            peer,
+            checkpoint::error_code(e).unwrap_or("history_unavailable"),
+            e,
+        ),
+    }
+}
+
+/// Handles the `checkpoint_create`, `checkpoint_list`, `checkpoint_delete`
+/// and `checkpoint_restore` messages.
+async fn handle_checkpoint(
+    kind: &str,
+    data: &serde_json::Value,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    if kind == "checkpoint_list" {
+        let checkpoints: Vec<_> = room
+            .document
+            .read()
+            .await
+            .checkpoints
+            .list()
+            .iter()
+            .map(Checkpoint::summary)
+            .collect();
+        send_json(
+            peer,
+            &json!({ "type": "checkpoints", "checkpoints": checkpoints }),
+        );
+        return;
+    }
+
+    let Some(name) = data["name"].as_str() else {
+        send_error(peer, "invalid_request", "Missing checkpoint name");
+        return;
+    };
+    let result = match kind {
+        "checkpoint_create" => {
+            // Checkpoints copy the whole document, so they cost an edit.
+            if !check_rate_limits(session, state, peer) {
+                return;
+            }
+            room.create_checkpoint(name, Some(&session.user))
+                .await
+                .map(|_| ())
+        }
+        "checkpoint_delete" => room.delete_checkpoint(name).await,
+        "checkpoint_restore" => {
+            if !check_rate_limits(session, state, peer) {
+                return;
+            }
+            room.restore_checkpoint(name, Some(&session.user))
+                .await
+                .map(|edit| {
+                    if let Some(edit) = edit {
+                        state.metrics.edits_applied_total.inc();
+                        session.undo.record(edit.version);
+                    }
+                })
+        }
+        _ => {
+            debug!(kind, "Ignoring message");
+            return;
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            span.record("outcome", "applied");
+        }
+        Err(e) => match checkpoint::error_code(e) {
+            Some(code) => {
+                span.record("outcome", code);
+                send_error(peer, code, e);
+            }
+            None => reject_edit(e, state, peer),
+        },
+    }
+}
+
+/// Handles the `comment_create`, `comment_reply`, `comment_resolve`,
+/// `comment_delete` and `comment_list` messages. Changes are broadcast to
+/// every peer in the room.
+async fn handle_comment(
+    kind: &str,
+    data: &serde_json::Value,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    if kind == "comment_list" {
+        let threads = json!({
+            "type": "comments",
+            "threads": room.document.read().await.comments.threads(),
+        });
+        send_json(peer, &threads);
+        return;
+    }
+    if matches!(kind, "comment_create" | "comment_reply")
+        && !check_rate_limits(session, state, peer)
+    {
+        return;
+    }
+
+    let author = Some(session.user.as_str());
+    let text = data["text"].as_str().unwrap_or_default();
+    let thread = data["thread"].as_u64();
+    let message = {
+        let mut doc = room.document.write().await;
+        let DocumentState {
+            content, comments, ..
+        } = &mut *doc;
+        let result = match (kind, thread) {
+            ("comment_create", _) => match (data["start"].as_u64(), data["end"].as_u64()) {
+                (Some(start), Some(end)) => {
+                    comments.create(content, start as usize, end as usize, author, text)
+                }
+                _ => Err(comments::INVALID_ANCHOR),
+            }
+            .map(Some),
+            ("comment_reply", Some(thread)) => comments.reply(thread, author, text).map(Some),
+            ("comment_resolve", Some(thread)) => {
+                let resolved = data["resolved"].as_bool().unwrap_or(true);
+                comments.set_resolved(thread, resolved).map(Some)
+            }
+            ("comment_delete", Some(thread)) => {
+                comments.delete(thread, data["comment"].as_u64(), author)
+            }
+            ("comment_reply" | "comment_resolve" | "comment_delete", None) => {
+                Err(comments::UNKNOWN_THREAD)
+            }
+            _ => {
+                debug!(kind, "Ignoring message");
+                return;
+            }
+        };
+        match result {
+            Ok(Some(thread)) => json!({ "type": "comment_thread", "thread": thread }),
+            Ok(None) => json!({
+                "type": "comment_deleted",
+                "thread": thread,
+            }),
+            Err(e) => {
+                span.record("outcome", comments::error_code(e));
+                send_error(peer, comments::error_code(e), e);
+                return;
+            }
+        }
+    };
+
+    span.record("outcome", "applied");
+    room.broadcast_all(&message.to_string()).await;
+}
+
+/// Records an edit from a connection in suggestion mode as a pending
+/// suggestion, which is broadcast to every peer without changing the
+/// document.
+async fn handle_suggest(
+    edit: Edit,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    if !check_rate_limits(session, state, peer) {
+        return;
+    }
+
+    let message = {
+        let mut doc = room.document.write().await;
+        match doc.suggest(&edit, &session.user) {
+            Ok(suggestion) => json!({ "type": "suggestion", "suggestion": suggestion }),
+            Err(e) => {
+                match suggestions::error_code(e) {
+                    Some(code) => {
+                        span.record("outcome", code);
+                        send_error(peer, code, e);
+                    }
+                    None => reject_edit(e, state, peer),
+                }
+                return;
+            }
+        }
+    };
+    span.record("outcome", "suggested");
+    room.broadcast_all(&message.to_string()).await;
+}
+
+/// Handles the `suggestion_list`, `suggestion_accept` and
+/// `suggestion_reject` messages. Only connections that are not in
+/// suggestion mode may accept; a suggestion's author may also reject it.
+async fn handle_suggestion(
+    kind: &str,
+    data: &serde_json::Value,
+    session: &mut Session,
+    room: &Room,
+    state: &ServerState,
+    peer: &Peer,
+) {
+    let span = Span::current();
+    if kind == "suggestion_list" {
+        let suggestions = json!({
+            "type": "suggestions",
+            "suggestions": room.document.read().await.suggestions.list(),
+        });
+        send_json(peer, &suggestions);
+        return;
+    }
+    let Some(id) = data["id"].as_u64() else {
+        send_error(peer, "invalid_request", "Missing suggestion id");
+        return;
+    };
+
+    let result = match kind {
+        "suggestion_accept" => {
+            if session.suggesting {
+                Err(suggestions::NOT_EDITOR)
+            } else if !check_rate_limits(session, state, peer) {
+                return;
+            } else {
+                let mut doc = room.document.write().await;
+                doc.accept_suggestion(id).map(|edit| {
+                    state.metrics.edits_applied_total.inc();
+                    session.undo.record(edit.version);
+                    let accepted = json!({ "type": "suggestion_accepted", "id": id });
+                    let checksum = doc.checksum_for(doc.version);
+                    vec![
+                        accepted.to_string(),
+                        edit_message(&edit, doc.version, checksum),
+                    ]
+                })
+            }
+        }
+        "suggestion_reject" => {
+            let mut doc = room.document.write().await;
+            match doc.suggestions.get(id) {
+                None => Err(suggestions::UNKNOWN_SUGGESTION),
+                Some(suggestion)
+                    if session.suggesting
+                        && suggestion.author.as_deref() != Some(session.user.as_str()) =>
+                {
+                    Err(suggestions::NOT_SUGGESTER)
+                }
+                Some(_) => doc.suggestions.remove(id).map(|_| {
+                    let rejected = json!({ "type": "suggestion_rejected", "id": id });
+                    vec![rejected.to_string()]
+                }),
+            }
+        }
+        _ => {
+            debug!(kind, "Ignoring message");
+            return;
+        }
+    };
+
+    match result {
+        Ok(messages) => {
+            span.record("outcome", "applied");
+            for message in messages {
+                room.broadcast_all(&message).await;
+            }
+        }
+        Err(e) => match suggestions::error_code(e) {
+            Some(code) => {
+                span.record("outcome", code);
+                send_error(peer, code, e);
+            }
+            None => reject_edit(e, state, peer),
+        },
+    }
+}
+
+fn send_json(peer: &Peer, message: &serde_json::Value) {
+    if let Err(e) = peer.send_json(message) {
+        error!(error = %e, "Failed to send reply");
+    }
+}
+
+/// Replies to a single client with an error it can surface to the user.
+fn send_error(peer: &Peer, code: &str, message: &str) {
+    let error = json!({
+        "type": "error",
+        "code": code,
+        "message": message,
+    });
+    if let Err(e) = peer.send_json(&error) {
+        error!(error = %e, "Failed to send error reply");
+    }
+}
+
+fn user_from_request(request: &HttpRequest) -> Option<String> {
+    request.query_param("user")
+}
+
+/// Which kind of document a WebSocket connection edits.
+enum Target {
+    Document(String),
+    Blocks(String),
+}
+
+/// Connections to `ws://host/blocks/{id}` edit a block document; every
+/// other path names a flat document as in [`room_id_from_request`].
+fn target_from_request(request: &HttpRequest) -> Option<Target> {
+    match request.path.trim_matches('/').strip_prefix("blocks/") {
+        Some(id) => room::is_valid_room_id(id).then(|| Target::Blocks(id.to_string())),
+        None => room_id_from_request(request).map(Target::Document),
+    }
 }
 
-impl Default for DocumentState {
-    fn default() -> Self {
-        Self::new()
+/// Takes the document id from the request path, so `ws://host/notes`
+/// edits the `notes` document. The bare path edits the default document.
+fn room_id_from_request(request: &HttpRequest) -> Option<String> {
+    let id = request.path.trim_matches('/');
+    if id.is_empty() {
+        Some(DEFAULT_ROOM.to_string())
+    } else if room::is_valid_room_id(id) {
+        Some(id.to_string())
+    } else {
+        None
     }
 }
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A classic token bucket: holds up to `capacity` tokens and refills at
+/// `refill_per_sec`. Each edit costs one token.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity,
+            refill_per_sec,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes one token if available, refilling first based on the time
+    /// elapsed since the previous call.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket will have refilled completely by `now`, making
+    /// it no different from a new one.
+    pub fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens + elapsed * self.refill_per_sec >= self.capacity
+    }
+}
+
+/// Users tracked before buckets that have refilled are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Token buckets shared by every connection from the same IP address.
+///
+/// Buckets that have refilled are dropped whenever the number tracked
+/// doubles, keeping only the addresses that edited recently.
+pub struct UserRateLimits {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<UserBuckets>,
+}
+
+struct UserBuckets {
+    buckets: HashMap<String, TokenBucket>,
+    /// Number of buckets at which refilled ones are next dropped.
+    prune_at: usize,
+}
+
+impl UserRateLimits {
+    pub fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        UserRateLimits {
+            capacity,
+            refill_per_sec,
+            buckets: Mutex::new(UserBuckets {
+                buckets: HashMap::new(),
+                prune_at: PRUNE_THRESHOLD,
+            }),
+        }
+    }
+
+    pub fn try_acquire(&self, user: &str) -> bool {
+        self.try_acquire_at(user, Instant::now())
+    }
+
+    pub fn try_acquire_at(&self, user: &str, now: Instant) -> bool {
+        let mut guard = self.buckets.lock().unwrap();
+        let UserBuckets { buckets, prune_at } = &mut *guard;
+        if buckets.len() >= *prune_at {
+            buckets.retain(|_, bucket| !bucket.is_full_at(now));
+            *prune_at = (2 * buckets.len()).max(PRUNE_THRESHOLD);
+        }
+        match buckets.get_mut(user) {
+            Some(bucket) => bucket.try_acquire_at(now),
+            None => {
+                let mut bucket = TokenBucket::new(self.capacity, self.refill_per_sec);
+                bucket.last_refill = now;
+                let acquired = bucket.try_acquire_at(now);
+                buckets.insert(user.to_string(), bucket);
+                acquired
+            }
+        }
+    }
+
+    /// The number of users whose edits are being tracked.
+    pub fn tracked_users(&self) -> usize {
+        self.buckets.lock().unwrap().buckets.len()
+    }
+}
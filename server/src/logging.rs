@@ -0,0 +1,38 @@
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::telemetry::{self, Telemetry};
+
+/// Installs the global `tracing` subscriber, along with the OpenTelemetry
+/// exporter when the `otel` feature is enabled.
+///
+/// The filter uses `EnvFilter` directives, so `info` or
+/// `info,collaborative_editor_server=debug` both work. The returned guard
+/// flushes pending traces when dropped.
+pub fn init(config: &LoggingConfig) -> Telemetry {
+    let filter = EnvFilter::try_new(&config.filter).unwrap_or_else(|e| {
+        eprintln!(
+            "Invalid log filter {:?}, using \"info\": {}",
+            config.filter, e
+        );
+        EnvFilter::new("info")
+    });
+    let fmt_layer = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .boxed(),
+    };
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![filter.boxed(), fmt_layer];
+    let (telemetry, otel_layer) = telemetry::init(config);
+    layers.extend(otel_layer);
+
+    if let Err(e) = tracing_subscriber::registry().with(layers).try_init() {
+        eprintln!("Failed to install the log subscriber: {}", e);
+    }
+    telemetry
+}
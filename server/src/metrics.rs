@@ -0,0 +1,205 @@
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use std::sync::Arc;
+
+use crate::room::Room;
+
+/// Prometheus metrics for one server instance.
+///
+/// Counters and histograms are updated as events happen; the per-room gauges
+/// are sampled from the rooms whenever the metrics are scraped.
+pub struct Metrics {
+    registry: Registry,
+    pub connections_total: IntCounter,
+    pub active_connections: IntGauge,
+    pub edits_applied_total: IntCounter,
+    pub edits_rejected_total: IntCounterVec,
+    pub oversized_messages_total: IntCounter,
+    pub divergences_total: IntCounter,
+    pub documents_unloaded_total: IntCounter,
+    pub apply_duration_seconds: Histogram,
+    pub broadcast_duration_seconds: Histogram,
+    outgoing_queue_depth: IntGaugeVec,
+    document_size_bytes: IntGaugeVec,
+    document_version: IntGaugeVec,
+    room_peers: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("editor".to_string()), None)
+            .expect("Metric namespace should be valid");
+
+        let connections_total = IntCounter::new(
+            "connections_total",
+            "WebSocket connections accepted since start",
+        )
+        .unwrap();
+        let active_connections =
+            IntGauge::new("active_connections", "Currently open WebSocket connections").unwrap();
+        let edits_applied_total =
+            IntCounter::new("edits_applied_total", "Edits applied to a document").unwrap();
+        let edits_rejected_total = IntCounterVec::new(
+            Opts::new("edits_rejected_total", "Edits rejected, by reason"),
+            &["reason"],
+        )
+        .unwrap();
+        let oversized_messages_total = IntCounter::new(
+            "oversized_messages_total",
+            "Connections closed for sending a message over the size limit",
+        )
+        .unwrap();
+        let divergences_total = IntCounter::new(
+            "divergences_total",
+            "Clients whose copy of a document failed verification",
+        )
+        .unwrap();
+        let documents_unloaded_total = IntCounter::new(
+            "documents_unloaded_total",
+            "Documents saved and dropped from memory while unused",
+        )
+        .unwrap();
+        let apply_duration_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "apply_duration_seconds",
+                "Time spent applying an edit, including waiting for the document lock",
+            )
+            .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 10).unwrap()),
+        )
+        .unwrap();
+        let broadcast_duration_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "broadcast_duration_seconds",
+                "Time spent fanning an applied edit out to the other peers",
+            )
+            .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 10).unwrap()),
+        )
+        .unwrap();
+        let outgoing_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "outgoing_queue_depth",
+                "Messages queued for peers but not yet written, per room",
+            ),
+            &["room"],
+        )
+        .unwrap();
+        let document_size_bytes = IntGaugeVec::new(
+            Opts::new("document_size_bytes", "Size of each document's content"),
+            &["room"],
+        )
+        .unwrap();
+        let document_version = IntGaugeVec::new(
+            Opts::new("document_version", "Current version of each document"),
+            &["room"],
+        )
+        .unwrap();
+        let room_peers = IntGaugeVec::new(
+            Opts::new("room_peers", "Peers connected to each room"),
+            &["room"],
+        )
+        .unwrap();
+
+        let collectors: [Box<dyn Collector>; 13] = [
+            Box::new(connections_total.clone()),
+            Box::new(active_connections.clone()),
+            Box::new(edits_applied_total.clone()),
+            Box::new(edits_rejected_total.clone()),
+            Box::new(oversized_messages_total.clone()),
+            Box::new(divergences_total.clone()),
+            Box::new(documents_unloaded_total.clone()),
+            Box::new(apply_duration_seconds.clone()),
+            Box::new(broadcast_duration_seconds.clone()),
+            Box::new(outgoing_queue_depth.clone()),
+            Box::new(document_size_bytes.clone()),
+            Box::new(document_version.clone()),
+            Box::new(room_peers.clone()),
+        ];
+        for collector in collectors {
+            registry
+                .register(collector)
+                .expect("Metric names should be unique");
+        }
+
+        Metrics {
+            registry,
+            connections_total,
+            active_connections,
+            edits_applied_total,
+            edits_rejected_total,
+            oversized_messages_total,
+            divergences_total,
+            documents_unloaded_total,
+            apply_duration_seconds,
+            broadcast_duration_seconds,
+            outgoing_queue_depth,
+            document_size_bytes,
+            document_version,
+            room_peers,
+        }
+    }
+
+    /// Counts a rejected edit under a label derived from the rejection error.
+    pub fn reject(&self, reason: &str) {
+        self.edits_rejected_total.with_label_values(&[reason]).inc();
+    }
+
+    /// Refreshes the per-room gauges from the current rooms.
+    pub async fn sample_rooms(&self, rooms: &[Arc<Room>]) {
+        self.outgoing_queue_depth.reset();
+        self.document_size_bytes.reset();
+        self.document_version.reset();
+        self.room_peers.reset();
+
+        for room in rooms {
+            let labels = [room.id.as_str()];
+            {
+                let doc = room.document.read().await;
+                self.document_size_bytes
+                    .with_label_values(&labels)
+                    .set(doc.content.len() as i64);
+                self.document_version
+                    .with_label_values(&labels)
+                    .set(doc.version as i64);
+            }
+            let peers = room.peers.read().await;
+            let queued: usize = peers.values().map(|peer| peer.queue_depth()).sum();
+            self.outgoing_queue_depth
+                .with_label_values(&labels)
+                .set(queued as i64);
+            self.room_peers
+                .with_label_values(&labels)
+                .set(peers.len() as i64);
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!(error = %e, "Failed to encode metrics");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps an `apply_edit` error onto the `reason` label used for rejected edits.
+pub fn rejection_reason(error: &str) -> &'static str {
+    match error {
+        "Version mismatch" => "version_mismatch",
+        crate::DOCUMENT_TOO_LARGE => "document_too_large",
+        "Insert position is not a valid UTF-8 boundary." => "invalid_position",
+        "Delete range is not valid UTF-8 boundaries." => "invalid_range",
+        crate::formatting::INVALID_FORMAT => "invalid_format",
+        _ => "other",
+    }
+}
@@ -0,0 +1,466 @@
+//! Operational transformation of applied edits.
+//!
+//! Edits are stored as [`Op`]s once applied, keeping the text a delete or
+//! replace removed so the operation can be inverted later. Positions are byte offsets
+//! into the document, like [`Edit::position`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::formatting::{AttributeChanges, FormatSpan};
+use crate::Edit;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Op {
+    Insert {
+        position: usize,
+        text: String,
+    },
+    Delete {
+        position: usize,
+        text: String,
+    },
+    /// Deletes `deleted` at `position`, then inserts `inserted` there.
+    Replace {
+        position: usize,
+        deleted: String,
+        inserted: String,
+    },
+    /// Changes the formatting of `length` bytes at `position`, leaving the
+    /// text as it is.
+    Format {
+        position: usize,
+        length: usize,
+        attributes: AttributeChanges,
+    },
+}
+
+impl Op {
+    pub fn position(&self) -> usize {
+        match self {
+            Op::Insert { position, .. }
+            | Op::Delete { position, .. }
+            | Op::Replace { position, .. }
+            | Op::Format { position, .. } => *position,
+        }
+    }
+
+    /// How many bytes of text the operation carries.
+    pub fn text_len(&self) -> usize {
+        match self {
+            Op::Insert { text, .. } | Op::Delete { text, .. } => text.len(),
+            Op::Replace {
+                deleted, inserted, ..
+            } => deleted.len() + inserted.len(),
+            Op::Format { .. } => 0,
+        }
+    }
+
+    /// Whether applying the operation leaves the content unchanged.
+    pub fn is_noop(&self) -> bool {
+        match self {
+            Op::Insert { text, .. } | Op::Delete { text, .. } => text.is_empty(),
+            Op::Replace {
+                deleted, inserted, ..
+            } => deleted == inserted,
+            Op::Format {
+                length, attributes, ..
+            } => *length == 0 || attributes.is_empty(),
+        }
+    }
+
+    /// Whether the operation changes the text, rather than only its
+    /// formatting.
+    pub fn changes_text(&self) -> bool {
+        !matches!(self, Op::Format { .. })
+    }
+
+    /// The operations that revert this one. A format is reverted by
+    /// setting the attributes it changed back to their values in
+    /// `replaced`, the formatting its range had before; other operations
+    /// ignore it.
+    pub fn invert(&self, replaced: &[FormatSpan]) -> Vec<Op> {
+        match self {
+            Op::Insert { position, text } => vec![Op::Delete {
+                position: *position,
+                text: text.clone(),
+            }],
+            Op::Delete { position, text } => vec![Op::Insert {
+                position: *position,
+                text: text.clone(),
+            }],
+            Op::Replace {
+                position,
+                deleted,
+                inserted,
+            } => vec![Op::Replace {
+                position: *position,
+                deleted: inserted.clone(),
+                inserted: deleted.clone(),
+            }],
+            Op::Format { attributes, .. } => replaced
+                .iter()
+                .map(|span| Op::Format {
+                    position: span.start,
+                    length: span.end - span.start,
+                    attributes: attributes.restoring(&span.attributes),
+                })
+                .collect(),
+        }
+    }
+
+    /// Splits a replace into the delete and insert it is made of.
+    fn split_replace(&self) -> Option<[Op; 2]> {
+        match self {
+            Op::Replace {
+                position,
+                deleted,
+                inserted,
+            } => Some([
+                Op::Delete {
+                    position: *position,
+                    text: deleted.clone(),
+                },
+                Op::Insert {
+                    position: *position,
+                    text: inserted.clone(),
+                },
+            ]),
+            _ => None,
+        }
+    }
+
+    /// Performs the operation on `content`, failing if it was not made
+    /// against that content.
+    pub fn apply(&self, content: &mut String) -> Result<(), &'static str> {
+        match self {
+            Op::Insert { position, text } => {
+                if !content.is_char_boundary(*position) {
+                    return Err("Insert position is not a valid UTF-8 boundary.");
+                }
+                content.insert_str(*position, text);
+            }
+            Op::Delete { position, text } => {
+                let end = position + text.len();
+                if content.get(*position..end) != Some(text.as_str()) {
+                    return Err("Deleted text does not match the content.");
+                }
+                content.replace_range(*position..end, "");
+            }
+            Op::Replace { .. } => {
+                for op in self.split_replace().into_iter().flatten() {
+                    op.apply(content)?;
+                }
+            }
+            Op::Format { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// The operation `edit` performs on `content`, ignoring its version, or
+    /// `None` if its range is not valid there.
+    pub fn from_edit(edit: &Edit, content: &str) -> Option<Op> {
+        let position = edit.position;
+        let end = position.checked_add(edit.delete.unwrap_or(0))?;
+        let deleted = content.get(position..end)?.to_string();
+        Some(match (&edit.insert, edit.delete) {
+            (Some(inserted), Some(_)) => Op::Replace {
+                position,
+                deleted,
+                inserted: inserted.clone(),
+            },
+            (None, Some(_)) => Op::Delete {
+                position,
+                text: deleted,
+            },
+            (insert, None) => Op::Insert {
+                position,
+                text: insert.clone().unwrap_or_default(),
+            },
+        })
+    }
+
+    /// The edit that performs this operation on the document at `version`.
+    /// Formats change no text, so their edit is empty.
+    pub fn to_edit(&self, version: usize) -> Edit {
+        match self {
+            Op::Insert { position, text } => Edit {
+                position: *position,
+                insert: Some(text.clone()),
+                delete: None,
+                version,
+            },
+            Op::Delete { position, text } => Edit {
+                position: *position,
+                insert: None,
+                delete: Some(text.len()),
+                version,
+            },
+            Op::Replace {
+                position,
+                deleted,
+                inserted,
+            } => Edit {
+                position: *position,
+                insert: Some(inserted.clone()),
+                delete: Some(deleted.len()),
+                version,
+            },
+            Op::Format { position, .. } => Edit {
+                position: *position,
+                insert: None,
+                delete: None,
+                version,
+            },
+        }
+    }
+}
+
+/// Transforms `op` so it can be applied after `against`, where both were
+/// made against the same document.
+///
+/// A delete that `against` inserted into the middle of is split so the
+/// inserted text survives; the pieces are returned highest position first,
+/// so applying them in order does not shift the ones still to come. A
+/// replace is transformed as the delete and insert it is made of.
+pub fn transform(op: &Op, against: &Op) -> Vec<Op> {
+    transform_with(op, against, false)
+}
+
+/// Like [`transform`], except that where both insert at the same place the
+/// text of `op` stays first if `op_first` is set.
+fn transform_with(op: &Op, against: &Op, op_first: bool) -> Vec<Op> {
+    if let Some(parts) = against.split_replace() {
+        return transform_all_with(op, &parts, op_first);
+    }
+    if let Some([delete, insert]) = op.split_replace() {
+        // The insert lands after both the delete and `against`, so it is
+        // transformed past `against` as seen after the delete.
+        let mut ops = transform_with(&delete, against, op_first);
+        ops.extend(transform_all_with(
+            &insert,
+            &transform_with(against, &delete, !op_first),
+            op_first,
+        ));
+        return ops;
+    }
+
+    let transformed = match (op, against) {
+        // Formatting never moves text, and formats of the same range are
+        // applied in order, the later one winning.
+        (_, Op::Format { .. }) => vec![op.clone()],
+        (
+            Op::Format {
+                position,
+                length,
+                attributes,
+            },
+            _,
+        ) => {
+            // The formatted range moves like a comment anchor: it grows with
+            // text inserted inside it and shrinks with text deleted from it.
+            let (mut start, mut end) = (*position, position + length);
+            match against {
+                Op::Insert {
+                    position: other,
+                    text,
+                } => {
+                    if *other <= start {
+                        start += text.len();
+                        end += text.len();
+                    } else if *other < end {
+                        end += text.len();
+                    }
+                }
+                Op::Delete {
+                    position: other,
+                    text,
+                } => {
+                    let map = |offset: usize| {
+                        if offset <= *other {
+                            offset
+                        } else {
+                            offset.saturating_sub(text.len()).max(*other)
+                        }
+                    };
+                    start = map(start);
+                    end = map(end);
+                }
+                _ => unreachable!("replaces and formats are handled above"),
+            }
+            vec![Op::Format {
+                position: start,
+                length: end - start,
+                attributes: attributes.clone(),
+            }]
+        }
+        (
+            Op::Insert { position, text },
+            Op::Insert {
+                position: other,
+                text: inserted,
+            },
+        ) => {
+            // On a tie the text that is already in the document stays first.
+            let position = if *other < *position || (*other == *position && !op_first) {
+                position + inserted.len()
+            } else {
+                *position
+            };
+            vec![Op::Insert {
+                position,
+                text: text.clone(),
+            }]
+        }
+        (
+            Op::Insert { position, text },
+            Op::Delete {
+                position: other,
+                text: deleted,
+            },
+        ) => {
+            let deleted_end = other + deleted.len();
+            let position = if *position <= *other {
+                *position
+            } else if *position >= deleted_end {
+                position - deleted.len()
+            } else {
+                *other
+            };
+            vec![Op::Insert {
+                position,
+                text: text.clone(),
+            }]
+        }
+        (
+            Op::Delete { position, text },
+            Op::Insert {
+                position: other,
+                text: inserted,
+            },
+        ) => {
+            let end = position + text.len();
+            if *other <= *position {
+                vec![Op::Delete {
+                    position: position + inserted.len(),
+                    text: text.clone(),
+                }]
+            } else if *other >= end {
+                vec![op.clone()]
+            } else {
+                let split = other - position;
+                vec![
+                    Op::Delete {
+                        position: other + inserted.len(),
+                        text: text[split..].to_string(),
+                    },
+                    Op::Delete {
+                        position: *position,
+                        text: text[..split].to_string(),
+                    },
+                ]
+            }
+        }
+        (
+            Op::Delete { position, text },
+            Op::Delete {
+                position: other,
+                text: deleted,
+            },
+        ) => {
+            let end = position + text.len();
+            let deleted_end = other + deleted.len();
+            if end <= *other {
+                vec![op.clone()]
+            } else if *position >= deleted_end {
+                vec![Op::Delete {
+                    position: position - deleted.len(),
+                    text: text.clone(),
+                }]
+            } else {
+                // Only the parts `against` did not already delete remain.
+                let mut remaining = String::new();
+                if *position < *other {
+                    remaining.push_str(&text[..other - position]);
+                }
+                if end > deleted_end {
+                    remaining.push_str(&text[deleted_end - position..]);
+                }
+                vec![Op::Delete {
+                    position: (*position).min(*other),
+                    text: remaining,
+                }]
+            }
+        }
+        (Op::Replace { .. }, _) | (_, Op::Replace { .. }) => unreachable!("replaces are split"),
+    };
+
+    transformed.into_iter().filter(|op| !op.is_noop()).collect()
+}
+
+/// Transforms `op` past every operation in `later`, in order.
+pub fn transform_all<'a>(op: &Op, later: impl IntoIterator<Item = &'a Op>) -> Vec<Op> {
+    transform_all_with(op, later, false)
+}
+
+fn transform_all_with<'a>(
+    op: &Op,
+    later: impl IntoIterator<Item = &'a Op>,
+    op_first: bool,
+) -> Vec<Op> {
+    let mut ops = vec![op.clone()];
+    for against in later {
+        ops = ops
+            .iter()
+            .flat_map(|op| transform_with(op, against, op_first))
+            .collect();
+    }
+    ops
+}
+
+/// Transforms `ops`, operations made one after another on some version of
+/// a document, so they apply after `concurrent`, the operations applied to
+/// that version since. Where both insert at the same place, the text
+/// already in the document stays first.
+pub fn rebase(ops: &[Op], concurrent: &[Op]) -> Vec<Op> {
+    let (rebased, _) = cross(split_replaces(ops), split_replaces(concurrent));
+    rebased.into_iter().filter(|op| !op.is_noop()).collect()
+}
+
+fn split_replaces(ops: &[Op]) -> Vec<Op> {
+    ops.iter()
+        .flat_map(|op| match op.split_replace() {
+            Some(parts) => parts.to_vec(),
+            None => vec![op.clone()],
+        })
+        .collect()
+}
+
+/// Transforms two sequences of operations made on the same document past
+/// each other, returning `ops` as applied after `against` and `against` as
+/// applied after `ops`. Each operation of one sequence has to be transformed
+/// past the other's as they were after the operations before it, so the
+/// sequences are split until single operations meet.
+fn cross(mut ops: Vec<Op>, mut against: Vec<Op>) -> (Vec<Op>, Vec<Op>) {
+    match (ops.len(), against.len()) {
+        (0, _) | (_, 0) => (ops, against),
+        (1, 1) => (
+            transform_with(&ops[0], &against[0], false),
+            transform_with(&against[0], &ops[0], true),
+        ),
+        (1, _) => {
+            let rest = against.split_off(1);
+            let (ops, mut against) = cross(ops, against);
+            let (ops, rest) = cross(ops, rest);
+            against.extend(rest);
+            (ops, against)
+        }
+        _ => {
+            let rest = ops.split_off(1);
+            let (mut ops, against) = cross(ops, against);
+            let (rest, against) = cross(rest, against);
+            ops.extend(rest);
+            (ops, against)
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! Hot standby followers.
+//!
+//! A primary accepts followers on its replication address. The first line
+//! a follower sends is the API token, and the primary drops followers that
+//! don't know it. The next names the version of each document the follower
+//! has saved. The primary answers with the operations each of those is
+//! missing, taken from the operation history saved with the document, and
+//! a whole copy of every other document or one whose history no longer
+//! reaches back far enough. Then it sends everything it broadcasts, which
+//! the follower applies to its replicas as described in
+//! [`crate::backplane`]. A follower with a data directory saves its
+//! replicas like any document, so it resumes from them when it reconnects
+//! or restarts.
+//!
+//! Followers serve read-only connections and can be promoted to take over
+//! from a primary that died.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tracing::{debug, error, info, warn};
+
+use crate::backplane::{
+    authenticate, block_document, encode_line, read_first_message, receive_from_node, Backplane,
+    NodeMessage, RECONNECT_DELAY,
+};
+use crate::checksum::checksum;
+use crate::storage::{Snapshot, Storage};
+use crate::ServerState;
+
+/// Error returned for changes sent to a follower.
+pub const READ_ONLY: &str = "This server is a read-only follower.";
+
+/// The node id of a primary, and of a follower, among the two.
+pub const PRIMARY: &str = "primary";
+pub const FOLLOWER: &str = "follower";
+
+/// Messages queued for a follower, beyond which it has fallen too far
+/// behind and is dropped. It catches up again when it reconnects.
+const FOLLOWER_QUEUE: usize = 1024;
+
+/// Whether a message only reads a document, so a follower can answer it.
+/// Followers refuse every other message with [`READ_ONLY`].
+pub fn is_query(kind: &str) -> bool {
+    matches!(
+        kind,
+        "content_at"
+            | "history"
+            | "diff"
+            | "blame"
+            | "formatting"
+            | "verify"
+            | "request_full_state"
+            | "checkpoint_list"
+            | "comment_list"
+            | "suggestion_list"
+            | "export"
+    )
+}
+
+/// The followers connected to a primary, which receive everything the
+/// primary publishes.
+#[derive(Default)]
+pub struct Followers {
+    next_id: AtomicU64,
+    queues: Mutex<HashMap<String, Sender<String>>>,
+}
+
+impl Followers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a follower, returning its id and the messages queued for it.
+    fn add(&self) -> (String, Receiver<String>) {
+        let id = format!(
+            "follower-{}",
+            self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+        );
+        let (tx, rx) = mpsc::channel(FOLLOWER_QUEUE);
+        self.queues.lock().unwrap().insert(id.clone(), tx);
+        (id, rx)
+    }
+
+    fn queue(&self, id: &str) -> Option<Sender<String>> {
+        self.queues.lock().unwrap().get(id).cloned()
+    }
+
+    fn remove(&self, id: &str) {
+        self.queues.lock().unwrap().remove(id);
+    }
+}
+
+impl Backplane for Followers {
+    fn publish(&self, message: &NodeMessage) {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.is_empty() {
+            return;
+        }
+        let line = encode_line(message);
+        queues.retain(|id, tx| queue_line(id, tx, line.clone()));
+    }
+
+    fn send(&self, node: &str, message: &NodeMessage) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(tx) = queues.get(node) {
+            if !queue_line(node, tx, encode_line(message)) {
+                queues.remove(node);
+            }
+        }
+    }
+}
+
+/// Queues a line for a follower. Returns false if the follower should be
+/// dropped, because it went away or fell too far behind.
+fn queue_line(id: &str, tx: &Sender<String>, line: String) -> bool {
+    match tx.try_send(line) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!(follower = %id, "Dropping a follower that fell behind");
+            false
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// Accepts followers on `listener`. Their requests for documents are passed
+/// to `incoming`, to be answered by [`crate::run_backplane`].
+pub async fn serve_followers(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    followers: Arc<Followers>,
+    incoming: UnboundedSender<NodeMessage>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(run_follower(
+                    stream,
+                    addr,
+                    state.clone(),
+                    followers.clone(),
+                    incoming.clone(),
+                ));
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to accept follower");
+                return;
+            }
+        }
+    }
+}
+
+async fn run_follower(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    followers: Arc<Followers>,
+    incoming: UnboundedSender<NodeMessage>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    if !authenticate(&mut reader, state.config.api_token.as_deref()).await {
+        warn!(%addr, "Refused a follower without the API token");
+        return;
+    }
+    let saved = match read_first_message(&mut reader).await {
+        Some(NodeMessage::Resume { versions }) => versions,
+        _ => HashMap::new(),
+    };
+    let (id, mut queue) = followers.add();
+    info!(follower = %id, %addr, resumed = saved.len(), "Follower connected");
+
+    // The follower was added first, so every change made after a
+    // document is copied reaches it, and none made before.
+    let copy = send_documents(&state, &followers, &id, &saved);
+    let (requests_tx, mut requests) = mpsc::unbounded_channel();
+    let read = async {
+        receive_from_node(reader, requests_tx).await;
+    };
+    // Followers only ask for documents, and are named by the primary.
+    let forward_requests = async {
+        while let Some(request) = requests.recv().await {
+            if let NodeMessage::SyncRequest { document, .. } = request {
+                let request = NodeMessage::SyncRequest {
+                    document,
+                    from: id.clone(),
+                };
+                let _ = incoming.send(request);
+            }
+        }
+    };
+    let write = async {
+        while let Some(line) = queue.recv().await {
+            if let Err(e) = writer.write_all(line.as_bytes()).await {
+                debug!(follower = %id, error = %e, "Failed to send to follower");
+                return;
+            }
+        }
+    };
+    tokio::select! {
+        _ = async { tokio::join!(copy, read, forward_requests) } => (),
+        _ = write => (),
+    }
+    followers.remove(&id);
+    info!(follower = %id, "Follower disconnected");
+}
+
+/// Queues a copy of every document for a follower, or the operations it is
+/// missing from the ones it has `saved`. Each is queued while the document
+/// is locked, so it lands between the broadcasts it includes and the ones
+/// it doesn't. Documents only in storage are copied from there, without
+/// loading them, while nothing else can load them. Block documents keep no
+/// history, so they are always copied whole.
+async fn send_documents(
+    state: &ServerState,
+    followers: &Followers,
+    id: &str,
+    saved: &HashMap<String, usize>,
+) {
+    let Some(queue) = followers.queue(id) else {
+        return;
+    };
+    let documents = state.document_ids().await.unwrap_or_else(|e| {
+        error!(error = %e, "Failed to list documents");
+        Vec::new()
+    });
+    for document in documents {
+        // Wait for room in the queue before locking anything.
+        let Ok(permit) = queue.reserve().await else {
+            return;
+        };
+        let _loading = state.loading.lock().await;
+        let room = state.rooms.read().await.get(&document).cloned();
+        let since = saved.get(&document).copied();
+        let snapshot = match room {
+            Some(room) => {
+                let doc = room.document.read().await;
+                let copy = copy_for(document, Snapshot::of(&doc), since);
+                permit.send(encode_line(&copy));
+                continue;
+            }
+            None => match state.storage.as_ref().map(|s| s.load_snapshot(&document)) {
+                Some(Ok(Some(snapshot))) => snapshot,
+                Some(Err(e)) => {
+                    error!(%document, error = %e, "Failed to read document");
+                    continue;
+                }
+                _ => continue,
+            },
+        };
+        permit.send(encode_line(&copy_for(document, snapshot, since)));
+    }
+    let block_documents = state.block_document_ids().await.unwrap_or_else(|e| {
+        error!(error = %e, "Failed to list block documents");
+        Vec::new()
+    });
+    for id in block_documents {
+        let Ok(permit) = queue.reserve().await else {
+            return;
+        };
+        let _loading = state.loading.lock().await;
+        let room = state.block_rooms.read().await.get(&id).cloned();
+        let blocks = match room {
+            Some(room) => room.document.read().await.clone(),
+            None => match state.storage.as_ref().map(|s| s.load_blocks(&id)) {
+                Some(Ok(Some(blocks))) => blocks,
+                Some(Err(e)) => {
+                    error!(document = %id, error = %e, "Failed to read block document");
+                    continue;
+                }
+                _ => continue,
+            },
+        };
+        let copy = NodeMessage::BlockSnapshot {
+            document: block_document(&id),
+            blocks,
+        };
+        permit.send(encode_line(&copy));
+    }
+}
+
+/// What a follower that saved `document` at version `since` is sent: the
+/// operations it is missing while history reaches back that far, or else
+/// the whole document.
+fn copy_for(document: String, snapshot: Snapshot, since: Option<usize>) -> NodeMessage {
+    let missing = since.and_then(|since| snapshot.history.as_ref()?.ops_since(since));
+    match missing.map(<[_]>::to_vec) {
+        Some(ops) => NodeMessage::OpLog {
+            document,
+            ops,
+            version: snapshot.version,
+            checksum: checksum(&snapshot.content),
+            checkpoints: snapshot.checkpoints,
+            comments: snapshot.comments,
+            suggestions: snapshot.suggestions,
+            client_ops: snapshot.client_ops,
+        },
+        None => NodeMessage::Snapshot { document, snapshot },
+    }
+}
+
+/// The version of each document saved in `storage`, which a follower's
+/// replicas resume from.
+fn saved_versions(storage: Option<&Storage>) -> HashMap<String, usize> {
+    let Some(storage) = storage else {
+        return HashMap::new();
+    };
+    let documents = storage.list_documents().unwrap_or_else(|e| {
+        error!(error = %e, "Failed to list documents");
+        Vec::new()
+    });
+    documents
+        .into_iter()
+        .filter_map(|document| match storage.load_snapshot(&document) {
+            Ok(snapshot) => Some((document, snapshot?.version)),
+            Err(e) => {
+                error!(%document, error = %e, "Failed to read document");
+                None
+            }
+        })
+        .collect()
+}
+
+/// A follower's connection to its primary.
+pub struct PrimaryLink {
+    queue: UnboundedSender<String>,
+    closed: watch::Sender<bool>,
+}
+
+impl PrimaryLink {
+    /// Follows the primary at `addr`, authenticating with the API `token`
+    /// and reconnecting whenever the connection drops, and returns the link
+    /// along with the messages the primary sends. Replicas saved in
+    /// `storage` resume from the versions saved.
+    pub fn connect(
+        addr: &str,
+        token: &str,
+        storage: Option<Storage>,
+    ) -> (PrimaryLink, UnboundedReceiver<NodeMessage>) {
+        let (queue, outgoing) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (closed, closed_rx) = watch::channel(false);
+        tokio::spawn(follow_primary(
+            addr.to_string(),
+            token.to_string(),
+            storage,
+            outgoing,
+            incoming_tx,
+            closed_rx,
+        ));
+        (PrimaryLink { queue, closed }, incoming)
+    }
+}
+
+impl Backplane for PrimaryLink {
+    fn publish(&self, message: &NodeMessage) {
+        let _ = self.queue.send(encode_line(message));
+    }
+
+    fn send(&self, _node: &str, message: &NodeMessage) {
+        self.publish(message);
+    }
+
+    fn close(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+async fn follow_primary(
+    addr: String,
+    token: String,
+    storage: Option<Storage>,
+    mut outgoing: UnboundedReceiver<String>,
+    incoming: UnboundedSender<NodeMessage>,
+    mut closed: watch::Receiver<bool>,
+) {
+    let following = async {
+        loop {
+            let mut stream = match TcpStream::connect(&addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!(%addr, error = %e, "Failed to connect to primary");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            let resume = NodeMessage::Resume {
+                versions: saved_versions(storage.as_ref()),
+            };
+            let hello = format!("{}\n{}", token, encode_line(&resume));
+            if let Err(e) = stream.write_all(hello.as_bytes()).await {
+                debug!(%addr, error = %e, "Failed to authenticate with primary");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+            info!(%addr, "Following primary");
+            let (reader, mut writer) = stream.into_split();
+            let write = async {
+                while let Some(line) = outgoing.recv().await {
+                    if writer.write_all(line.as_bytes()).await.is_err() {
+                        return false;
+                    }
+                }
+                true
+            };
+            tokio::select! {
+                _ = receive_from_node(reader, incoming.clone()) => (),
+                link_dropped = write => if link_dropped {
+                    return;
+                },
+            }
+            // The primary sends what the saved replicas are missing again
+            // on reconnection, and every other document.
+            warn!(%addr, "Lost connection to primary");
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    };
+    tokio::select! {
+        _ = following => (),
+        _ = closed.wait_for(|closed| *closed) => info!(%addr, "Stopped following primary"),
+    }
+}
@@ -0,0 +1,439 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{watch, RwLock};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::backplane::{Cluster, NodeMessage, NOT_OWNER};
+use crate::blocks::BlockDocument;
+use crate::codec::{compress, Codec, Encodings};
+use crate::formatting::FormatEdit;
+use crate::history::AppliedOp;
+use crate::ot::Op;
+use crate::replication::READ_ONLY;
+use crate::{DocumentState, Edit, Tx};
+
+/// Room used by connections that do not name a document.
+pub const DEFAULT_ROOM: &str = "default";
+
+/// The sending half of a connection, along with the number of messages
+/// queued for it that have not been written to the socket yet.
+#[derive(Clone)]
+pub struct Peer {
+    tx: Tx,
+    queued: Arc<AtomicUsize>,
+    /// How messages to this peer are encoded.
+    codec: Codec,
+    /// Size from which messages to this peer are compressed, if it asked
+    /// for compression.
+    compress_over: Option<usize>,
+}
+
+impl Peer {
+    pub fn new(tx: Tx) -> Self {
+        Peer::with_codec(tx, Codec::Json)
+    }
+
+    pub fn with_codec(tx: Tx, codec: Codec) -> Self {
+        Peer {
+            tx,
+            queued: Arc::new(AtomicUsize::new(0)),
+            codec,
+            compress_over: None,
+        }
+    }
+
+    /// Compresses messages of at least `threshold` bytes sent to this peer.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_over = Some(threshold);
+        self
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn compress_over(&self) -> Option<usize> {
+        self.compress_over
+    }
+
+    /// Encodes a protocol message the way this peer asked for.
+    pub fn encode(&self, value: &serde_json::Value) -> Message {
+        let message = self.codec.encode(value);
+        match self.compress_over {
+            Some(threshold) => compress(message, threshold),
+            None => message,
+        }
+    }
+
+    /// Sends a protocol message in the peer's encoding.
+    pub fn send_json(&self, value: &serde_json::Value) -> Result<(), SendError<Message>> {
+        self.send(self.encode(value))
+    }
+
+    pub fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        self.tx.send(msg)?;
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Called by the connection's writer for every message it takes off the queue.
+    pub fn mark_sent(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+pub type PeerMap = Arc<RwLock<HashMap<String, Peer>>>;
+
+/// A document together with the peers currently editing it.
+pub struct Room<D = DocumentState> {
+    pub id: String,
+    pub document: RwLock<D>,
+    pub peers: PeerMap,
+    /// The nodes the document is shared with, if any.
+    shared: Option<Shared>,
+    /// When the room was last looked up or left by a peer.
+    last_used: Mutex<Instant>,
+    /// How many [`RoomHandle`]s to the room exist.
+    handles: AtomicUsize,
+}
+
+struct Shared {
+    cluster: Cluster,
+    /// The document's name among the nodes.
+    name: String,
+    /// False while a replica waits for the owner's copy.
+    synced: watch::Sender<bool>,
+}
+
+/// A room holding a block-structured document.
+pub type BlockRoom = Room<BlockDocument>;
+
+/// A room held by a connection or request. Rooms are only unloaded while no
+/// handle to them exists, so handles must be taken while the map the room
+/// is in is locked.
+pub struct RoomHandle<D = DocumentState>(Arc<Room<D>>);
+
+impl<D> RoomHandle<D> {
+    pub fn new(room: &Arc<Room<D>>) -> Self {
+        room.handles.fetch_add(1, Ordering::AcqRel);
+        RoomHandle(room.clone())
+    }
+}
+
+impl<D> Deref for RoomHandle<D> {
+    type Target = Room<D>;
+
+    fn deref(&self) -> &Room<D> {
+        &self.0
+    }
+}
+
+impl<D> Drop for RoomHandle<D> {
+    fn drop(&mut self) {
+        self.0.handles.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<D> Room<D> {
+    pub fn new(id: &str, document: D) -> Self {
+        Room {
+            id: id.to_string(),
+            document: RwLock::new(document),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            shared: None,
+            last_used: Mutex::new(Instant::now()),
+            handles: AtomicUsize::new(0),
+        }
+    }
+
+    /// Marks the room as in use now.
+    pub fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    /// How long ago the room was last in use.
+    pub fn idle_for(&self) -> Duration {
+        self.last_used.lock().unwrap().elapsed()
+    }
+
+    /// Whether a connection or request holds a handle to the room.
+    pub fn in_use(&self) -> bool {
+        self.handles.load(Ordering::Acquire) > 0
+    }
+
+    /// A room for a document shared with other nodes as `name`. If another
+    /// node owns it, the room holds a replica, which counts as out of date
+    /// until [`Room::set_synced`] is called.
+    pub fn shared(id: &str, name: &str, document: D, cluster: Cluster) -> Self {
+        Room {
+            shared: Some(Shared {
+                synced: watch::Sender::new(cluster.owns(name)),
+                cluster,
+                name: name.to_string(),
+            }),
+            ..Room::new(id, document)
+        }
+    }
+
+    /// Whether another node owns the document, so this room only holds a
+    /// replica of it.
+    pub fn is_replica(&self) -> bool {
+        self.shared
+            .as_ref()
+            .is_some_and(|shared| !shared.cluster.owns(&shared.name))
+    }
+
+    /// Whether the room's document can be used: always, unless it is a
+    /// replica waiting for the owner's copy.
+    pub fn is_synced(&self) -> bool {
+        !self.is_replica()
+            || self
+                .shared
+                .as_ref()
+                .is_some_and(|shared| *shared.synced.borrow())
+    }
+
+    pub fn set_synced(&self, value: bool) {
+        if let Some(shared) = &self.shared {
+            shared.synced.send_replace(value);
+        }
+    }
+
+    /// Waits up to `timeout` for a replica to be in sync, returning whether
+    /// it is.
+    pub async fn wait_until_synced(&self, timeout: Duration) -> bool {
+        let Some(shared) = self.shared.as_ref().filter(|_| self.is_replica()) else {
+            return true;
+        };
+        let mut synced = shared.synced.subscribe();
+        let synced = tokio::time::timeout(timeout, synced.wait_for(|synced| *synced)).await;
+        matches!(synced, Ok(Ok(_)))
+    }
+
+    /// Fails with [`NOT_OWNER`] if another node owns the document, or
+    /// [`READ_ONLY`] if this node follows the primary that does.
+    fn check_owner(&self) -> Result<(), &'static str> {
+        match &self.shared {
+            _ if !self.is_replica() => Ok(()),
+            Some(shared) if shared.cluster.is_follower() => Err(READ_ONLY),
+            _ => Err(NOT_OWNER),
+        }
+    }
+
+    /// Sends a message to every peer in the room except `sender`.
+    pub async fn broadcast(&self, sender: &str, msg: &str) {
+        self.deliver(Some(sender), msg).await;
+        self.relay(Some(sender), msg);
+    }
+
+    /// Sends a message to every peer in the room.
+    pub async fn broadcast_all(&self, msg: &str) {
+        self.deliver(None, msg).await;
+        self.relay(None, msg);
+    }
+
+    /// Sends a message to the peers connected to this node, except `except`.
+    pub async fn deliver(&self, except: Option<&str>, msg: &str) {
+        let peers = self.peers.read().await;
+        let mut encodings = Encodings::new(msg);
+        for (peer_addr, peer) in peers.iter() {
+            if Some(peer_addr.as_str()) != except {
+                if let Err(e) = peer.send(encodings.message_for(peer.codec(), peer.compress_over()))
+                {
+                    tracing::warn!(peer = %peer_addr, error = %e, "Failed to send message");
+                }
+            }
+        }
+    }
+
+    /// Passes a broadcast on to the other nodes if this one owns the room.
+    fn relay(&self, except: Option<&str>, msg: &str) {
+        let Some(Shared { cluster, name, .. }) = &self.shared else {
+            return;
+        };
+        if cluster.owns(name) {
+            cluster.publish(&NodeMessage::Broadcast {
+                document: name.clone(),
+                except: except.map(|sender| relayed_sender(cluster, sender)),
+                message: msg.to_string(),
+            });
+        }
+    }
+}
+
+/// Names a sender across nodes. Connections forwarded from another node are
+/// already named `node/address`, ones to this node just by their address.
+fn relayed_sender(cluster: &Cluster, sender: &str) -> String {
+    if sender.contains('/') {
+        sender.to_string()
+    } else {
+        format!("{}/{}", cluster.node_id(), sender)
+    }
+}
+
+impl Room {
+    /// Names the current content of the document and tells every peer.
+    pub async fn create_checkpoint(
+        &self,
+        name: &str,
+        author: Option<&str>,
+    ) -> Result<serde_json::Value, &'static str> {
+        self.check_owner()?;
+        let summary = {
+            let mut doc = self.document.write().await;
+            let DocumentState {
+                content,
+                version,
+                checkpoints,
+                ..
+            } = &mut *doc;
+            checkpoints
+                .create(name, *version, content, author)?
+                .summary()
+        };
+        let msg = json!({ "type": "checkpoint_created", "checkpoint": summary });
+        self.broadcast_all(&msg.to_string()).await;
+        Ok(summary)
+    }
+
+    /// Deletes a checkpoint and tells every peer.
+    pub async fn delete_checkpoint(&self, name: &str) -> Result<(), &'static str> {
+        self.check_owner()?;
+        self.document.write().await.checkpoints.delete(name)?;
+        let msg = json!({ "type": "checkpoint_deleted", "name": name });
+        self.broadcast_all(&msg.to_string()).await;
+        Ok(())
+    }
+
+    /// Applies edits made one after another on `version`, see
+    /// [`DocumentState::apply_edits`], broadcasting each to every peer.
+    /// Returns the edits applied and the new version.
+    pub async fn apply_edits(
+        &self,
+        version: usize,
+        edits: &[Edit],
+        author: Option<&str>,
+    ) -> Result<(Vec<Edit>, usize), &'static str> {
+        self.check_owner()?;
+        let mut doc = self.document.write().await;
+        let applied = doc.apply_edits(version, edits, author)?;
+        for edit in &applied {
+            let checksum = doc.checksum_for(edit.version + 1);
+            self.broadcast_all(&edit_message(edit, edit.version + 1, checksum))
+                .await;
+        }
+        Ok((applied, doc.version))
+    }
+
+    /// Rolls the document back to a checkpoint with a single edit, which is
+    /// broadcast to every peer like any other. Returns the edit, or `None` if
+    /// the content already matched.
+    pub async fn restore_checkpoint(
+        &self,
+        name: &str,
+        author: Option<&str>,
+    ) -> Result<Option<Edit>, &'static str> {
+        self.check_owner()?;
+        let mut doc = self.document.write().await;
+        let Some(edit) = doc.checkpoint_restore_edit(name)? else {
+            return Ok(None);
+        };
+        match author {
+            Some(author) => doc.apply_edit_by(&edit, author)?,
+            None => doc.apply_edit(&edit)?,
+        }
+        let checksum = doc.checksum_for(doc.version);
+        self.broadcast_all(&edit_message(&edit, doc.version, checksum))
+            .await;
+        Ok(Some(edit))
+    }
+}
+
+/// The message telling peers about an applied edit, with the checksum of
+/// the content it produced if one is due.
+pub fn edit_message(edit: &Edit, new_version: usize, checksum: Option<u32>) -> String {
+    with_checksum(
+        json!({
+            "type": "edit",
+            "edit": {
+                "position": edit.position,
+                "insert": edit.insert,
+                "delete": edit.delete,
+                "version": new_version,
+            }
+        }),
+        checksum,
+    )
+}
+
+/// The message telling peers about a formatting change. It is sent as an
+/// edit that inserts and deletes nothing, so plain-text clients still move
+/// on to the new version.
+pub fn format_message(format: &FormatEdit, new_version: usize, checksum: Option<u32>) -> String {
+    with_checksum(
+        json!({
+            "type": "edit",
+            "edit": {
+                "position": format.position,
+                "insert": null,
+                "delete": null,
+                "version": new_version,
+                "format": {
+                    "length": format.length,
+                    "attributes": format.attributes,
+                },
+            }
+        }),
+        checksum,
+    )
+}
+
+fn with_checksum(mut message: serde_json::Value, checksum: Option<u32>) -> String {
+    if let Some(checksum) = checksum {
+        message["checksum"] = checksum.into();
+    }
+    message.to_string()
+}
+
+/// The `edit` part of the message that announced an operation from the
+/// history, as [`edit_message`] or [`format_message`] built it.
+pub fn applied_edit(applied: &AppliedOp) -> serde_json::Value {
+    let new_version = applied.version + 1;
+    match &applied.op {
+        Op::Format {
+            position,
+            length,
+            attributes,
+        } => json!({
+            "position": position,
+            "insert": null,
+            "delete": null,
+            "version": new_version,
+            "format": {
+                "length": length,
+                "attributes": attributes,
+            },
+        }),
+        op => serde_json::to_value(op.to_edit(new_version)).expect("edits serialize"),
+    }
+}
+
+/// Checks that a document id taken from a URL path is safe to use as a room
+/// name and as a file name in the data directory.
+pub fn is_valid_room_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
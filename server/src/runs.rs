@@ -0,0 +1,143 @@
+//! Runs of bytes sharing a value, laid over a document's content.
+//!
+//! Authorship, formatting and history attribution all annotate the content
+//! this way: a list of runs whose lengths add up to the content's, updated
+//! as text is inserted and deleted.
+
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// A value covering a run of bytes.
+pub trait RunValue: Clone {
+    /// The value of the part of a run that starts `at` bytes into it. Most
+    /// values are the same throughout their run.
+    fn split_off(&self, _at: usize) -> Self {
+        self.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run<T> {
+    pub len: usize,
+    /// Saved as `attributes` by formatting and `origin` by authorship
+    /// before the two shared this type.
+    #[serde(alias = "attributes", alias = "origin")]
+    pub value: T,
+}
+
+/// Runs covering the content from its start, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Runs<T> {
+    runs: Vec<Run<T>>,
+}
+
+impl<T> Default for Runs<T> {
+    fn default() -> Self {
+        Runs { runs: Vec::new() }
+    }
+}
+
+impl<T: RunValue> Runs<T> {
+    /// A single run of `len` bytes, or none if `len` is 0.
+    pub fn filled(len: usize, value: T) -> Self {
+        let mut runs = Runs::default();
+        runs.push(len, value);
+        runs
+    }
+
+    /// Appends a run at the end of the content.
+    pub fn push(&mut self, len: usize, value: T) {
+        if len > 0 {
+            self.runs.push(Run { len, value });
+        }
+    }
+
+    /// Length in bytes of the content the runs cover.
+    pub fn content_len(&self) -> usize {
+        self.runs.iter().map(|run| run.len).sum()
+    }
+
+    /// Each run with the range of the content it covers.
+    pub fn iter(&self) -> impl Iterator<Item = (Range<usize>, &T)> {
+        self.runs.iter().scan(0, |offset, run| {
+            let start = *offset;
+            *offset += run.len;
+            Some((start..*offset, &run.value))
+        })
+    }
+
+    /// The value of the byte at `position`, if there is one.
+    pub fn value_at(&self, position: usize) -> Option<&T> {
+        self.iter()
+            .find(|(range, _)| range.contains(&position))
+            .map(|(_, value)| value)
+    }
+
+    /// Splits the runs so one starts at `position`, returning its index.
+    fn split_at(&mut self, position: usize) -> usize {
+        let mut offset = 0;
+        for index in 0..self.runs.len() {
+            if offset == position {
+                return index;
+            }
+            let len = self.runs[index].len;
+            if offset + len > position {
+                let at = position - offset;
+                let right = Run {
+                    len: len - at,
+                    value: self.runs[index].value.split_off(at),
+                };
+                self.runs[index].len = at;
+                self.runs.insert(index + 1, right);
+                return index + 1;
+            }
+            offset += len;
+        }
+        self.runs.len()
+    }
+
+    /// Adds a run of `len` bytes at `position`, moving the ones after it.
+    pub fn insert(&mut self, position: usize, len: usize, value: T) {
+        if len == 0 {
+            return;
+        }
+        let index = self.split_at(position);
+        self.runs.insert(index, Run { len, value });
+    }
+
+    /// Removes `len` bytes at `position`, returning the runs they were in,
+    /// cut to the removed range.
+    pub fn delete(&mut self, position: usize, len: usize) -> Vec<Run<T>> {
+        if len == 0 {
+            return Vec::new();
+        }
+        let start = self.split_at(position);
+        let end = self.split_at(position + len);
+        self.runs.drain(start..end).collect()
+    }
+
+    /// Calls `update` on the value of every run within `len` bytes at
+    /// `position`, splitting the runs at either end first.
+    pub fn update(&mut self, position: usize, len: usize, mut update: impl FnMut(&mut T)) {
+        let start = self.split_at(position);
+        let end = self.split_at(position + len);
+        for run in &mut self.runs[start..end] {
+            update(&mut run.value);
+        }
+    }
+}
+
+impl<T: RunValue + PartialEq> Runs<T> {
+    /// Joins neighbouring runs with the same value.
+    pub fn merge(&mut self) {
+        let mut runs: Vec<Run<T>> = Vec::with_capacity(self.runs.len());
+        for run in self.runs.drain(..) {
+            match runs.last_mut() {
+                Some(last) if last.value == run.value => last.len += run.len,
+                _ => runs.push(run),
+            }
+        }
+        self.runs = runs;
+    }
+}
@@ -0,0 +1,229 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::authorship::Authorship;
+use crate::blocks::BlockDocument;
+use crate::checkpoint::Checkpoints;
+use crate::client_ops::ClientOps;
+use crate::comments::Comments;
+use crate::formatting::Formatting;
+use crate::history::History;
+use crate::room::is_valid_room_id;
+use crate::suggestions::Suggestions;
+use crate::DocumentState;
+
+const SNAPSHOT_EXTENSION: &str = "json";
+/// Subdirectory of the data directory block documents are saved in.
+const BLOCKS_DIR: &str = "blocks";
+
+/// The on-disk form of a document, also sent whole to other nodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub content: String,
+    pub version: usize,
+    /// The document's operation history. Missing from snapshots written
+    /// before history was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<History>,
+    #[serde(default)]
+    pub checkpoints: Checkpoints,
+    #[serde(default)]
+    pub comments: Comments,
+    #[serde(default)]
+    pub suggestions: Suggestions,
+    #[serde(default)]
+    pub client_ops: ClientOps,
+    /// Formatting and authorship of the content, which can't be rebuilt
+    /// from history once the operations that made them are dropped.
+    /// Missing from snapshots written before history was trimmed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formatting: Option<Formatting>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorship: Option<Authorship>,
+}
+
+impl Snapshot {
+    /// Copies everything about a document that is saved.
+    pub fn of(doc: &DocumentState) -> Self {
+        Snapshot {
+            content: doc.content.clone(),
+            version: doc.version,
+            history: Some(doc.history.clone()),
+            checkpoints: doc.checkpoints.clone(),
+            comments: doc.comments.clone(),
+            suggestions: doc.suggestions.clone(),
+            client_ops: doc.client_ops.clone(),
+            formatting: Some(doc.formatting.clone()),
+            authorship: Some(doc.authorship.clone()),
+        }
+    }
+
+    /// Replaces a document with the snapshot. If its history cannot be
+    /// used, the content is restored without it and the error returned.
+    pub fn restore_into(self, doc: &mut DocumentState) -> Result<(), &'static str> {
+        let mut result = Ok(());
+        if let Some(history) = self.history {
+            result = doc.restore_history(history);
+        }
+        if result.is_err() || doc.version != self.version || doc.content != self.content {
+            doc.restore(self.content, self.version);
+        }
+        doc.checkpoints = self.checkpoints;
+        doc.comments = self.comments;
+        doc.suggestions = self.suggestions;
+        doc.client_ops = self.client_ops;
+        let content_len = doc.content.len();
+        if let Some(formatting) = self.formatting.filter(|f| f.content_len() == content_len) {
+            doc.formatting = formatting;
+        }
+        if let Some(authorship) = self.authorship.filter(|a| a.content_len() == content_len) {
+            doc.authorship = authorship;
+        }
+        result
+    }
+}
+
+/// What [`Storage::save_snapshot`] writes, borrowed from the document to
+/// avoid copying its history.
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    content: &'a str,
+    version: usize,
+    history: &'a History,
+    checkpoints: &'a Checkpoints,
+    comments: &'a Comments,
+    suggestions: &'a Suggestions,
+    client_ops: &'a ClientOps,
+    formatting: &'a Formatting,
+    authorship: &'a Authorship,
+}
+
+/// Persists document snapshots as JSON files in a data directory, one file
+/// per document.
+#[derive(Clone)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Storage { dir: dir.into() }
+    }
+
+    /// Checks that the data directory exists, or can be created, and is
+    /// writable.
+    pub fn check(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let probe = self.dir.join(".probe");
+        fs::write(&probe, b"")?;
+        fs::remove_file(probe)
+    }
+
+    /// Lists the ids of every document with a saved snapshot.
+    pub fn list_documents(&self) -> io::Result<Vec<String>> {
+        list_snapshots(&self.dir)
+    }
+
+    /// Lists the ids of every saved block document.
+    pub fn list_block_documents(&self) -> io::Result<Vec<String>> {
+        list_snapshots(&self.dir.join(BLOCKS_DIR))
+    }
+
+    /// Loads the last saved snapshot of a document, if one has been written.
+    pub fn load_snapshot(&self, doc_id: &str) -> io::Result<Option<Snapshot>> {
+        read_json(&snapshot_path(&self.dir, doc_id)?)
+    }
+
+    /// Loads a saved block document, if one has been written.
+    pub fn load_blocks(&self, doc_id: &str) -> io::Result<Option<BlockDocument>> {
+        read_json(&snapshot_path(&self.dir.join(BLOCKS_DIR), doc_id)?)
+    }
+
+    /// Writes the document to disk, replacing the previous snapshot
+    /// atomically so a crash never leaves a half-written file behind.
+    pub fn save_snapshot(&self, doc_id: &str, doc: &DocumentState) -> io::Result<()> {
+        let snapshot = SnapshotRef {
+            content: &doc.content,
+            version: doc.version,
+            history: &doc.history,
+            checkpoints: &doc.checkpoints,
+            comments: &doc.comments,
+            suggestions: &doc.suggestions,
+            client_ops: &doc.client_ops,
+            formatting: &doc.formatting,
+            authorship: &doc.authorship,
+        };
+        write_json(&self.dir, doc_id, &snapshot)
+    }
+
+    /// Deletes a document's snapshot, returning whether there was one.
+    pub fn delete_snapshot(&self, doc_id: &str) -> io::Result<bool> {
+        match fs::remove_file(snapshot_path(&self.dir, doc_id)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes a block document to disk, atomically like
+    /// [`Storage::save_snapshot`].
+    pub fn save_blocks(&self, doc_id: &str, doc: &BlockDocument) -> io::Result<()> {
+        write_json(&self.dir.join(BLOCKS_DIR), doc_id, doc)
+    }
+}
+
+/// The ids of the snapshots saved in `dir`.
+fn list_snapshots(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut ids = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(SNAPSHOT_EXTENSION) {
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                ids.push(id.to_string());
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// The file a document is saved in. Ids that could name a file outside
+/// `dir` are refused.
+fn snapshot_path(dir: &Path, doc_id: &str) -> io::Result<PathBuf> {
+    if !is_valid_room_id(doc_id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid document id",
+        ));
+    }
+    Ok(dir.join(format!("{}.{}", doc_id, SNAPSHOT_EXTENSION)))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_json(dir: &Path, doc_id: &str, value: &impl Serialize) -> io::Result<()> {
+    let path = snapshot_path(dir, doc_id)?;
+    fs::create_dir_all(dir)?;
+    let bytes = serde_json::to_vec(value)?;
+    let tmp_path = dir.join(format!("{}.tmp", doc_id));
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(tmp_path, path)
+}
@@ -0,0 +1,126 @@
+//! Suggested edits layered over a document until they are accepted or
+//! rejected.
+//!
+//! A suggestion proposes replacing a byte range of the content, which may be
+//! empty for a pure insertion, with some text. The range is anchored like a
+//! comment thread's; a suggestion whose range is deleted entirely by other
+//! edits is marked orphaned and can only be rejected.
+
+use serde::{Deserialize, Serialize};
+
+use crate::anchor::Anchor;
+use crate::history::now_ms;
+use crate::ot::Op;
+use crate::Edit;
+
+pub const UNKNOWN_SUGGESTION: &str = "No suggestion with that id exists.";
+pub const INVALID_SUGGESTION: &str = "Suggestions must change a valid range of the document.";
+pub const SUGGESTION_ORPHANED: &str = "The text this suggestion changes has been deleted.";
+pub const NOT_EDITOR: &str = "Only editors can accept suggestions.";
+pub const NOT_SUGGESTER: &str = "Only editors or the suggestion's author can reject it.";
+
+/// The code clients receive for a suggestion error, or `None` for errors
+/// raised while applying an accepted suggestion.
+pub fn error_code(error: &str) -> Option<&'static str> {
+    match error {
+        UNKNOWN_SUGGESTION => Some("unknown_suggestion"),
+        INVALID_SUGGESTION => Some("invalid_suggestion"),
+        SUGGESTION_ORPHANED => Some("suggestion_orphaned"),
+        NOT_EDITOR => Some("not_editor"),
+        NOT_SUGGESTER => Some("not_suggester"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub id: u64,
+    pub author: Option<String>,
+    /// The text proposed for deletion. Empty for a suggestion that only
+    /// inserts.
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    /// Text proposed for insertion at the anchor's start.
+    pub insert: String,
+    pub timestamp_ms: u64,
+}
+
+impl Suggestion {
+    /// The edit that applies the suggestion to a document at `version`.
+    pub fn edit(&self, version: usize) -> Edit {
+        Edit {
+            position: self.anchor.start,
+            insert: (!self.insert.is_empty()).then(|| self.insert.clone()),
+            delete: (self.anchor.end > self.anchor.start)
+                .then_some(self.anchor.end - self.anchor.start),
+            version,
+        }
+    }
+}
+
+/// The pending suggestions on one document.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Suggestions {
+    next_id: u64,
+    suggestions: Vec<Suggestion>,
+}
+
+impl Suggestions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn list(&self) -> &[Suggestion] {
+        &self.suggestions
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Suggestion> {
+        self.suggestions
+            .iter()
+            .find(|suggestion| suggestion.id == id)
+    }
+
+    /// Moves every pending suggestion past an operation applied to the
+    /// document.
+    pub fn transform(&mut self, op: &Op) {
+        for suggestion in &mut self.suggestions {
+            suggestion.anchor.transform(op);
+        }
+    }
+
+    /// Records an edit to `content` as a suggestion instead of applying it.
+    pub fn create(
+        &mut self,
+        content: &str,
+        edit: &Edit,
+        author: Option<&str>,
+    ) -> Result<&Suggestion, &'static str> {
+        let start = edit.position;
+        let end = start
+            .checked_add(edit.delete.unwrap_or(0))
+            .ok_or(INVALID_SUGGESTION)?;
+        let insert = edit.insert.clone().unwrap_or_default();
+        if content.get(start..end).is_none() || (start == end && insert.is_empty()) {
+            return Err(INVALID_SUGGESTION);
+        }
+        self.next_id += 1;
+        self.suggestions.push(Suggestion {
+            id: self.next_id,
+            author: author.map(str::to_string),
+            anchor: Anchor::new(start, end),
+            insert,
+            timestamp_ms: now_ms(),
+        });
+        Ok(self.suggestions.last().expect("just pushed"))
+    }
+
+    /// Removes a suggestion, returning it.
+    pub fn remove(&mut self, id: u64) -> Result<Suggestion, &'static str> {
+        let index = self
+            .suggestions
+            .iter()
+            .position(|suggestion| suggestion.id == id)
+            .ok_or(UNKNOWN_SUGGESTION)?;
+        Ok(self.suggestions.remove(index))
+    }
+}
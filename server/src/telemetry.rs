@@ -0,0 +1,97 @@
+//! OpenTelemetry trace export.
+//!
+//! With the `otel` cargo feature the server's `tracing` spans (handshake,
+//! message parse, `apply_edit` and broadcast) are exported over OTLP, and an
+//! edit's span is parented to the `traceparent` its client sent. Without the
+//! feature every function here is a no-op.
+
+use tracing::Span;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::config::LoggingConfig;
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Keeps the trace exporter alive; dropping it flushes any buffered spans.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(_config: &LoggingConfig) -> (Telemetry, Option<BoxedLayer>) {
+    (Telemetry {}, None)
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn set_remote_parent(_span: &Span, _traceparent: Option<&str>) {}
+
+#[cfg(feature = "otel")]
+pub fn init(config: &LoggingConfig) -> (Telemetry, Option<BoxedLayer>) {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("Failed to create the OTLP exporter: {}", e);
+            return (Telemetry { provider: None }, None);
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name("collaborative-editor-server")
+                .build(),
+        )
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("collaborative-editor-server"))
+        .boxed();
+
+    (
+        Telemetry {
+            provider: Some(provider),
+        },
+        Some(layer),
+    )
+}
+
+/// Continues the trace a client started, given the W3C `traceparent` it
+/// sent along with its message.
+#[cfg(feature = "otel")]
+pub fn set_remote_parent(span: &Span, traceparent: Option<&str>) {
+    use std::collections::HashMap;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    if let Some(traceparent) = traceparent {
+        let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+        let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&carrier)
+        });
+        if let Err(e) = span.set_parent(context) {
+            tracing::debug!(error = ?e, "Failed to continue the client's trace");
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Per-session undo and redo.
+//!
+//! Each session remembers the versions of the edits it made. Undoing one
+//! inverts that edit, transforms the inverse past everything applied since
+//! (including collaborators' edits) and applies the result as a new edit, so
+//! only the session's own change is reverted. Formats are undone by
+//! restoring the formatting they replaced.
+
+use crate::formatting::{FormatEdit, INVALID_FORMAT};
+use crate::ot::{self, Op};
+use crate::{DocumentState, Edit, DOCUMENT_TOO_LARGE};
+
+pub const NOTHING_TO_UNDO: &str = "Nothing to undo.";
+pub const NOTHING_TO_REDO: &str = "Nothing to redo.";
+
+/// How many edits a session can undo.
+const MAX_DEPTH: usize = 100;
+
+/// A change applied by an undo or redo.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Edit(Edit),
+    Format(FormatEdit),
+}
+
+impl Change {
+    /// The version the change was applied to.
+    pub fn version(&self) -> usize {
+        match self {
+            Change::Edit(edit) => edit.version,
+            Change::Format(format) => format.version,
+        }
+    }
+}
+
+/// The undo and redo stacks of one session. Each entry lists the versions
+/// of the edits that make up a single step, in the order they were applied.
+#[derive(Debug, Default)]
+pub struct UndoStack {
+    undo: Vec<Vec<usize>>,
+    redo: Vec<Vec<usize>>,
+}
+
+impl UndoStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an edit the session made at `version`. Anything undone
+    /// before it can no longer be redone.
+    pub fn record(&mut self, version: usize) {
+        push_bounded(&mut self.undo, vec![version]);
+        self.redo.clear();
+    }
+
+    /// Reverts the session's most recent step that still changes the
+    /// document, returning the edits applied to do so on behalf of `author`.
+    pub fn undo(
+        &mut self,
+        doc: &mut DocumentState,
+        author: &str,
+    ) -> Result<Vec<Change>, &'static str> {
+        while let Some(step) = self.undo.pop() {
+            match revert(doc, &step, author) {
+                Ok(edits) if edits.is_empty() => continue,
+                Ok(edits) => {
+                    push_bounded(&mut self.redo, versions(&edits));
+                    return Ok(edits);
+                }
+                Err(e) => {
+                    self.undo.push(step);
+                    return Err(e);
+                }
+            }
+        }
+        Err(NOTHING_TO_UNDO)
+    }
+
+    /// Re-applies the step most recently undone.
+    pub fn redo(
+        &mut self,
+        doc: &mut DocumentState,
+        author: &str,
+    ) -> Result<Vec<Change>, &'static str> {
+        while let Some(step) = self.redo.pop() {
+            match revert(doc, &step, author) {
+                Ok(edits) if edits.is_empty() => continue,
+                Ok(edits) => {
+                    push_bounded(&mut self.undo, versions(&edits));
+                    return Ok(edits);
+                }
+                Err(e) => {
+                    self.redo.push(step);
+                    return Err(e);
+                }
+            }
+        }
+        Err(NOTHING_TO_REDO)
+    }
+}
+
+fn push_bounded(stack: &mut Vec<Vec<usize>>, step: Vec<usize>) {
+    if stack.len() == MAX_DEPTH {
+        stack.remove(0);
+    }
+    stack.push(step);
+}
+
+fn versions(changes: &[Change]) -> Vec<usize> {
+    changes.iter().map(Change::version).collect()
+}
+
+/// Applies the inverse of every edit in `step`, newest first. Each inverse
+/// is transformed past all edits applied after the one it reverts, and the
+/// whole step is checked before any of it is applied, so it is applied all
+/// or nothing.
+///
+/// Steps whose history is no longer available are skipped.
+fn revert(
+    doc: &mut DocumentState,
+    step: &[usize],
+    author: &str,
+) -> Result<Vec<Change>, &'static str> {
+    let mut ops: Vec<Op> = Vec::new();
+    for &version in step.iter().rev() {
+        let inverse = match doc.applied_op(version) {
+            Some(applied_op) => applied_op.invert(),
+            None => continue,
+        };
+        // The inverses of newer edits in the step are applied before this
+        // one, so it is transformed past them too.
+        let later: Vec<Op> = match doc.ops_since(version + 1) {
+            Some(later) => later
+                .iter()
+                .map(|applied| applied.op.clone())
+                .chain(ops.iter().cloned())
+                .collect(),
+            None => continue,
+        };
+        ops.extend(inverse.iter().flat_map(|op| ot::transform_all(op, &later)));
+    }
+    check_applies(doc, &ops)?;
+
+    let mut applied = Vec::with_capacity(ops.len());
+    for op in ops {
+        match op {
+            Op::Format {
+                position,
+                length,
+                attributes,
+            } => {
+                // Text deleted since leaves nothing to restore.
+                if length == 0 {
+                    continue;
+                }
+                let format = FormatEdit {
+                    position,
+                    length,
+                    attributes,
+                    version: doc.version,
+                };
+                doc.apply_format_by(&format, author)?;
+                applied.push(Change::Format(format));
+            }
+            op => {
+                let edit = op.to_edit(doc.version);
+                doc.apply_edit_by(&edit, author)?;
+                applied.push(Change::Edit(edit));
+            }
+        }
+    }
+    Ok(applied)
+}
+
+/// Checks that `ops` apply to the document one after another without
+/// leaving a valid range or growing past its size limit.
+fn check_applies(doc: &DocumentState, ops: &[Op]) -> Result<(), &'static str> {
+    let mut content = doc.content.clone();
+    for op in ops {
+        if let Op::Format {
+            position, length, ..
+        } = op
+        {
+            let end = position.checked_add(*length).ok_or(INVALID_FORMAT)?;
+            if !content.is_char_boundary(*position) || !content.is_char_boundary(end) {
+                return Err(INVALID_FORMAT);
+            }
+            continue;
+        }
+        op.apply(&mut content)?;
+        if doc
+            .max_size
+            .is_some_and(|max_size| content.len() > max_size)
+        {
+            return Err(DOCUMENT_TOO_LARGE);
+        }
+    }
+    Ok(())
+}
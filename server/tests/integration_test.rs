@@ -0,0 +1,1794 @@
+use collaborative_editor_client as client;
+use collaborative_editor_server::backplane::{
+    owner, Backplane, Cluster, LoopbackHub, NodeMessage, TcpBackplane,
+};
+use collaborative_editor_server::config::{ClusterPeer, ServerConfig};
+use collaborative_editor_server::document::DocumentState;
+use collaborative_editor_server::replication::{
+    serve_followers, Followers, PrimaryLink, FOLLOWER, PRIMARY,
+};
+use collaborative_editor_server::storage::Storage;
+use collaborative_editor_server::{run_backplane, serve, Edit, ServerState};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The API token every test server is configured with, and the HTTP
+/// helpers send.
+const API_TOKEN: &str = "test-token";
+
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        api_token: Some(API_TOKEN.to_string()),
+        ..ServerConfig::default()
+    }
+}
+
+async fn start_server(config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(ServerState::new(config));
+    state.restore().await.unwrap();
+    tokio::spawn(serve(listener, state, std::future::pending()));
+    addr
+}
+
+/// Starts a server sharing documents with the other `nodes` on `hub`.
+async fn start_node(hub: &LoopbackHub, node: &str, nodes: &[&str]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (backplane, messages) = hub.join(node);
+    let peers: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+    let cluster = Cluster::new(node, &peers, Arc::new(backplane));
+    let state = Arc::new(ServerState::new(test_config()).with_cluster(cluster));
+    state.restore().await.unwrap();
+    tokio::spawn(run_backplane(state.clone(), messages));
+    tokio::spawn(serve(listener, state, std::future::pending()));
+    addr
+}
+
+/// Starts a primary, returning its address and the address followers
+/// connect to.
+async fn start_primary() -> (SocketAddr, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let replication = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let replication_addr = replication.local_addr().unwrap();
+    let followers = Arc::new(Followers::new());
+    let cluster = Cluster::primary(PRIMARY, followers.clone());
+    let state = Arc::new(ServerState::new(test_config()).with_cluster(cluster));
+    state.restore().await.unwrap();
+    let (requests, messages) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(run_backplane(state.clone(), messages));
+    tokio::spawn(serve_followers(
+        replication,
+        state.clone(),
+        followers,
+        requests,
+    ));
+    tokio::spawn(serve(listener, state, std::future::pending()));
+    (addr, replication_addr)
+}
+
+/// Starts a follower of the primary accepting followers on `primary`.
+async fn start_follower(primary: SocketAddr) -> SocketAddr {
+    start_follower_with(primary, test_config()).await
+}
+
+async fn start_follower_with(primary: SocketAddr, config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let storage = config.data_dir.as_ref().map(Storage::new);
+    let (link, messages) = PrimaryLink::connect(&primary.to_string(), API_TOKEN, storage);
+    let cluster = Cluster::follower(FOLLOWER, PRIMARY, Arc::new(link));
+    let state = Arc::new(ServerState::new(config).with_cluster(cluster));
+    state.restore().await.unwrap();
+    tokio::spawn(run_backplane(state.clone(), messages));
+    tokio::spawn(serve(listener, state, std::future::pending()));
+    addr
+}
+
+/// A document id that `node` owns among `nodes`.
+fn document_owned_by(node: &str, nodes: &[&str]) -> String {
+    let nodes: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+    (0..)
+        .map(|i| format!("shared-{}", i))
+        .find(|document| owner(document, &nodes) == Some(node))
+        .unwrap()
+}
+
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Runs the server binary as a node of a cluster. It is killed when the
+/// returned handle is dropped.
+fn spawn_node(id: &str, addr: SocketAddr, backplane: SocketAddr, peers: &str) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_collaborative-editor-server"))
+        .env("EDITOR_ADDR", addr.to_string())
+        .env("EDITOR_NODE_ID", id)
+        .env("EDITOR_BACKPLANE_ADDR", backplane.to_string())
+        .env("EDITOR_CLUSTER_PEERS", peers)
+        .env("EDITOR_API_TOKEN", API_TOKEN)
+        .env_remove("EDITOR_DATA_DIR")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+async fn wait_until_ready(addr: SocketAddr) {
+    for _ in 0..200 {
+        if TcpStream::connect(addr).await.is_ok()
+            && http_request(addr, "GET", "/readyz").await.0 == 200
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Server at {} never became ready", addr);
+}
+
+async fn connect(addr: SocketAddr, query: &str) -> Client {
+    let (ws_stream, _) = connect_async(format!("ws://{}/{}", addr, query))
+        .await
+        .unwrap();
+    ws_stream
+}
+
+async fn next_json(client: &mut Client) -> Value {
+    match client.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+        other => panic!("Expected a text message, got {:?}", other),
+    }
+}
+
+/// Sends a plain HTTP request and returns the status code and body.
+async fn http_request(addr: SocketAddr, method: &str, path: &str) -> (u16, String) {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        method, path, addr, API_TOKEN
+    );
+    http_exchange(addr, &request).await
+}
+
+/// Sends a plain HTTP POST with a JSON body and returns the status code and
+/// body of the response.
+async fn http_post(addr: SocketAddr, path: &str, body: &Value) -> (u16, String) {
+    http_send(addr, "POST", path, body).await
+}
+
+/// Sends a plain HTTP request with a JSON body and returns the status code
+/// and body of the response.
+async fn http_send(addr: SocketAddr, method: &str, path: &str, body: &Value) -> (u16, String) {
+    let body = body.to_string();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        API_TOKEN,
+        body.len(),
+        body
+    );
+    http_exchange(addr, &request).await
+}
+
+/// Writes a raw HTTP request and returns the status code and body of the
+/// response.
+async fn http_exchange(addr: SocketAddr, request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+fn edit_message(position: usize, insert: &str, version: usize) -> Message {
+    let message = json!({
+        "type": "edit",
+        "edit": {
+            "position": position,
+            "insert": insert,
+            "delete": null,
+            "version": version,
+        }
+    });
+    Message::Text(message.to_string())
+}
+
+#[tokio::test]
+async fn test_connection_rate_limit_replies_with_error() {
+    let mut config = test_config();
+    config.limits.connection_burst = 1.0;
+    config.limits.connection_edits_per_sec = 0.001;
+    let addr = start_server(config).await;
+
+    let mut client = connect(addr, "?user=alice").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+
+    client.send(edit_message(0, "a", 0)).await.unwrap();
+    client.send(edit_message(1, "b", 1)).await.unwrap();
+
+    let reply = next_json(&mut client).await;
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], "rate_limited");
+}
+
+#[tokio::test]
+async fn test_checkpoints_count_against_the_rate_limit() {
+    let mut config = test_config();
+    config.limits.connection_burst = 1.0;
+    config.limits.connection_edits_per_sec = 0.001;
+    let addr = start_server(config).await;
+
+    let mut client = connect(addr, "?user=alice").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+    for name in ["first", "second"] {
+        let create = json!({ "type": "checkpoint_create", "name": name });
+        client
+            .send(Message::Text(create.to_string()))
+            .await
+            .unwrap();
+    }
+    assert_eq!(next_json(&mut client).await["type"], "checkpoint_created");
+    let reply = next_json(&mut client).await;
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], "rate_limited");
+}
+
+#[tokio::test]
+async fn test_user_names_share_their_address_rate_limit() {
+    let mut config = test_config();
+    config.limits.user_burst = 1.0;
+    config.limits.user_edits_per_sec = 0.001;
+    let addr = start_server(config).await;
+
+    let mut alice = connect(addr, "?user=alice").await;
+    let mut bob = connect(addr, "?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    alice.send(edit_message(0, "a", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["insert"], "a");
+    // Bob connects from the same address, so Alice used up his edits too.
+    bob.send(edit_message(0, "b", 1)).await.unwrap();
+    let reply = next_json(&mut bob).await;
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], "rate_limited");
+}
+
+#[tokio::test]
+async fn test_http_edits_are_rate_limited_by_address() {
+    let mut config = test_config();
+    config.limits.user_burst = 2.0;
+    config.limits.user_edits_per_sec = 0.001;
+    let addr = start_server(config).await;
+
+    let edits =
+        |version: u64| json!({ "version": version, "edits": [{ "position": 0, "insert": "x" }] });
+    let (status, _) = http_send(addr, "PUT", "/documents/notes", &edits(0)).await;
+    assert_eq!(status, 200);
+    let (status, _) = http_send(addr, "PUT", "/documents/notes?user=bob", &edits(1)).await;
+    assert_eq!(status, 200);
+    // Naming another author doesn't get a fresh allowance.
+    let (status, _) = http_send(addr, "PUT", "/documents/notes?user=carol", &edits(2)).await;
+    assert_eq!(status, 429);
+}
+
+#[tokio::test]
+async fn test_oversized_message_closes_connection() {
+    let mut config = test_config();
+    config.limits.max_message_size = 64;
+    config.limits.max_frame_size = 64;
+    let addr = start_server(config).await;
+
+    let mut client = connect(addr, "").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+
+    client
+        .send(edit_message(0, &"x".repeat(128), 0))
+        .await
+        .unwrap();
+
+    match client.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Size),
+        other => panic!("Expected a close frame, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_peers_and_flushes_document() {
+    let data_dir = std::env::temp_dir().join(format!("editor-shutdown-{}", std::process::id()));
+    let config = ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        shutdown_timeout: Duration::from_secs(2),
+        ..test_config()
+    };
+    let state = Arc::new(ServerState::new(config));
+    state.restore().await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (trigger, shutdown) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve(listener, state.clone(), async {
+        let _ = shutdown.await;
+    }));
+
+    let mut client = connect(addr, "").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+    client.send(edit_message(0, "draft", 0)).await.unwrap();
+
+    // Wait until the edit has been applied before shutting down.
+    let room = state.room("default").await.unwrap();
+    while room.document.read().await.version == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    trigger.send(()).unwrap();
+
+    match client.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(frame.code, CloseCode::Restart);
+            assert_eq!(frame.reason, "server restarting");
+        }
+        other => panic!("Expected a close frame, got {:?}", other),
+    }
+    // Completing the close handshake lets the server finish draining.
+    while client.next().await.is_some() {}
+    server.await.unwrap();
+
+    state.flush().await.unwrap();
+    let snapshot = Storage::new(&data_dir)
+        .load_snapshot("default")
+        .unwrap()
+        .unwrap();
+    assert_eq!(snapshot.content, "draft");
+    assert_eq!(snapshot.version, 1);
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint_reports_rooms_and_edits() {
+    let addr = start_server(test_config()).await;
+
+    let mut client = connect(addr, "notes").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+    client.send(edit_message(0, "stale", 3)).await.unwrap();
+    client.send(edit_message(0, "hello", 0)).await.unwrap();
+    // Edits are handled in order, so once the second one shows up in a new
+    // connection's initial state, the rejected one has been counted too.
+    let mut other = connect(addr, "notes").await;
+    if next_json(&mut other).await["version"] == 0 {
+        assert_eq!(next_json(&mut other).await["edit"]["version"], 1);
+    }
+
+    let (status, body) = http_request(addr, "GET", "/metrics").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("editor_edits_applied_total 1"));
+    assert!(body.contains(r#"editor_edits_rejected_total{reason="version_mismatch"} 1"#));
+    assert!(body.contains(r#"editor_document_size_bytes{room="notes"} 5"#));
+    assert!(body.contains(r#"editor_room_peers{room="notes"} 2"#));
+}
+
+#[tokio::test]
+async fn test_health_and_readiness_endpoints() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(ServerState::new(test_config()));
+    tokio::spawn(serve(listener, state.clone(), std::future::pending()));
+
+    let (status, body) = http_request(addr, "GET", "/healthz").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["status"],
+        "ok"
+    );
+
+    // Not ready, and not accepting editors, until recovery has run.
+    let (status, body) = http_request(addr, "GET", "/readyz").await;
+    assert_eq!(status, 503);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["checks"]["recovery"], "in_progress");
+    assert!(connect_async(format!("ws://{}/", addr)).await.is_err());
+
+    state.restore().await.unwrap();
+    let (status, body) = http_request(addr, "GET", "/readyz").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["status"],
+        "ready"
+    );
+}
+
+#[tokio::test]
+async fn test_undo_broadcasts_inverse_to_everyone() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "undo?user=alice").await;
+    let mut bob = connect(addr, "undo?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    alice.send(edit_message(0, "hello", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 1);
+    bob.send(edit_message(0, ">> ", 1)).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["edit"]["version"], 2);
+
+    alice
+        .send(Message::Text(json!({ "type": "undo" }).to_string()))
+        .await
+        .unwrap();
+    for client in [&mut alice, &mut bob] {
+        let edit = next_json(client).await["edit"].clone();
+        assert_eq!(edit["position"], 3);
+        assert_eq!(edit["delete"], 5);
+        assert_eq!(edit["version"], 3);
+    }
+
+    alice
+        .send(Message::Text(json!({ "type": "undo" }).to_string()))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut alice).await["code"], "nothing_to_undo");
+}
+
+#[tokio::test]
+async fn test_history_queries_over_http_and_websocket() {
+    let addr = start_server(test_config()).await;
+
+    let mut client = connect(addr, "story?user=alice").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+    client.send(edit_message(0, "once", 0)).await.unwrap();
+    client.send(edit_message(4, " upon", 1)).await.unwrap();
+
+    client
+        .send(Message::Text(
+            json!({ "type": "content_at", "version": 1 }).to_string(),
+        ))
+        .await
+        .unwrap();
+    let reply = next_json(&mut client).await;
+    assert_eq!(reply["type"], "content_at");
+    assert_eq!(reply["content"], "once");
+
+    let (status, body) = http_request(addr, "GET", "/documents/story/content?version=2").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["content"],
+        "once upon"
+    );
+
+    let (status, body) = http_request(addr, "GET", "/documents/story/history?since=1").await;
+    assert_eq!(status, 200);
+    let history: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(history["ops"].as_array().unwrap().len(), 1);
+    assert_eq!(history["ops"][0]["type"], "insert");
+    assert_eq!(history["ops"][0]["text"], " upon");
+    assert_eq!(history["ops"][0]["author"], "alice");
+
+    let (status, _) = http_request(addr, "GET", "/documents/story/content?version=9").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_documents_can_be_edited_over_http() {
+    let addr = start_server(test_config()).await;
+
+    let edits = json!({ "version": 0, "edits": [{ "position": 0, "insert": "world" }] });
+    let (status, _) = http_send(addr, "PATCH", "/documents/notes", &edits).await;
+    assert_eq!(status, 404);
+    let (status, body) = http_send(addr, "PUT", "/documents/notes?user=ci", &edits).await;
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["version"], 1);
+
+    let mut alice = connect(addr, "notes?user=alice").await;
+    let mut bob = connect(addr, "notes?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["content"], "world");
+    assert_eq!(next_json(&mut bob).await["content"], "world");
+    alice.send(edit_message(5, "!", 1)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 2);
+
+    // Made on version 1, so they are transformed past alice's edit.
+    let edits = json!({
+        "version": 1,
+        "edits": [
+            { "position": 0, "insert": "hello " },
+            { "position": 6, "insert": "big " },
+        ],
+    });
+    let (status, body) = http_send(addr, "PATCH", "/documents/notes?user=ci", &edits).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["version"], 4);
+    assert_eq!(body["applied"], 2);
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(next_json(client).await["edit"]["position"], 0);
+        assert_eq!(next_json(client).await["edit"]["position"], 6);
+    }
+
+    let (status, body) = http_request(addr, "GET", "/documents/notes").await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["content"], "hello big world!");
+    assert_eq!(body["version"], 4);
+    let (_, body) = http_request(addr, "GET", "/documents").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["documents"],
+        json!(["notes"])
+    );
+
+    let (status, _) = http_request(addr, "DELETE", "/documents/notes").await;
+    assert_eq!(status, 200);
+    assert!(matches!(alice.next().await, Some(Ok(Message::Close(_)))));
+    let (status, _) = http_request(addr, "GET", "/documents/notes").await;
+    assert_eq!(status, 404);
+    let (status, _) = http_request(addr, "DELETE", "/documents/notes").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_reads_over_http_need_the_api_token() {
+    let get = |addr: SocketAddr, path: &str| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        )
+    };
+
+    let addr = start_server(test_config()).await;
+    for path in ["/documents", "/documents/notes", "/metrics"] {
+        assert_eq!(http_exchange(addr, &get(addr, path)).await.0, 401);
+    }
+    assert_eq!(http_request(addr, "GET", "/documents").await.0, 200);
+    assert_eq!(http_exchange(addr, &get(addr, "/healthz")).await.0, 200);
+    assert_eq!(http_exchange(addr, &get(addr, "/readyz")).await.0, 200);
+
+    // Without a configured token there is nothing to check reads against.
+    let addr = start_server(ServerConfig::default()).await;
+    assert_eq!(http_exchange(addr, &get(addr, "/documents")).await.0, 200);
+}
+
+#[tokio::test]
+async fn test_changes_over_http_need_the_api_token() {
+    let edits = json!({ "version": 0, "edits": [{ "position": 0, "insert": "x" }] }).to_string();
+    let put = |addr: SocketAddr, token: Option<&str>| {
+        let authorization = token.map_or(String::new(), |token| {
+            format!("Authorization: Bearer {}\r\n", token)
+        });
+        format!(
+            "PUT /documents/locked HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            addr,
+            authorization,
+            edits.len(),
+            edits
+        )
+    };
+
+    let addr = start_server(test_config()).await;
+    for token in [None, Some("wrong-token")] {
+        assert_eq!(http_exchange(addr, &put(addr, token)).await.0, 401);
+    }
+    let promote = format!(
+        "POST /promote HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    );
+    assert_eq!(http_exchange(addr, &promote).await.0, 401);
+    let (status, _) = http_request(addr, "GET", "/documents/locked").await;
+    assert_eq!(status, 404);
+    assert_eq!(
+        http_exchange(addr, &put(addr, Some(API_TOKEN))).await.0,
+        200
+    );
+
+    // Without a configured token, nothing can be changed over HTTP.
+    let addr = start_server(ServerConfig::default()).await;
+    assert_eq!(
+        http_exchange(addr, &put(addr, Some(API_TOKEN))).await.0,
+        401
+    );
+    assert_eq!(http_request(addr, "GET", "/healthz").await.0, 200);
+}
+
+#[tokio::test]
+async fn test_checkpoint_restore_converges_all_clients() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "essay?user=alice").await;
+    let mut bob = connect(addr, "essay?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    alice.send(edit_message(0, "draft one", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 1);
+    alice
+        .send(Message::Text(
+            json!({ "type": "checkpoint_create", "name": "submitted draft" }).to_string(),
+        ))
+        .await
+        .unwrap();
+    for client in [&mut alice, &mut bob] {
+        let created = next_json(client).await;
+        assert_eq!(created["type"], "checkpoint_created");
+        assert_eq!(created["checkpoint"]["version"], 1);
+    }
+
+    bob.send(edit_message(6, "two and ", 1)).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["edit"]["version"], 2);
+
+    let (status, body) = http_post(
+        addr,
+        "/documents/essay/checkpoints/submitted%20draft/restore",
+        &json!({}),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["version"], 3);
+    for client in [&mut alice, &mut bob] {
+        let edit = next_json(client).await["edit"].clone();
+        assert_eq!(edit["position"], 6);
+        assert_eq!(edit["delete"], 8);
+        assert_eq!(edit["version"], 3);
+    }
+
+    let (_, body) = http_request(addr, "GET", "/documents/essay/content").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["content"],
+        "draft one"
+    );
+
+    let (status, _) = http_post(
+        addr,
+        "/documents/essay/checkpoints",
+        &json!({ "name": "submitted draft" }),
+    )
+    .await;
+    assert_eq!(status, 409);
+    let (status, _) = http_request(
+        addr,
+        "DELETE",
+        "/documents/essay/checkpoints/submitted%20draft",
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (_, body) = http_request(addr, "GET", "/documents/essay/checkpoints").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["checkpoints"],
+        json!([])
+    );
+}
+
+#[tokio::test]
+async fn test_diff_endpoint_returns_unified_and_json() {
+    let addr = start_server(test_config()).await;
+
+    let mut client = connect(addr, "minutes?user=alice").await;
+    assert_eq!(next_json(&mut client).await["type"], "initial");
+    client.send(edit_message(0, "agenda\n", 0)).await.unwrap();
+    client
+        .send(edit_message(7, "decisions\n", 1))
+        .await
+        .unwrap();
+    client
+        .send(Message::Text(
+            json!({ "type": "diff", "from": 1 }).to_string(),
+        ))
+        .await
+        .unwrap();
+    let reply = next_json(&mut client).await;
+    assert_eq!(reply["type"], "diff");
+    assert_eq!(reply["diff"]["to"], 2);
+
+    let (status, body) = http_request(
+        addr,
+        "GET",
+        "/documents/minutes/diff?from=1&to=2&format=unified",
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        "--- version 1\n+++ version 2\n@@ -1 +1,2 @@\n agenda\n+decisions\n"
+    );
+
+    let (status, body) = http_request(addr, "GET", "/documents/minutes/diff?from=0").await;
+    assert_eq!(status, 200);
+    let diff: Value = serde_json::from_str(&body).unwrap();
+    let lines = diff["diff"]["hunks"][0]["lines"].as_array().unwrap();
+    assert!(lines
+        .iter()
+        .all(|line| line["tag"] == "insert" && line["authors"] == json!(["alice"])));
+
+    let (status, _) =
+        http_request(addr, "GET", "/documents/minutes/diff?from_checkpoint=nope").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_blame_endpoint_reports_authors() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "poem?user=alice").await;
+    let mut bob = connect(addr, "poem?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+    alice.send(edit_message(0, "roses", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 1);
+    bob.send(edit_message(5, " are red", 1)).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["edit"]["version"], 2);
+
+    let (status, body) = http_request(addr, "GET", "/documents/poem/blame").await;
+    assert_eq!(status, 200);
+    let blame: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(blame["version"], 2);
+    let spans = blame["spans"].as_array().unwrap();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0]["author"], "alice");
+    assert_eq!(
+        (spans[0]["start"].clone(), spans[0]["end"].clone()),
+        (json!(0), json!(5))
+    );
+    assert_eq!(spans[1]["author"], "bob");
+    assert_eq!(spans[1]["version"], 1);
+    assert!(spans[1]["timestamp_ms"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_comment_threads_are_broadcast_and_anchored() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "review?user=alice").await;
+    let mut bob = connect(addr, "review?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+    alice.send(edit_message(0, "ship it", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 1);
+
+    let create = json!({
+        "type": "comment_create",
+        "start": 0,
+        "end": 4,
+        "text": "Are we sure?",
+    });
+    alice.send(Message::Text(create.to_string())).await.unwrap();
+    let mut thread_id = 0;
+    for client in [&mut alice, &mut bob] {
+        let created = next_json(client).await;
+        assert_eq!(created["type"], "comment_thread");
+        assert_eq!(created["thread"]["comments"][0]["author"], "alice");
+        thread_id = created["thread"]["id"].as_u64().unwrap();
+    }
+
+    let reply = json!({ "type": "comment_reply", "thread": thread_id, "text": "Yes" });
+    bob.send(Message::Text(reply.to_string())).await.unwrap();
+    let replied = next_json(&mut alice).await;
+    assert_eq!(replied["thread"]["comments"][1]["author"], "bob");
+    assert_eq!(next_json(&mut bob).await["type"], "comment_thread");
+
+    bob.send(edit_message(0, "please ", 1)).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["type"], "edit");
+
+    let (status, body) = http_request(addr, "GET", "/documents/review/comments").await;
+    assert_eq!(status, 200);
+    let threads: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(threads["threads"][0]["start"], 7);
+    assert_eq!(threads["threads"][0]["end"], 11);
+
+    let delete = json!({ "type": "comment_delete", "thread": thread_id });
+    bob.send(Message::Text(delete.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["code"], "not_author");
+}
+
+#[tokio::test]
+async fn test_suggestions_are_shared_and_accepted_by_editors() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "draft?user=alice").await;
+    let mut bob = connect(addr, "draft?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+    alice.send(edit_message(0, "hello", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 1);
+
+    let mode = json!({ "type": "suggestion_mode", "enabled": true });
+    bob.send(Message::Text(mode.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["enabled"], true);
+    bob.send(edit_message(5, " world", 1)).await.unwrap();
+    let mut id = 0;
+    for client in [&mut alice, &mut bob] {
+        let suggested = next_json(client).await;
+        assert_eq!(suggested["type"], "suggestion");
+        assert_eq!(suggested["suggestion"]["author"], "bob");
+        assert_eq!(suggested["suggestion"]["insert"], " world");
+        id = suggested["suggestion"]["id"].as_u64().unwrap();
+    }
+
+    let (_, body) = http_request(addr, "GET", "/documents/draft/content?version=1").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["content"],
+        "hello"
+    );
+    let (status, body) = http_request(addr, "GET", "/documents/draft/suggestions").await;
+    assert_eq!(status, 200);
+    let listed: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(listed["version"], 1);
+    assert_eq!(listed["suggestions"][0]["id"], id);
+
+    let accept = json!({ "type": "suggestion_accept", "id": id });
+    bob.send(Message::Text(accept.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["code"], "not_editor");
+
+    alice.send(Message::Text(accept.to_string())).await.unwrap();
+    for client in [&mut alice, &mut bob] {
+        let accepted = next_json(client).await;
+        assert_eq!(accepted["type"], "suggestion_accepted");
+        assert_eq!(accepted["id"], id);
+        let edit = next_json(client).await;
+        assert_eq!(edit["edit"]["insert"], " world");
+        assert_eq!(edit["edit"]["version"], 2);
+    }
+}
+
+#[tokio::test]
+async fn test_formatting_reaches_rich_and_plain_clients() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "styled?user=alice").await;
+    let mut bob = connect(addr, "styled?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+    alice.send(edit_message(0, "Title\nbody", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 1);
+
+    let format = json!({
+        "type": "format",
+        "format": {
+            "position": 0,
+            "length": 5,
+            "attributes": { "heading": 1 },
+            "version": 1,
+        },
+    });
+    alice.send(Message::Text(format.to_string())).await.unwrap();
+    let edit = next_json(&mut bob).await;
+    assert_eq!(edit["type"], "edit");
+    assert_eq!(edit["edit"]["insert"], Value::Null);
+    assert_eq!(edit["edit"]["delete"], Value::Null);
+    assert_eq!(edit["edit"]["version"], 2);
+    assert_eq!(edit["edit"]["format"]["attributes"]["heading"], 1);
+
+    // A client that only understands text can keep editing at the new version.
+    bob.send(edit_message(10, "!", 2)).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["edit"]["version"], 3);
+
+    let mut carol = connect(addr, "styled?user=carol").await;
+    let initial = next_json(&mut carol).await;
+    assert_eq!(initial["content"], "Title\nbody!");
+    assert_eq!(initial["formatting"][0]["end"], 5);
+
+    let (status, body) = http_request(addr, "GET", "/documents/styled/formatting").await;
+    assert_eq!(status, 200);
+    let formatting: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(formatting["spans"][0]["attributes"]["heading"], 1);
+}
+
+#[tokio::test]
+async fn test_block_documents_share_ops_and_convert_to_markdown() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "blocks/notes?user=alice").await;
+    let mut bob = connect(addr, "blocks/notes?user=bob").await;
+    let initial = next_json(&mut alice).await;
+    assert_eq!(initial["blocks"], json!([]));
+    next_json(&mut bob).await;
+
+    let import = json!({
+        "type": "import",
+        "format": "markdown",
+        "text": "# Plan\n\n- write tests",
+    });
+    alice.send(Message::Text(import.to_string())).await.unwrap();
+    let imported = next_json(&mut bob).await;
+    assert_eq!(imported["type"], "blocks");
+    assert_eq!(imported["version"], 1);
+    let item = imported["blocks"][1]["id"].as_u64().unwrap();
+    next_json(&mut alice).await;
+
+    let split = json!({
+        "type": "block_edit",
+        "op": { "op": "split", "block": item, "position": 5 },
+        "version": 1,
+    });
+    bob.send(Message::Text(split.to_string())).await.unwrap();
+    for client in [&mut alice, &mut bob] {
+        let edit = next_json(client).await;
+        assert_eq!(edit["type"], "block_edit");
+        assert_eq!(edit["author"], "bob");
+        assert_eq!(edit["version"], 2);
+        assert!(edit["op"]["new_block"].as_u64().unwrap() > item);
+    }
+
+    let (status, body) = http_request(addr, "GET", "/blocks/notes?format=markdown").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "# Plan\n\n- write\n-  tests");
+    let (status, _) = http_request(addr, "GET", "/blocks/unknown").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn test_binary_subprotocols_interoperate_with_json_clients() {
+    let addr = start_server(test_config()).await;
+
+    let mut request = format!("ws://{}/packed?user=alice", addr)
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        "chat, editor.msgpack".parse().unwrap(),
+    );
+    let (mut alice, response) = connect_async(request).await.unwrap();
+    assert_eq!(
+        response.headers()["Sec-WebSocket-Protocol"],
+        "editor.msgpack"
+    );
+    let mut bob = connect(addr, "packed?user=bob").await;
+
+    let initial: Value = match alice.next().await {
+        Some(Ok(Message::Binary(bytes))) => rmp_serde::from_slice(&bytes).unwrap(),
+        other => panic!("Expected a binary message, got {:?}", other),
+    };
+    assert_eq!(initial["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    let edit = json!({
+        "type": "edit",
+        "edit": { "position": 0, "insert": "packed", "delete": null, "version": 0 },
+    });
+    alice
+        .send(Message::Binary(rmp_serde::to_vec(&edit).unwrap()))
+        .await
+        .unwrap();
+    let received = next_json(&mut bob).await;
+    assert_eq!(received["edit"]["insert"], "packed");
+
+    bob.send(edit_message(6, "!", 1)).await.unwrap();
+    let received: Value = match alice.next().await {
+        Some(Ok(Message::Binary(bytes))) => rmp_serde::from_slice(&bytes).unwrap(),
+        other => panic!("Expected a binary message, got {:?}", other),
+    };
+    assert_eq!(received["edit"]["insert"], "!");
+    assert_eq!(received["edit"]["version"], 2);
+}
+
+#[tokio::test]
+async fn test_large_messages_are_compressed_on_request() {
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    let addr = start_server(test_config()).await;
+    let mut writer = connect(addr, "large?user=alice").await;
+    assert_eq!(next_json(&mut writer).await["type"], "initial");
+    let text = "It was a dark and stormy night. ".repeat(1000);
+    writer.send(edit_message(0, &text, 0)).await.unwrap();
+    // The edit has been applied once another client sees the new version.
+    let mut plain = connect(addr, "large?user=bob").await;
+    let initial = next_json(&mut plain).await;
+    assert_eq!(initial["content"], text.as_str());
+
+    let mut compressed = connect(addr, "large?user=carol&compress=deflate").await;
+    let bytes = match compressed.next().await {
+        Some(Ok(Message::Binary(bytes))) => bytes,
+        other => panic!("Expected a binary message, got {:?}", other),
+    };
+    assert!(bytes.len() < text.len() / 10);
+    let mut json = String::new();
+    ZlibDecoder::new(bytes.as_slice())
+        .read_to_string(&mut json)
+        .unwrap();
+    let initial: Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(initial["content"], text.as_str());
+
+    // Small messages still arrive as plain JSON.
+    writer.send(edit_message(0, "!", 1)).await.unwrap();
+    assert_eq!(next_json(&mut compressed).await["edit"]["insert"], "!");
+}
+
+#[tokio::test]
+async fn test_large_documents_stream_in_chunks_before_new_edits() {
+    let addr = start_server(ServerConfig {
+        initial_chunk_size: 16,
+        ..test_config()
+    })
+    .await;
+
+    let mut alice = connect(addr, "novel?user=alice").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    let text = "Ünïcödé chapters never split a character in two.";
+    alice.send(edit_message(0, text, 0)).await.unwrap();
+    let mut small = connect(addr, "novel?user=carol").await;
+    assert_eq!(next_json(&mut small).await["version"], 1);
+
+    let mut bob = connect(addr, "novel?user=bob").await;
+    // Sent while bob may still be receiving chunks.
+    alice.send(edit_message(0, ">", 1)).await.unwrap();
+
+    let start = next_json(&mut bob).await;
+    assert_eq!(start["type"], "initial_start");
+    assert_eq!(start["length"], text.len());
+    let version = start["version"].as_u64().unwrap();
+    let mut content = String::new();
+    for index in 0..start["chunks"].as_u64().unwrap() {
+        let chunk = next_json(&mut bob).await;
+        assert_eq!(chunk["type"], "initial_chunk");
+        assert_eq!(chunk["index"], index);
+        assert_eq!(chunk["version"], version);
+        let piece = chunk["content"].as_str().unwrap();
+        assert!(piece.len() <= 16);
+        content.push_str(piece);
+    }
+    let end = next_json(&mut bob).await;
+    assert_eq!(end["type"], "initial_end");
+    assert_eq!(end["version"], version);
+
+    if version == 1 {
+        assert_eq!(content, text);
+        let edit = next_json(&mut bob).await;
+        assert_eq!(edit["type"], "edit");
+        assert_eq!(edit["edit"]["version"], 2);
+    } else {
+        assert_eq!(content, format!(">{}", text));
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_catches_up_and_rebases_pending_edits() {
+    let addr = start_server(test_config()).await;
+
+    let mut alice = connect(addr, "trip?user=alice").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    alice.send(edit_message(0, "hello world", 0)).await.unwrap();
+    let mut bob = connect(addr, "trip?user=bob").await;
+    assert_eq!(next_json(&mut bob).await["version"], 1);
+    let mut carol = connect(addr, "trip?user=carol").await;
+    assert_eq!(next_json(&mut carol).await["version"], 1);
+    alice.close(None).await.unwrap();
+
+    // Bob keeps editing while alice is offline.
+    bob.send(edit_message(6, "big ", 1)).await.unwrap();
+    bob.send(edit_message(15, "!", 2)).await.unwrap();
+    assert_eq!(next_json(&mut carol).await["edit"]["version"], 2);
+    assert_eq!(next_json(&mut carol).await["edit"]["version"], 3);
+
+    let mut alice = connect(addr, "trip?user=alice&since=1").await;
+    let catch_up = next_json(&mut alice).await;
+    assert_eq!(catch_up["type"], "catch_up");
+    assert_eq!(catch_up["since"], 1);
+    assert_eq!(catch_up["version"], 3);
+    assert_eq!(catch_up["edits"][0]["insert"], "big ");
+    assert_eq!(catch_up["edits"][0]["version"], 2);
+    assert_eq!(catch_up["edits"][1]["version"], 3);
+
+    // Alice typed a comma on version 1 that never reached the server.
+    let resume = json!({
+        "type": "resume",
+        "version": 1,
+        "edits": [{ "position": 5, "insert": ",", "delete": null, "version": 1 }],
+    });
+    alice.send(Message::Text(resume.to_string())).await.unwrap();
+    let edit = next_json(&mut alice).await;
+    assert_eq!(edit["edit"]["position"], 5);
+    assert_eq!(edit["edit"]["version"], 4);
+    let resumed = next_json(&mut alice).await;
+    assert_eq!(resumed["type"], "resumed");
+    assert_eq!(resumed["applied"], 1);
+    assert_eq!(next_json(&mut bob).await["edit"]["version"], 4);
+
+    // History that no longer reaches back falls back to the full document.
+    let mut dave = connect(addr, "trip?user=dave&since=40").await;
+    let initial = next_json(&mut dave).await;
+    assert_eq!(initial["type"], "initial");
+    assert_eq!(initial["content"], "hello, big world!");
+}
+
+#[tokio::test]
+async fn test_retried_edits_are_acknowledged_but_applied_once() {
+    let addr = start_server(test_config()).await;
+    let mut alice = connect(addr, "retry?user=alice").await;
+    let mut bob = connect(addr, "retry?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    let edit = json!({
+        "type": "edit",
+        "edit": { "position": 0, "insert": "once", "delete": null, "version": 0 },
+        "client_id": "alice-laptop",
+        "seq": 1,
+    });
+    alice.send(Message::Text(edit.to_string())).await.unwrap();
+    let ack = next_json(&mut alice).await;
+    assert_eq!(ack["type"], "ack");
+    assert_eq!(ack["seq"], 1);
+    assert_eq!(ack["version"], 1);
+    assert_eq!(ack["duplicate"], false);
+
+    // The ack was lost, so alice reconnects and sends the edit again.
+    alice.close(None).await.unwrap();
+    let mut alice = connect(addr, "retry?user=alice&since=0").await;
+    assert_eq!(next_json(&mut alice).await["type"], "catch_up");
+    alice.send(Message::Text(edit.to_string())).await.unwrap();
+    let ack = next_json(&mut alice).await;
+    assert_eq!(ack["duplicate"], true);
+    assert_eq!(ack["version"], 1);
+
+    bob.send(edit_message(4, "!", 1)).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["edit"]["version"], 2);
+    assert_eq!(next_json(&mut bob).await["edit"]["insert"], "once");
+
+    let bad = json!({
+        "type": "edit",
+        "edit": { "position": 0, "insert": "x", "delete": null, "version": 2 },
+        "client_id": "",
+        "seq": 2,
+    });
+    alice.send(Message::Text(bad.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["code"], "invalid_op_id");
+}
+
+#[tokio::test]
+async fn test_verify_sends_full_state_to_diverged_clients() {
+    let addr = start_server(test_config()).await;
+    let mut alice = connect(addr, "sums?user=alice").await;
+    let mut bob = connect(addr, "sums?user=bob").await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    let mut content = String::new();
+    for version in 0..16 {
+        alice
+            .send(edit_message(version, "a", version))
+            .await
+            .unwrap();
+        content.push('a');
+        let edit = next_json(&mut bob).await;
+        // Every 16th version carries the checksum of the content.
+        match version + 1 {
+            16 => assert_eq!(edit["checksum"], crc32fast::hash(content.as_bytes())),
+            _ => assert!(edit.get("checksum").is_none()),
+        }
+    }
+
+    let verify = json!({
+        "type": "verify",
+        "version": 3,
+        "checksum": crc32fast::hash(b"aaa"),
+    });
+    bob.send(Message::Text(verify.to_string())).await.unwrap();
+    let reply = next_json(&mut bob).await;
+    assert_eq!(reply["type"], "verified");
+    assert_eq!(reply["version"], 3);
+
+    let verify = json!({ "type": "verify", "version": 16, "checksum": 12345 });
+    bob.send(Message::Text(verify.to_string())).await.unwrap();
+    let reply = next_json(&mut bob).await;
+    assert_eq!(reply["type"], "full_state");
+    assert_eq!(reply["content"], content.as_str());
+    assert_eq!(reply["version"], 16);
+
+    let (_, metrics) = http_request(addr, "GET", "/metrics").await;
+    assert!(metrics.contains("editor_divergences_total 1"));
+}
+
+#[tokio::test]
+async fn test_nodes_forward_edits_to_the_document_owner() {
+    let hub = LoopbackHub::new();
+    let a = start_node(&hub, "a", &["b"]).await;
+    let b = start_node(&hub, "b", &["a"]).await;
+    let document = document_owned_by("a", &["a", "b"]);
+
+    let mut alice = connect(a, &format!("{}?user=alice", document)).await;
+    let mut bob = connect(b, &format!("{}?user=bob", document)).await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    // Bob's node does not own the document, so a applies his edit.
+    let edit = json!({
+        "type": "edit",
+        "edit": { "position": 0, "insert": "hi", "delete": null, "version": 0 },
+        "client_id": "bob-phone",
+        "seq": 1,
+    });
+    bob.send(Message::Text(edit.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut alice).await["edit"]["insert"], "hi");
+    let ack = next_json(&mut bob).await;
+    assert_eq!(ack["type"], "ack");
+    assert_eq!(ack["version"], 1);
+
+    alice.send(edit_message(2, "!", 1)).await.unwrap();
+    let edit = next_json(&mut bob).await;
+    assert_eq!(edit["edit"]["insert"], "!");
+    assert_eq!(edit["edit"]["version"], 2);
+
+    // The owner's errors reach clients on other nodes too.
+    let bad = json!({
+        "type": "edit",
+        "edit": { "position": 0, "insert": "x", "delete": null, "version": 2 },
+        "client_id": "",
+        "seq": 2,
+    });
+    bob.send(Message::Text(bad.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["code"], "invalid_op_id");
+
+    // b's replica kept up, so new connections to it start from there.
+    let mut carol = connect(b, &format!("{}?user=carol", document)).await;
+    let initial = next_json(&mut carol).await;
+    assert_eq!(initial["content"], "hi!");
+    assert_eq!(initial["version"], 2);
+
+    let path = format!("/documents/{}/checkpoints", document);
+    let (status, _) = http_post(b, &path, &json!({ "name": "draft" })).await;
+    assert_eq!(status, 409);
+    let (status, _) = http_post(a, &path, &json!({ "name": "draft" })).await;
+    assert_eq!(status, 201);
+}
+
+#[tokio::test]
+async fn test_nodes_drop_connections_without_the_api_token() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_a, mut received) = TcpBackplane::start(listener, &[], API_TOKEN);
+    let sync = |from: &str| NodeMessage::SyncRequest {
+        document: "notes".to_string(),
+        from: from.to_string(),
+    };
+
+    let mut intruder = TcpStream::connect(addr).await.unwrap();
+    let line = format!("{}\n", serde_json::to_string(&sync("intruder")).unwrap());
+    intruder.write_all(line.as_bytes()).await.unwrap();
+
+    let peer = ClusterPeer {
+        id: "a".to_string(),
+        addr: addr.to_string(),
+    };
+    let (b, _) = TcpBackplane::start(
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        &[peer],
+        API_TOKEN,
+    );
+    b.send("a", &sync("b"));
+    let message = tokio::time::timeout(Duration::from_secs(5), received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(message, NodeMessage::SyncRequest { from, .. } if from == "b"));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), received.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_server_processes_share_documents_over_tcp() {
+    let (a, a_backplane) = (free_addr(), free_addr());
+    let (b, b_backplane) = (free_addr(), free_addr());
+    let _a = spawn_node("a", a, a_backplane, &format!("b={}", b_backplane));
+    let _b = spawn_node("b", b, b_backplane, &format!("a={}", a_backplane));
+    wait_until_ready(a).await;
+    wait_until_ready(b).await;
+    let document = document_owned_by("b", &["a", "b"]);
+
+    let mut alice = connect(a, &format!("{}?user=alice", document)).await;
+    let mut bob = connect(b, &format!("{}?user=bob", document)).await;
+    assert_eq!(next_json(&mut alice).await["type"], "initial");
+    assert_eq!(next_json(&mut bob).await["type"], "initial");
+
+    alice.send(edit_message(0, "over", 0)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["edit"]["insert"], "over");
+    bob.send(edit_message(4, " tcp", 1)).await.unwrap();
+    let edit = next_json(&mut alice).await;
+    assert_eq!(edit["edit"]["insert"], " tcp");
+    assert_eq!(edit["edit"]["version"], 2);
+
+    let (_, body) = http_request(a, "GET", &format!("/documents/{}/content", document)).await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["content"],
+        "over tcp"
+    );
+}
+
+#[tokio::test]
+async fn test_follower_serves_reads_until_promoted() {
+    let (primary, replication) = start_primary().await;
+    let mut alice = connect(primary, "standby?user=alice").await;
+    next_json(&mut alice).await;
+    alice.send(edit_message(0, "hello", 0)).await.unwrap();
+    let mut notes = connect(primary, "blocks/notes?user=alice").await;
+    next_json(&mut notes).await;
+    let import = json!({ "type": "import", "format": "text", "text": "one\ntwo" });
+    notes.send(Message::Text(import.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut notes).await["version"], 1);
+
+    // The follower is sent the documents that existed before it connected.
+    let follower = start_follower(replication).await;
+    let mut bob = connect(follower, "standby?user=bob").await;
+    let initial = next_json(&mut bob).await;
+    assert_eq!(initial["content"], "hello");
+    assert_eq!(initial["version"], 1);
+    let mut blocks = connect(follower, "blocks/notes?user=bob").await;
+    assert_eq!(next_json(&mut blocks).await["blocks"][1]["text"], "two");
+
+    // Then everything the primary changes.
+    alice.send(edit_message(5, " world", 1)).await.unwrap();
+    let edit = next_json(&mut bob).await;
+    assert_eq!(edit["edit"]["insert"], " world");
+    assert_eq!(edit["edit"]["version"], 2);
+
+    // Reads are served, changes refused.
+    let query = json!({ "type": "content_at", "version": 1 });
+    bob.send(Message::Text(query.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["content"], "hello");
+    bob.send(edit_message(0, "!", 2)).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["code"], "read_only");
+    let (status, _) = http_post(
+        follower,
+        "/documents/standby/checkpoints",
+        &json!({ "name": "v1" }),
+    )
+    .await;
+    assert_eq!(status, 409);
+
+    let (status, _) = http_request(primary, "POST", "/promote").await;
+    assert_eq!(status, 409);
+    let (status, _) = http_request(follower, "POST", "/promote").await;
+    assert_eq!(status, 200);
+    let (status, _) = http_request(follower, "POST", "/promote").await;
+    assert_eq!(status, 409);
+
+    // Once promoted, the follower takes changes itself.
+    bob.send(edit_message(11, "!", 2)).await.unwrap();
+    let full_state = json!({ "type": "request_full_state" });
+    bob.send(Message::Text(full_state.to_string()))
+        .await
+        .unwrap();
+    let state = next_json(&mut bob).await;
+    assert_eq!(state["content"], "hello world!");
+    assert_eq!(state["version"], 3);
+}
+
+#[tokio::test]
+async fn test_followers_are_sent_only_the_operations_they_miss() {
+    let (primary, replication) = start_primary().await;
+    let insert = |text: &str| json!({ "position": 0, "insert": text });
+    let edits = json!({ "version": 0, "edits": [insert("a"), insert("b"), insert("c")] });
+    assert_eq!(
+        http_send(primary, "PUT", "/documents/standby", &edits)
+            .await
+            .0,
+        200
+    );
+    let edits = json!({ "version": 0, "edits": [insert("x")] });
+    assert_eq!(
+        http_send(primary, "PUT", "/documents/other", &edits)
+            .await
+            .0,
+        200
+    );
+
+    // A follower that saved `standby` at version 2 and nothing else.
+    let mut stream = TcpStream::connect(replication).await.unwrap();
+    let resume = json!({ "type": "resume", "versions": { "standby": 2 } });
+    let hello = format!("{}\n{}\n", API_TOKEN, resume);
+    stream.write_all(hello.as_bytes()).await.unwrap();
+    let mut lines = tokio::io::BufReader::new(stream).lines();
+    let mut copies = std::collections::HashMap::new();
+    while copies.len() < 2 {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let message: Value = serde_json::from_str(&line).unwrap();
+        copies.insert(message["document"].as_str().unwrap().to_string(), message);
+    }
+    let standby = &copies["standby"];
+    assert_eq!(standby["type"], "op_log");
+    assert_eq!(standby["version"], 3);
+    assert_eq!(standby["ops"].as_array().unwrap().len(), 1);
+    assert_eq!(standby["ops"][0]["text"], "c");
+    assert_eq!(copies["other"]["type"], "snapshot");
+}
+
+#[tokio::test]
+async fn test_follower_resumes_the_replicas_it_saved() {
+    let (primary, replication) = start_primary().await;
+    let edits = json!({ "version": 0, "edits": [{ "position": 0, "insert": "hello" }] });
+    assert_eq!(
+        http_send(primary, "PUT", "/documents/standby", &edits)
+            .await
+            .0,
+        200
+    );
+
+    let data_dir = std::env::temp_dir().join(format!("editor-follower-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let mut saved = DocumentState::new();
+    saved
+        .apply_edit(&Edit {
+            position: 0,
+            insert: Some("hello".to_string()),
+            delete: None,
+            version: 0,
+        })
+        .unwrap();
+    Storage::new(&data_dir)
+        .save_snapshot("standby", &saved)
+        .unwrap();
+    let edits = json!({ "version": 1, "edits": [{ "position": 5, "insert": " world" }] });
+    assert_eq!(
+        http_send(primary, "PATCH", "/documents/standby", &edits)
+            .await
+            .0,
+        200
+    );
+
+    let config = ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        ..test_config()
+    };
+    let follower = start_follower_with(replication, config).await;
+    let mut bob = connect(follower, "standby?user=bob").await;
+    let initial = next_json(&mut bob).await;
+    assert_eq!(initial["content"], "hello world");
+    assert_eq!(initial["version"], 2);
+    // The saved replica took the missing edit rather than diverging.
+    let (_, metrics) = http_request(follower, "GET", "/metrics").await;
+    assert!(metrics.contains("editor_divergences_total 0"));
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+#[tokio::test]
+async fn test_idle_documents_are_unloaded_and_loaded_again() {
+    let data_dir = std::env::temp_dir().join(format!("editor-unload-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let config = ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        idle_timeout: Duration::from_secs(3600),
+        memory_budget: 1,
+        ..test_config()
+    };
+    let state = Arc::new(ServerState::new(config));
+    state.restore().await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, state.clone(), std::future::pending()));
+
+    let mut alice = connect(addr, "kept?user=alice").await;
+    next_json(&mut alice).await;
+    let mut bob = connect(addr, "idle?user=bob").await;
+    next_json(&mut bob).await;
+    bob.send(edit_message(0, "saved", 0)).await.unwrap();
+    let blame = json!({ "type": "blame" });
+    bob.send(Message::Text(blame.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut bob).await["version"], 1);
+    bob.close(None).await.unwrap();
+    while bob.next().await.is_some() {}
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Over the budget, the document nobody is connected to goes.
+    assert_eq!(state.unload_idle().await, 1);
+    let loaded: Vec<String> = state.rooms.read().await.keys().cloned().collect();
+    assert_eq!(loaded, ["kept"]);
+    assert_eq!(state.document_ids().await.unwrap(), ["idle", "kept"]);
+
+    // And comes back when next used.
+    let (status, body) = http_request(addr, "GET", "/documents/idle/content").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["content"],
+        "saved"
+    );
+    let mut carol = connect(addr, "idle?user=carol").await;
+    let initial = next_json(&mut carol).await;
+    assert_eq!(initial["content"], "saved");
+    assert_eq!(initial["version"], 1);
+
+    let (_, metrics) = http_request(addr, "GET", "/metrics").await;
+    assert!(metrics.contains("editor_documents_unloaded_total 1"));
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+#[tokio::test]
+async fn test_block_documents_are_size_limited_and_unloaded() {
+    let data_dir = std::env::temp_dir().join(format!("editor-blocks-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let mut config = ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        idle_timeout: Duration::ZERO,
+        ..test_config()
+    };
+    config.limits.max_document_size = 10;
+    let state = Arc::new(ServerState::new(config));
+    state.restore().await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, state.clone(), std::future::pending()));
+
+    let put = |body: &str| {
+        format!(
+            "PUT /blocks/notes?format=text HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            addr,
+            API_TOKEN,
+            body.len(),
+            body
+        )
+    };
+    let (status, _) = http_exchange(addr, &put("hello")).await;
+    assert_eq!(status, 200);
+    let (status, _) = http_exchange(addr, &put("far too long for the limit")).await;
+    assert_eq!(status, 413);
+    let mut notes = connect(addr, "blocks/notes?user=alice").await;
+    let initial = next_json(&mut notes).await;
+    let block = initial["blocks"][0]["id"].clone();
+    let insert = json!({
+        "type": "block_edit",
+        "op": {"op": "insert_text", "block": block, "position": 5, "text": " world"},
+        "version": initial["version"],
+    });
+    notes.send(Message::Text(insert.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut notes).await["code"], "document_too_large");
+
+    // Idle block documents are saved and dropped like flat ones.
+    assert_eq!(state.unload_idle().await, 0);
+    notes.close(None).await.unwrap();
+    while notes.next().await.is_some() {}
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(state.unload_idle().await, 1);
+    assert!(state.block_rooms.read().await.is_empty());
+    let (status, body) = http_request(addr, "GET", "/blocks/notes?format=text").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "hello");
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+#[tokio::test]
+async fn test_documents_are_not_unloaded_while_a_handle_is_held() {
+    let data_dir = std::env::temp_dir().join(format!("editor-held-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let state = ServerState::new(ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        idle_timeout: Duration::ZERO,
+        ..test_config()
+    });
+
+    let room = state.room("held").await.unwrap();
+    // Clones of the room that are not handles do not keep it loaded.
+    let listed = state.rooms_snapshot().await;
+    assert_eq!(state.unload_idle().await, 0);
+    room.document
+        .write()
+        .await
+        .apply_edit(&Edit {
+            position: 0,
+            insert: Some("kept".to_string()),
+            delete: None,
+            version: 0,
+        })
+        .unwrap();
+    drop(room);
+    assert_eq!(state.unload_idle().await, 1);
+    drop(listed);
+
+    let room = state.find_room("held").await.unwrap();
+    assert_eq!(room.document.read().await.content, "kept");
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+#[tokio::test]
+async fn test_document_ids_cannot_name_files_outside_the_data_dir() {
+    let root = std::env::temp_dir().join(format!("editor-escape-{}", std::process::id()));
+    let data_dir = root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let victim = root.join("victim.json");
+    std::fs::write(&victim, r#"{"content": "secret", "version": 1}"#).unwrap();
+    let addr = start_server(ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        ..test_config()
+    })
+    .await;
+
+    for method in ["GET", "DELETE"] {
+        let (status, _) = http_request(addr, method, "/documents/..%2Fvictim").await;
+        assert_eq!(status, 400);
+    }
+    let (status, _) = http_request(addr, "GET", "/documents/..%2Fvictim/content").await;
+    assert_eq!(status, 400);
+    assert!(victim.exists());
+    assert!(Storage::new(&data_dir).load_snapshot("../victim").is_err());
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test]
+async fn test_documents_with_invalid_ids_are_never_loaded() {
+    let root = std::env::temp_dir().join(format!("editor-leak-{}", std::process::id()));
+    let data_dir = root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(
+        root.join("leak.json"),
+        r#"{"content": "secret", "version": 1}"#,
+    )
+    .unwrap();
+    let state = ServerState::new(ServerConfig {
+        data_dir: Some(data_dir),
+        ..test_config()
+    });
+
+    assert!(state.find_room("../leak").await.is_none());
+    assert!(state.room("../leak").await.is_err());
+    assert!(state.rooms.read().await.is_empty());
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test]
+async fn test_followers_need_the_token_and_get_stored_documents_unloaded() {
+    let data_dir = std::env::temp_dir().join(format!("editor-followers-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(
+        data_dir.join("stored.json"),
+        r#"{"content": "from disk", "version": 1}"#,
+    )
+    .unwrap();
+    let replication = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let replication_addr = replication.local_addr().unwrap();
+    let followers = Arc::new(Followers::new());
+    let cluster = Cluster::primary(PRIMARY, followers.clone());
+    let config = ServerConfig {
+        data_dir: Some(data_dir.clone()),
+        ..test_config()
+    };
+    let state = Arc::new(ServerState::new(config).with_cluster(cluster));
+    state.restore().await.unwrap();
+    let (requests, _messages) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(serve_followers(
+        replication,
+        state.clone(),
+        followers,
+        requests,
+    ));
+
+    let mut stranger = TcpStream::connect(replication_addr).await.unwrap();
+    stranger.write_all(b"wrong-token\n").await.unwrap();
+    let mut sent = String::new();
+    stranger.read_to_string(&mut sent).await.unwrap();
+    assert_eq!(sent, "");
+
+    let follower = TcpStream::connect(replication_addr).await.unwrap();
+    let (reader, mut writer) = follower.into_split();
+    let resume = json!({ "type": "resume", "versions": {} });
+    writer
+        .write_all(format!("{}\n{}\n", API_TOKEN, resume).as_bytes())
+        .await
+        .unwrap();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    let copy: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+    assert_eq!(copy["type"], "snapshot");
+    assert_eq!(copy["document"], "stored");
+    assert_eq!(copy["snapshot"]["content"], "from disk");
+    // Copied straight from storage, without loading it.
+    assert!(state.rooms.read().await.is_empty());
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+/// Collects the spans the server exports.
+#[cfg(feature = "otel")]
+#[derive(Debug, Clone, Default)]
+struct SpanCollector(Arc<std::sync::Mutex<Vec<opentelemetry_sdk::trace::SpanData>>>);
+
+#[cfg(feature = "otel")]
+impl opentelemetry_sdk::trace::SpanExporter for SpanCollector {
+    async fn export(
+        &self,
+        batch: Vec<opentelemetry_sdk::trace::SpanData>,
+    ) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.0.lock().unwrap().extend(batch);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_client_edits_are_applied_under_their_trace() {
+    let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+    #[cfg(feature = "otel")]
+    let (spans, _subscriber) = {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        let spans = SpanCollector::default();
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        // Tests run on a single thread, so the server's tasks use it too.
+        (spans, tracing::subscriber::set_default(subscriber))
+    };
+
+    let addr = start_server(test_config()).await;
+    let mut alice = connect(addr, "traced?user=alice").await;
+    let Some(Ok(Message::Text(initial))) = alice.next().await else {
+        panic!("Expected the initial state");
+    };
+    let version = client::document_version(&initial).unwrap();
+    let edit = client::parse_user_input("0,hello").unwrap();
+    let id = client::OpId {
+        client_id: "cli-1",
+        seq: 1,
+    };
+    let message = client::edit_message(&edit, version, Some(id), Some(traceparent)).unwrap();
+    alice.send(Message::Text(message)).await.unwrap();
+    let Some(Ok(Message::Text(ack))) = alice.next().await else {
+        panic!("Expected an ack");
+    };
+    assert_eq!(client::document_version(&ack), Some(version + 1));
+    let (_, body) = http_request(addr, "GET", "/documents/traced/content").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["content"],
+        "hello"
+    );
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::{SpanId, TraceId};
+
+        // The edit span ends once the ack is on its way.
+        let find = |name: &str| {
+            spans
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|span| span.name == name)
+                .cloned()
+        };
+        let mut edit_span = find("edit");
+        for _ in 0..50 {
+            if edit_span.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            edit_span = find("edit");
+        }
+        let edit_span = edit_span.expect("the edit span was not exported");
+        let apply_span = find("apply_edit").expect("the apply_edit span was not exported");
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+        assert_eq!(edit_span.span_context.trace_id(), trace_id);
+        assert_eq!(
+            edit_span.parent_span_id,
+            SpanId::from_hex("b7ad6b7169203331").unwrap()
+        );
+        assert!(edit_span.parent_span_is_remote);
+        assert_eq!(apply_span.span_context.trace_id(), trace_id);
+        assert_eq!(apply_span.parent_span_id, edit_span.span_context.span_id());
+    }
+}
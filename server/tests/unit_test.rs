@@ -1,4 +1,30 @@
-use collaborative_editor_server::{DocumentState, Edit};
+use collaborative_editor_server::backplane::{owner, Backplane, Cluster, LoopbackHub, NodeMessage};
+use collaborative_editor_server::blocks::{
+    from_markdown, BlockDocument, BlockKind, BlockOp, INVALID_POSITION, NOTHING_TO_MERGE,
+};
+use collaborative_editor_server::checkpoint::{
+    edit_between, Checkpoints, MAX_CHECKPOINTS, TOO_MANY_CHECKPOINTS,
+};
+use collaborative_editor_server::checksum::{checksum, CHECKSUM_INTERVAL};
+use collaborative_editor_server::client_ops::OpId;
+use collaborative_editor_server::codec::{compress, Codec, CBOR_PROTOCOL, MSGPACK_PROTOCOL};
+use collaborative_editor_server::comments::NOT_AUTHOR;
+use collaborative_editor_server::config::{ClusterPeer, LogFormat};
+use collaborative_editor_server::diff::{diff_document, DiffEnd};
+use collaborative_editor_server::formatting::{AttributeChanges, FormatEdit, INVALID_FORMAT};
+use collaborative_editor_server::history::{
+    History, HistoryPoint, HISTORY_UNAVAILABLE, MAX_HISTORY_OPS, SNAPSHOT_INTERVAL,
+};
+use collaborative_editor_server::limits::{TokenBucket, UserRateLimits};
+use collaborative_editor_server::ot::{transform, Op};
+use collaborative_editor_server::replication::is_query;
+use collaborative_editor_server::room::is_valid_room_id;
+use collaborative_editor_server::storage::Snapshot;
+use collaborative_editor_server::suggestions::SUGGESTION_ORPHANED;
+use collaborative_editor_server::undo::{UndoStack, NOTHING_TO_REDO, NOTHING_TO_UNDO};
+use collaborative_editor_server::{DocumentState, Edit, DOCUMENT_TOO_LARGE, INVALID_PENDING};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_apply_edit_insert_success() {
@@ -101,6 +127,39 @@ fn test_apply_edit_invalid_delete_range() {
     assert_eq!(doc.version, 0); // Version should remain unchanged
 }
 
+#[test]
+fn test_apply_edit_rejects_overflowing_delete() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "Hello", 0)).unwrap();
+
+    for insert in [None, Some("x".to_string())] {
+        let edit = Edit {
+            position: 1,
+            insert,
+            delete: Some(usize::MAX),
+            version: 1,
+        };
+        assert_eq!(
+            doc.apply_edit(&edit),
+            Err("Delete range is not valid UTF-8 boundaries.")
+        );
+        assert_eq!(
+            doc.rebase_pending(1, &[edit], "alice", None),
+            Err(INVALID_PENDING)
+        );
+    }
+    let bold = AttributeChanges {
+        bold: Some(true),
+        ..AttributeChanges::default()
+    };
+    assert_eq!(
+        doc.apply_format(&format(1, usize::MAX, bold, 1)),
+        Err(INVALID_FORMAT)
+    );
+    assert_eq!(doc.content, "Hello");
+    assert_eq!(doc.version, 1);
+}
+
 #[test]
 fn test_apply_edit_consecutive_edits() {
     let mut doc = DocumentState::new();
@@ -125,3 +184,988 @@ fn test_apply_edit_consecutive_edits() {
     assert_eq!(doc.content, "Hello, World!");
     assert_eq!(doc.version, 2);
 }
+
+#[test]
+fn test_apply_edit_document_size_limit() {
+    let mut doc = DocumentState::with_max_size(8);
+    doc.content = "Hello".to_string();
+
+    let edit = Edit {
+        position: 5,
+        insert: Some(", World!".to_string()),
+        delete: None,
+        version: 0,
+    };
+
+    let result = doc.apply_edit(&edit);
+    assert_eq!(result.unwrap_err(), DOCUMENT_TOO_LARGE);
+    assert_eq!(doc.content, "Hello"); // Content should remain unchanged
+    assert_eq!(doc.version, 0); // Version should remain unchanged
+}
+
+#[test]
+fn test_token_bucket_refills_over_time() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(2.0, 1.0);
+
+    assert!(bucket.try_acquire_at(start));
+    assert!(bucket.try_acquire_at(start));
+    assert!(!bucket.try_acquire_at(start)); // Burst exhausted
+
+    assert!(bucket.try_acquire_at(start + Duration::from_secs(1)));
+    assert!(!bucket.try_acquire_at(start + Duration::from_secs(1)));
+}
+
+#[test]
+fn test_user_rate_limits_forget_refilled_users() {
+    let start = Instant::now();
+    let later = start + Duration::from_secs(2);
+    let limits = UserRateLimits::new(2.0, 1.0);
+    for i in 0..5000 {
+        assert!(limits.try_acquire_at(&format!("early-{}", i), start));
+    }
+    assert_eq!(limits.tracked_users(), 5000);
+
+    // By the time the map has doubled again, the early users' buckets
+    // have refilled and they are forgotten. Recent users are kept.
+    assert!(limits.try_acquire_at("alice", later));
+    for i in 0..4000 {
+        limits.try_acquire_at(&format!("later-{}", i), later);
+    }
+    assert!(limits.tracked_users() < 5000);
+    assert!(limits.try_acquire_at("alice", later));
+    assert!(!limits.try_acquire_at("alice", later));
+}
+
+#[test]
+fn test_is_valid_room_id() {
+    assert!(is_valid_room_id("notes"));
+    assert!(is_valid_room_id("meeting-2024_01.draft"));
+    assert!(!is_valid_room_id(""));
+    assert!(!is_valid_room_id("..")); // Must not escape the data directory
+    assert!(!is_valid_room_id("a/b"));
+    assert!(!is_valid_room_id(&"x".repeat(65)));
+}
+
+#[test]
+fn test_log_format_from_str() {
+    assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+    assert_eq!("Text".parse::<LogFormat>(), Ok(LogFormat::Text));
+    assert!("yaml".parse::<LogFormat>().is_err());
+}
+
+fn insert(position: usize, text: &str, version: usize) -> Edit {
+    Edit {
+        position,
+        insert: Some(text.to_string()),
+        delete: None,
+        version,
+    }
+}
+
+#[test]
+fn test_transform_delete_around_concurrent_insert() {
+    let delete = Op::Delete {
+        position: 0,
+        text: "abcd".to_string(),
+    };
+    let concurrent = Op::Insert {
+        position: 2,
+        text: "XY".to_string(),
+    };
+
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "abXYcd", 0)).unwrap();
+    for op in transform(&delete, &concurrent) {
+        doc.apply_edit(&op.to_edit(doc.version)).unwrap();
+    }
+    assert_eq!(doc.content, "XY");
+}
+
+#[test]
+fn test_undo_reverts_only_own_edit() {
+    let mut doc = DocumentState::new();
+    let mut mine = UndoStack::new();
+    doc.apply_edit(&insert(0, "hello", 0)).unwrap();
+    mine.record(0);
+    // A collaborator inserts before our text.
+    doc.apply_edit(&insert(0, ">> ", 1)).unwrap();
+
+    let edits = mine.undo(&mut doc, "me").unwrap();
+    assert_eq!(edits.len(), 1);
+    assert_eq!(doc.content, ">> ");
+    assert_eq!(doc.version, 3);
+    assert_eq!(mine.undo(&mut doc, "me"), Err(NOTHING_TO_UNDO));
+
+    mine.redo(&mut doc, "me").unwrap();
+    assert_eq!(doc.content, ">> hello");
+    assert_eq!(mine.redo(&mut doc, "me"), Err(NOTHING_TO_REDO));
+}
+
+#[test]
+fn test_undo_that_fails_partway_changes_nothing() {
+    let mut doc = DocumentState::new();
+    let mut mine = UndoStack::new();
+    doc.apply_edit(&insert(0, "abcdef", 0)).unwrap();
+    let replace = Edit {
+        position: 1,
+        insert: Some("ZZ".to_string()),
+        delete: Some(4),
+        version: 1,
+    };
+    doc.apply_edit(&replace).unwrap();
+    mine.record(1);
+    // A collaborator types between the two replaced characters, so undoing
+    // deletes them separately before inserting the original text.
+    doc.apply_edit(&insert(2, "Q", 2)).unwrap();
+    assert_eq!(doc.content, "aZQZf");
+    doc.max_size = Some(6);
+
+    assert_eq!(mine.undo(&mut doc, "me"), Err(DOCUMENT_TOO_LARGE));
+    assert_eq!(doc.content, "aZQZf");
+    assert_eq!(doc.version, 3);
+
+    doc.max_size = None;
+    mine.undo(&mut doc, "me").unwrap();
+    assert_eq!(doc.content, "aQbcdef");
+}
+
+#[test]
+fn test_history_content_at_version_and_time() {
+    let mut doc = DocumentState::new();
+    for version in 0..SNAPSHOT_INTERVAL + 5 {
+        doc.apply_edit_by(&insert(version, "a", version), "alice")
+            .unwrap();
+    }
+
+    assert_eq!(doc.content_at(0).unwrap(), "");
+    assert_eq!(doc.content_at(3).unwrap(), "aaa");
+    assert_eq!(
+        doc.content_at(SNAPSHOT_INTERVAL + 2).unwrap().len(),
+        SNAPSHOT_INTERVAL + 2
+    );
+    assert_eq!(doc.content_at(doc.version + 1), None);
+    assert_eq!(doc.applied_op(0).unwrap().author.as_deref(), Some("alice"));
+
+    let last = doc.applied_op(doc.version - 1).unwrap().timestamp_ms;
+    let (version, content) = doc.history.checkout(HistoryPoint::Time(last)).unwrap();
+    assert_eq!(version, doc.version);
+    assert_eq!(content, doc.content);
+    assert!(doc.history.checkout(HistoryPoint::Time(0)).is_err());
+}
+
+#[test]
+fn test_history_keeps_only_recent_ops() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "hello", 0)).unwrap();
+    let bold = AttributeChanges {
+        bold: Some(true),
+        ..AttributeChanges::default()
+    };
+    doc.apply_format_by(&format(0, 3, bold, 1), "alice")
+        .unwrap();
+    let total = MAX_HISTORY_OPS + 2 * SNAPSHOT_INTERVAL;
+    for version in 2..total {
+        doc.apply_edit_by(&insert(doc.content.len(), "a", version), "bob")
+            .unwrap();
+    }
+
+    let first_version = doc.history.first_version();
+    assert_eq!(doc.history.latest_version(), total);
+    assert_eq!(first_version, total - MAX_HISTORY_OPS);
+    assert_eq!(doc.content_at(0), None);
+    assert_eq!(
+        doc.rebase_pending(1, &[insert(0, "x", 1)], "alice", None),
+        Err(HISTORY_UNAVAILABLE)
+    );
+    let first = doc.content_at(first_version).unwrap();
+    assert_eq!(first.len(), first_version + 3);
+
+    // Formatting and authorship of the dropped edits survive a reload.
+    let mut copy = DocumentState::new();
+    let saved = serde_json::to_string(&Snapshot::of(&doc)).unwrap();
+    serde_json::from_str::<Snapshot>(&saved)
+        .unwrap()
+        .restore_into(&mut copy)
+        .unwrap();
+    assert_eq!(copy.content, doc.content);
+    assert_eq!(copy.formatting.spans(), doc.formatting.spans());
+    assert_eq!(copy.formatting.spans()[0].end, 3);
+    assert_eq!(copy.authorship.spans(), doc.authorship.spans());
+}
+
+#[test]
+fn test_restore_history_replays_saved_ops() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "hello", 0)).unwrap();
+    doc.apply_edit(&insert(5, " world", 1)).unwrap();
+
+    let saved = serde_json::to_string(&doc.history).unwrap();
+    let mut restored = DocumentState::new();
+    restored
+        .restore_history(serde_json::from_str(&saved).unwrap())
+        .unwrap();
+    assert_eq!(restored.content, "hello world");
+    assert_eq!(restored.version, 2);
+    assert_eq!(restored.content_at(1).unwrap(), "hello");
+}
+
+#[test]
+fn test_checkpoints_are_capped_per_document() {
+    let mut checkpoints = Checkpoints::new();
+    for i in 0..MAX_CHECKPOINTS {
+        checkpoints
+            .create(&format!("v{}", i), i, "text", None)
+            .unwrap();
+    }
+    assert_eq!(
+        checkpoints.create("one more", 0, "text", None),
+        Err(TOO_MANY_CHECKPOINTS)
+    );
+    checkpoints.delete("v0").unwrap();
+    assert!(checkpoints.create("one more", 0, "text", None).is_ok());
+}
+
+#[test]
+fn test_edit_between_replaces_differing_middle() {
+    let edit = edit_between("naïve café", "naïve tea café", 3).unwrap();
+    assert_eq!(edit.position, "naïve ".len());
+    assert_eq!(edit.insert.as_deref(), Some("tea "));
+    assert_eq!(edit.delete, None);
+
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "the quick fox", 0)).unwrap();
+    let edit = edit_between(&doc.content, "the slow fox", doc.version).unwrap();
+    doc.apply_edit(&edit).unwrap();
+    assert_eq!(doc.content, "the slow fox");
+    assert_eq!(edit_between("same", "same", 0), None);
+}
+
+#[test]
+fn test_undo_replace_after_concurrent_insert() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "hello world", 0)).unwrap();
+    let mut mine = UndoStack::new();
+    let replace = Edit {
+        position: 6,
+        insert: Some("there".to_string()),
+        delete: Some(5),
+        version: 1,
+    };
+    doc.apply_edit(&replace).unwrap();
+    mine.record(1);
+    assert_eq!(doc.content, "hello there");
+
+    doc.apply_edit(&insert(0, "oh, ", 2)).unwrap();
+    mine.undo(&mut doc, "me").unwrap();
+    assert_eq!(doc.content, "oh, hello world");
+}
+
+#[test]
+fn test_diff_attributes_changed_lines() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit_by(&insert(0, "one\ntwo\nthree\n", 0), "alice")
+        .unwrap();
+    doc.apply_edit_by(&insert(4, "2", 1), "bob").unwrap();
+    let delete = Edit {
+        position: 0,
+        insert: None,
+        delete: Some(4),
+        version: 2,
+    };
+    doc.apply_edit_by(&delete, "carol").unwrap();
+    assert_eq!(doc.content, "2two\nthree\n");
+
+    let diff = diff_document(&doc, &DiffEnd::Version(1), &DiffEnd::Version(3)).unwrap();
+    assert!(diff.unified.starts_with("--- version 1\n+++ version 3\n"));
+    assert!(diff.unified.contains("-one\n-two\n+2two\n"));
+
+    let lines = &diff.hunks[0].lines;
+    let deleted: Vec<_> = lines.iter().filter(|line| line.tag == "delete").collect();
+    let inserted: Vec<_> = lines.iter().filter(|line| line.tag == "insert").collect();
+    assert!(deleted[0].authors.contains("carol"));
+    assert!(inserted[0].authors.contains("bob"));
+    assert!(inserted[0]
+        .segments
+        .iter()
+        .any(|segment| segment.changed && segment.text == "2"));
+
+    doc.checkpoints
+        .create("start", 1, "one\ntwo\nthree\n", None)
+        .unwrap();
+    let from_checkpoint = diff_document(
+        &doc,
+        &DiffEnd::Checkpoint("start".to_string()),
+        &DiffEnd::Version(3),
+    )
+    .unwrap();
+    assert_eq!(from_checkpoint.unified, diff.unified);
+}
+
+#[test]
+fn test_blame_tracks_authors_through_edits() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit_by(&insert(0, "hello world", 0), "alice")
+        .unwrap();
+    doc.apply_edit_by(&insert(5, ", dear", 1), "bob").unwrap();
+    let delete = Edit {
+        position: 0,
+        insert: None,
+        delete: Some(2),
+        version: 2,
+    };
+    doc.apply_edit_by(&delete, "carol").unwrap();
+    assert_eq!(doc.content, "llo, dear world");
+
+    let spans: Vec<_> = doc
+        .authorship
+        .spans()
+        .into_iter()
+        .map(|span| {
+            (
+                span.start..span.end,
+                span.author.unwrap(),
+                span.version.unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            (0..3, "alice".to_string(), 0),
+            (3..9, "bob".to_string(), 1),
+            (9..15, "alice".to_string(), 0),
+        ]
+    );
+
+    let mut restored = DocumentState::new();
+    restored.restore_history(doc.history.clone()).unwrap();
+    assert_eq!(restored.authorship.spans(), doc.authorship.spans());
+}
+
+#[test]
+fn test_comment_anchor_follows_edits() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "the quick brown fox", 0))
+        .unwrap();
+    let id = doc
+        .comments
+        .create(&doc.content, 4, 15, Some("alice"), "Too wordy?")
+        .unwrap()
+        .id;
+
+    // Before the anchor: shifts it. Inside: grows it. At its end: outside.
+    doc.apply_edit(&insert(0, "> ", 1)).unwrap();
+    doc.apply_edit(&insert(11, "ly", 2)).unwrap();
+    doc.apply_edit(&insert(19, "!", 3)).unwrap();
+    let thread = &doc.comments.threads()[0];
+    assert_eq!(
+        &doc.content[thread.anchor.start..thread.anchor.end],
+        "quickly brown"
+    );
+
+    let delete = Edit {
+        position: 2,
+        insert: None,
+        delete: Some("the quickly ".len()),
+        version: 4,
+    };
+    doc.apply_edit(&delete).unwrap();
+    let thread = &doc.comments.threads()[0];
+    assert_eq!(
+        &doc.content[thread.anchor.start..thread.anchor.end],
+        "brown"
+    );
+    assert!(!thread.anchor.orphaned);
+
+    let replace = Edit {
+        position: 2,
+        insert: Some("red".to_string()),
+        delete: Some("brown".len()),
+        version: 5,
+    };
+    doc.apply_edit(&replace).unwrap();
+    assert_eq!(doc.content, "> red! fox");
+    let thread = &doc.comments.threads()[0];
+    assert!(thread.anchor.orphaned);
+    assert_eq!(thread.anchor.start, thread.anchor.end);
+
+    assert_eq!(doc.comments.delete(id, None, Some("bob")), Err(NOT_AUTHOR));
+    assert_eq!(doc.comments.delete(id, None, Some("alice")), Ok(None));
+    assert!(doc.comments.threads().is_empty());
+}
+
+#[test]
+fn test_suggestion_follows_edits_until_accepted() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "colour of the sky", 0)).unwrap();
+    let replace = Edit {
+        position: 0,
+        insert: Some("color".to_string()),
+        delete: Some("colour".len()),
+        version: 1,
+    };
+    let id = doc.suggest(&replace, "bob").unwrap().id;
+    assert_eq!(doc.content, "colour of the sky");
+    assert_eq!(doc.version, 1);
+
+    doc.apply_edit_by(&insert(0, "The ", 1), "alice").unwrap();
+    let suggestion = &doc.suggestions.list()[0];
+    assert_eq!((suggestion.anchor.start, suggestion.anchor.end), (4, 10));
+
+    let edit = doc.accept_suggestion(id).unwrap();
+    assert_eq!(edit.version, 2);
+    assert_eq!(doc.content, "The color of the sky");
+    assert!(doc.suggestions.list().is_empty());
+    assert_eq!(doc.authorship.spans()[1].author.as_deref(), Some("bob"));
+
+    let delete = Edit {
+        position: 16,
+        insert: None,
+        delete: Some(" sky".len()),
+        version: 3,
+    };
+    let id = doc.suggest(&delete, "bob").unwrap().id;
+    let overlapping = Edit {
+        position: 9,
+        insert: None,
+        delete: Some(" of the sky".len()),
+        version: 3,
+    };
+    doc.apply_edit(&overlapping).unwrap();
+    assert_eq!(doc.accept_suggestion(id), Err(SUGGESTION_ORPHANED));
+}
+
+fn format(
+    position: usize,
+    length: usize,
+    attributes: AttributeChanges,
+    version: usize,
+) -> FormatEdit {
+    FormatEdit {
+        position,
+        length,
+        attributes,
+        version,
+    }
+}
+
+#[test]
+fn test_formatting_expands_at_boundaries_except_links() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "hello world", 0)).unwrap();
+    let bold = AttributeChanges {
+        bold: Some(true),
+        ..AttributeChanges::default()
+    };
+    let link = AttributeChanges {
+        link: Some("https://example.com".to_string()),
+        ..AttributeChanges::default()
+    };
+    doc.apply_format_by(&format(0, 5, bold, 1), "alice")
+        .unwrap();
+    doc.apply_format_by(&format(6, 5, link, 2), "alice")
+        .unwrap();
+    assert_eq!(doc.content, "hello world");
+    assert_eq!(doc.version, 3);
+
+    // Typing after the bold word stays bold, typing after the link does not.
+    doc.apply_edit(&insert(5, ",", 3)).unwrap();
+    doc.apply_edit(&insert(12, "!", 4)).unwrap();
+    doc.apply_edit(&insert(9, "-", 5)).unwrap();
+    assert_eq!(doc.content, "hello, wo-rld!");
+    let spans = doc.formatting.spans();
+    assert_eq!(spans.len(), 2);
+    assert_eq!((spans[0].start, spans[0].end), (0, 6));
+    assert!(spans[0].attributes.bold);
+    assert_eq!((spans[1].start, spans[1].end), (7, 13));
+    assert_eq!(
+        spans[1].attributes.link.as_deref(),
+        Some("https://example.com")
+    );
+
+    let unbold = AttributeChanges {
+        bold: Some(false),
+        ..AttributeChanges::default()
+    };
+    doc.apply_format_by(&format(0, 6, unbold, 6), "bob")
+        .unwrap();
+    assert_eq!(doc.formatting.spans().len(), 1);
+
+    let mut restored = DocumentState::new();
+    restored.restore_history(doc.history.clone()).unwrap();
+    assert_eq!(restored.formatting.spans(), doc.formatting.spans());
+
+    let nothing = AttributeChanges::default();
+    assert_eq!(
+        doc.apply_format_by(&format(0, 1, nothing, 7), "bob"),
+        Err(INVALID_FORMAT)
+    );
+}
+
+#[test]
+fn test_transform_format_past_concurrent_edits() {
+    let attributes = AttributeChanges {
+        italic: Some(true),
+        ..AttributeChanges::default()
+    };
+    let format = Op::Format {
+        position: 4,
+        length: 5,
+        attributes: attributes.clone(),
+    };
+    let inside = Op::Insert {
+        position: 6,
+        text: "xx".to_string(),
+    };
+    let overlapping = Op::Delete {
+        position: 2,
+        text: "abcd".to_string(),
+    };
+    assert_eq!(
+        transform(&format, &inside),
+        vec![Op::Format {
+            position: 4,
+            length: 7,
+            attributes: attributes.clone(),
+        }]
+    );
+    assert_eq!(
+        transform(&format, &overlapping),
+        vec![Op::Format {
+            position: 2,
+            length: 3,
+            attributes,
+        }]
+    );
+    assert_eq!(transform(&inside, &format), vec![inside.clone()]);
+}
+
+#[test]
+fn test_undo_format_restores_replaced_formatting() {
+    let mut doc = DocumentState::new();
+    let mut mine = UndoStack::new();
+    doc.apply_edit(&insert(0, "hello world", 0)).unwrap();
+    let italic = AttributeChanges {
+        italic: Some(true),
+        ..AttributeChanges::default()
+    };
+    doc.apply_format_by(&format(0, 5, italic, 1), "them")
+        .unwrap();
+    let bold_upright = AttributeChanges {
+        bold: Some(true),
+        italic: Some(false),
+        ..AttributeChanges::default()
+    };
+    doc.apply_format_by(&format(3, 5, bold_upright, 2), "me")
+        .unwrap();
+    mine.record(2);
+    // A collaborator types inside the formatted text.
+    doc.apply_edit(&insert(4, "xx", 3)).unwrap();
+
+    let changes = mine.undo(&mut doc, "me").unwrap();
+    assert_eq!(changes.len(), 2);
+    let spans = doc.formatting.spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!((spans[0].start, spans[0].end), (0, 7));
+    assert!(spans[0].attributes.italic && !spans[0].attributes.bold);
+
+    mine.redo(&mut doc, "me").unwrap();
+    let spans = doc.formatting.spans();
+    assert_eq!(spans.len(), 2);
+    assert_eq!((spans[0].start, spans[0].end), (0, 3));
+    assert!(spans[0].attributes.italic);
+    assert_eq!((spans[1].start, spans[1].end), (3, 10));
+    assert!(spans[1].attributes.bold && !spans[1].attributes.italic);
+}
+
+#[test]
+fn test_block_split_merge_move_and_set_kind() {
+    let mut doc = BlockDocument::new();
+    doc.replace_all(from_markdown("# Groceries and chores"))
+        .unwrap();
+    let heading = doc.blocks[0].id;
+
+    let mut split = BlockOp::Split {
+        block: heading,
+        position: "Groceries".len(),
+        new_block: 0,
+    };
+    doc.apply(&mut split, 1).unwrap();
+    let BlockOp::Split { new_block, .. } = split else {
+        unreachable!()
+    };
+    assert_eq!(doc.blocks[0].text, "Groceries");
+    assert_eq!(doc.blocks[1].text, " and chores");
+    assert_eq!(doc.blocks[1].kind, BlockKind::Paragraph);
+
+    let mut set_kind: BlockOp = serde_json::from_value(serde_json::json!({
+        "op": "set_kind",
+        "block": new_block,
+        "kind": "bullet",
+        "depth": 1,
+    }))
+    .unwrap();
+    doc.apply(&mut set_kind, 2).unwrap();
+    assert_eq!(
+        (&doc.blocks[1].kind, doc.blocks[1].depth),
+        (&BlockKind::Bullet, 1)
+    );
+
+    let mut to_top = BlockOp::Move {
+        block: new_block,
+        after: None,
+    };
+    doc.apply(&mut to_top, 3).unwrap();
+    assert_eq!(doc.blocks[0].id, new_block);
+    assert_eq!(
+        doc.apply(&mut BlockOp::Merge { block: new_block }, 4),
+        Err(NOTHING_TO_MERGE)
+    );
+
+    doc.apply(&mut BlockOp::Merge { block: heading }, 4)
+        .unwrap();
+    assert_eq!(doc.blocks.len(), 1);
+    assert_eq!(doc.to_plain_text(), " and choresGroceries");
+    assert_eq!(
+        doc.apply(&mut BlockOp::DeleteBlock { block: heading }, 4),
+        Err("Version mismatch")
+    );
+}
+
+#[test]
+fn test_block_delete_rejects_overflowing_range() {
+    let mut doc = BlockDocument::new();
+    doc.replace_all(from_markdown("hello")).unwrap();
+    let mut delete = BlockOp::DeleteText {
+        block: doc.blocks[0].id,
+        position: 2,
+        length: usize::MAX,
+    };
+    assert_eq!(doc.apply(&mut delete, 1), Err(INVALID_POSITION));
+    assert_eq!(doc.to_plain_text(), "hello");
+}
+
+#[test]
+fn test_block_markdown_round_trip() {
+    let markdown = "# Shopping\n\n- milk\n  - oat\n1. eggs\n\n> be quick\n\n```\nfn main() {}\n```";
+    let mut doc = BlockDocument::new();
+    doc.replace_all(from_markdown(markdown)).unwrap();
+    let kinds: Vec<_> = doc.blocks.iter().map(|block| &block.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            &BlockKind::Heading { level: 1 },
+            &BlockKind::Bullet,
+            &BlockKind::Bullet,
+            &BlockKind::Numbered,
+            &BlockKind::Quote,
+            &BlockKind::Code,
+        ]
+    );
+    assert_eq!(doc.blocks[2].depth, 1);
+    assert_eq!(doc.to_markdown(), markdown);
+    assert_eq!(
+        doc.to_plain_text(),
+        "Shopping\nmilk\noat\neggs\nbe quick\nfn main() {}"
+    );
+}
+
+#[test]
+fn test_codec_negotiation_picks_first_supported_protocol() {
+    assert_eq!(Codec::negotiate(None), Some(Codec::Json));
+    assert_eq!(
+        Codec::negotiate(Some("chat, editor.cbor, editor.msgpack")),
+        Some(Codec::Cbor)
+    );
+    assert_eq!(Codec::negotiate(Some("chat, v2.example")), None);
+    assert_eq!(Codec::MessagePack.protocol(), MSGPACK_PROTOCOL);
+    assert_eq!(Codec::Cbor.protocol(), CBOR_PROTOCOL);
+}
+
+#[test]
+fn test_binary_codecs_round_trip_messages() {
+    let message = serde_json::json!({
+        "type": "edit",
+        "edit": { "position": 3, "insert": "héllo", "delete": null, "version": 7 },
+    });
+    for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+        let encoded = codec.encode(&message);
+        assert_eq!(encoded.is_binary(), codec != Codec::Json);
+        assert_eq!(codec.decode(&encoded).unwrap(), message);
+    }
+    // Text frames are read as JSON whatever the connection negotiated.
+    let text = Codec::Json.encode(&message);
+    assert_eq!(Codec::Cbor.decode(&text).unwrap(), message);
+    assert!(Codec::Json
+        .decode(&Codec::MessagePack.encode(&message))
+        .is_err());
+}
+
+#[test]
+fn test_compress_only_messages_over_threshold() {
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let small = Message::Text("{\"type\":\"ack\"}".to_string());
+    assert_eq!(compress(small.clone(), 64), small);
+
+    let content = "all work and no play ".repeat(100);
+    let large = Codec::Json.encode(&serde_json::json!({ "content": content }));
+    let Message::Binary(bytes) = compress(large.clone(), 64) else {
+        panic!("Expected a compressed binary message");
+    };
+    assert_eq!(bytes[0], 0x78);
+    assert!(bytes.len() < large.len() / 10);
+    let mut decompressed = String::new();
+    ZlibDecoder::new(bytes.as_slice())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, large.into_text().unwrap());
+}
+
+#[test]
+fn test_rebase_pending_edits_past_concurrent_ones() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "hello world", 0)).unwrap();
+    // While a client is offline, another inserts into the middle.
+    doc.apply_edit_by(&insert(6, "big ", 1), "bob").unwrap();
+
+    // The offline client added a comma, then deleted "world", both on top
+    // of version 1.
+    let pending = [
+        insert(5, ",", 1),
+        Edit {
+            position: 7,
+            insert: None,
+            delete: Some(5),
+            version: 2,
+        },
+    ];
+    let applied = doc.rebase_pending(1, &pending, "alice", None).unwrap();
+    assert_eq!(applied.len(), 2);
+    assert_eq!(doc.content, "hello, big ");
+    assert_eq!(doc.version, 4);
+
+    // Concurrent inserts at the same place keep the earlier one first.
+    doc.apply_edit(&insert(11, "?", 4)).unwrap();
+    doc.rebase_pending(4, &[insert(11, "!", 4)], "alice", None)
+        .unwrap();
+    assert_eq!(doc.content, "hello, big ?!");
+}
+
+#[test]
+fn test_rebase_pending_rejects_unknown_bases() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "abc", 0)).unwrap();
+    assert_eq!(
+        doc.rebase_pending(0, &[insert(5, "x", 0)], "alice", None),
+        Err(INVALID_PENDING)
+    );
+    assert_eq!(
+        doc.rebase_pending(7, &[insert(0, "x", 7)], "alice", None),
+        Err(HISTORY_UNAVAILABLE)
+    );
+    assert_eq!(doc.version, 1);
+}
+
+#[test]
+fn test_apply_edits_is_all_or_nothing() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "abc", 0)).unwrap();
+    // The second edit is past the end of the first's result.
+    let edits = [insert(3, "d", 1), insert(9, "e", 1)];
+    assert_eq!(doc.apply_edits(1, &edits, None), Err(INVALID_PENDING));
+    assert_eq!(doc.content, "abc");
+
+    let edits = [insert(3, "d", 1), insert(4, "e", 1)];
+    let applied = doc.apply_edits(1, &edits, Some("ci")).unwrap();
+    assert_eq!(applied.len(), 2);
+    assert_eq!(doc.content, "abcde");
+    assert_eq!(doc.version, 3);
+}
+
+#[test]
+fn test_tagged_edits_apply_once() {
+    let mut doc = DocumentState::new();
+    let id = |seq| OpId {
+        client_id: "tab-1".to_string(),
+        seq,
+    };
+    assert_eq!(
+        doc.apply_edit_once(&insert(0, "a", 0), "alice", &id(1)),
+        Ok(true)
+    );
+    // The retry arrives after the client has already moved on.
+    assert_eq!(
+        doc.apply_edit_once(&insert(0, "a", 0), "alice", &id(1)),
+        Ok(false)
+    );
+    // A rejected edit does not use up its sequence number.
+    assert!(doc
+        .apply_edit_once(&insert(0, "b", 0), "alice", &id(2))
+        .is_err());
+    assert_eq!(
+        doc.apply_edit_once(&insert(1, "b", 1), "alice", &id(2)),
+        Ok(true)
+    );
+    assert_eq!(doc.content, "ab");
+    assert_eq!(doc.client_ops.last("tab-1").unwrap().version, 2);
+}
+
+#[test]
+fn test_rebase_pending_skips_edits_already_applied() {
+    let mut doc = DocumentState::new();
+    let id = |seq| OpId {
+        client_id: "tab-1".to_string(),
+        seq,
+    };
+    doc.apply_edit(&insert(0, "ab", 0)).unwrap();
+    // The first of two pending edits reached the server before the
+    // connection dropped, but its ack did not reach the client.
+    doc.apply_edit_once(&insert(2, "c", 1), "alice", &id(7))
+        .unwrap();
+    doc.apply_edit(&insert(0, ">", 2)).unwrap();
+
+    let pending = [insert(2, "c", 1), insert(3, "d", 2)];
+    let applied = doc
+        .rebase_pending(1, &pending, "alice", Some(&id(7)))
+        .unwrap();
+    assert_eq!(applied.len(), 1);
+    assert_eq!(doc.content, ">abcd");
+    assert_eq!(doc.client_ops.last("tab-1").unwrap().seq, 8);
+}
+
+#[test]
+fn test_checksums_are_due_every_interval() {
+    assert_eq!(checksum("123456789"), 0xCBF4_3926);
+
+    let mut doc = DocumentState::new();
+    for version in 0..CHECKSUM_INTERVAL {
+        doc.apply_edit(&insert(version, "x", version)).unwrap();
+        let due = doc.checksum_for(doc.version);
+        assert_eq!(due.is_some(), doc.version == CHECKSUM_INTERVAL);
+    }
+    assert_eq!(
+        doc.checksum_for(CHECKSUM_INTERVAL),
+        Some(checksum(&"x".repeat(CHECKSUM_INTERVAL)))
+    );
+    // Only the current version is checked in passing; older ones on request.
+    assert_eq!(doc.checksum_for(0), None);
+    assert_eq!(doc.checksum_at(2), Some(checksum("xx")));
+}
+
+#[test]
+fn test_documents_have_one_owner_on_every_node() {
+    let nodes = ["a", "b", "c"].map(String::from);
+    let mut reordered = nodes.clone();
+    reordered.reverse();
+    let mut owned = [0; 3];
+    for i in 0..300 {
+        let document = format!("doc-{}", i);
+        let first = owner(&document, &nodes).unwrap();
+        assert_eq!(first, owner(&document, &reordered).unwrap());
+        owned[nodes.iter().position(|node| node == first).unwrap()] += 1;
+    }
+    assert!(owned.iter().all(|&count| count > 50), "{:?}", owned);
+
+    // Removing a node only moves the documents it owned.
+    let remaining = ["a", "b"].map(String::from);
+    for i in 0..300 {
+        let document = format!("doc-{}", i);
+        let before = owner(&document, &nodes).unwrap();
+        if before != "c" {
+            assert_eq!(owner(&document, &remaining).unwrap(), before);
+        }
+    }
+    assert_eq!(owner("doc", &[]), None);
+}
+
+#[test]
+fn test_cluster_peers_parse_and_loopback_skips_sender() {
+    let peer: ClusterPeer = "b=10.0.0.2:9090".parse().unwrap();
+    assert_eq!(peer.id, "b");
+    assert_eq!(peer.addr, "10.0.0.2:9090");
+    assert!("10.0.0.2:9090".parse::<ClusterPeer>().is_err());
+    assert!("=10.0.0.2:9090".parse::<ClusterPeer>().is_err());
+
+    let hub = LoopbackHub::new();
+    let (a, mut a_rx) = hub.join("a");
+    let (_b, mut b_rx) = hub.join("b");
+    let message = NodeMessage::SyncRequest {
+        document: "doc".to_string(),
+        from: "a".to_string(),
+    };
+    a.publish(&message);
+    assert!(matches!(
+        b_rx.try_recv(),
+        Ok(NodeMessage::SyncRequest { .. })
+    ));
+    assert!(a_rx.try_recv().is_err());
+    a.send("a", &message);
+    assert!(a_rx.try_recv().is_ok());
+}
+
+#[test]
+fn test_followers_own_nothing_until_promoted() {
+    let hub = LoopbackHub::new();
+    let (primary, _) = hub.join("primary");
+    let (follower, _) = hub.join("follower");
+    let primary = Cluster::primary("primary", Arc::new(primary));
+    let follower = Cluster::follower("follower", "primary", Arc::new(follower));
+    assert!(primary.owns("doc") && primary.replicates_everything());
+    assert!(!primary.promote());
+    assert!(follower.is_follower() && !follower.forwards());
+    assert_eq!(follower.owner("doc"), "primary");
+
+    assert!(follower.promote());
+    assert!(!follower.is_follower());
+    assert!(follower.owns("doc"));
+    assert!(!follower.promote());
+
+    assert!(is_query("history") && is_query("export"));
+    assert!(!is_query("edit") && !is_query("comment_add"));
+}
+
+#[test]
+fn test_snapshots_copy_documents_whole() {
+    let mut doc = DocumentState::new();
+    doc.apply_edit(&insert(0, "hello", 0)).unwrap();
+    doc.apply_edit(&insert(5, "!", 1)).unwrap();
+    doc.checkpoints
+        .create("v2", doc.version, &doc.content, None)
+        .unwrap();
+
+    let mut copy = DocumentState::new();
+    Snapshot::of(&doc).restore_into(&mut copy).unwrap();
+    assert_eq!(copy.content, "hello!");
+    assert_eq!(copy.version, 2);
+    assert_eq!(
+        copy.history.checkout(HistoryPoint::Version(1)).unwrap(),
+        (1, "hello".to_string())
+    );
+    assert_eq!(copy.checkpoints.list().len(), 1);
+}
+
+#[test]
+fn test_memory_size_counts_content_and_history() {
+    let mut doc = DocumentState::new();
+    let empty = doc.memory_size();
+    doc.apply_edit(&insert(0, "hello", 0)).unwrap();
+    assert!(doc.memory_size() >= empty + 2 * "hello".len());
+
+    let replace = Op::Replace {
+        position: 0,
+        deleted: "hello".to_string(),
+        inserted: "hi".to_string(),
+    };
+    assert_eq!(replace.text_len(), 7);
+}
+
+#[test]
+fn test_history_memory_size_counts_the_base_once() {
+    let content = "x".repeat(1000);
+    let history = History::new(&content, 0, 0);
+    assert_eq!(history.memory_size(), content.len());
+}
@@ -6,6 +6,18 @@ void main() {
   runApp(MyApp());
 }
 
+/// CRC-32 (IEEE) of [bytes], the checksum the server sends with edits.
+int crc32(List<int> bytes) {
+  int crc = 0xFFFFFFFF;
+  for (final byte in bytes) {
+    crc ^= byte;
+    for (int bit = 0; bit < 8; bit++) {
+      crc = (crc & 1) != 0 ? (crc >> 1) ^ 0xEDB88320 : crc >> 1;
+    }
+  }
+  return crc ^ 0xFFFFFFFF;
+}
+
 // Make MyApp a StatefulWidget
 class MyApp extends StatefulWidget {
   @override
@@ -74,17 +86,35 @@ class _EditorPageState extends State<EditorPage> {
           _controller.selection = TextSelection.fromPosition(
               TextPosition(offset: _controller.text.length));
           _updatingTextField = false;
+        } else if (data['type'] == 'initial_start') {
+          // Large documents arrive in chunks, shown as they come in
+          _content = '';
+          _version = data['version'];
+        } else if (data['type'] == 'initial_chunk') {
+          _content += data['content'];
+          _updatingTextField = true;
+          _controller.text = _content;
+          _updatingTextField = false;
+        } else if (data['type'] == 'initial_end') {
+          _version = data['version'];
+          _updatingTextField = true;
+          _controller.selection = TextSelection.fromPosition(
+              TextPosition(offset: _controller.text.length));
+          _updatingTextField = false;
         } else if (data['type'] == 'edit') {
           // Handle incoming edits
           final edit = data['edit'];
           if (edit['version'] > _version) {
+            // An edit with both parts, such as a checkpoint restore,
+            // replaces the deleted range with the inserted text.
+            if (edit['delete'] != null) {
+              _content = _content.substring(0, edit['position']) +
+                  _content.substring(edit['position'] + edit['delete']);
+            }
             if (edit['insert'] != null) {
               _content = _content.substring(0, edit['position']) +
                   edit['insert'] +
                   _content.substring(edit['position']);
-            } else if (edit['delete'] != null) {
-              _content = _content.substring(0, edit['position']) +
-                  _content.substring(edit['position'] + edit['delete']);
             }
             _version = edit['version'];
             _updatingTextField = true;
@@ -92,6 +122,16 @@ class _EditorPageState extends State<EditorPage> {
             _controller.selection = TextSelection.fromPosition(
                 TextPosition(offset: _controller.text.length));
             _updatingTextField = false;
+            // Some edits carry the checksum of the server's copy; on a
+            // mismatch the server answers with the full state.
+            if (data['checksum'] != null &&
+                data['checksum'] != crc32(utf8.encode(_content))) {
+              channel.sink.add(json.encode({
+                'type': 'verify',
+                'version': _version,
+                'checksum': crc32(utf8.encode(_content)),
+              }));
+            }
           } else {
             // Version mismatch, request full document state
             channel.sink.add(json.encode({'type': 'request_full_state'}));